@@ -0,0 +1,165 @@
+use crate::configuration::{
+    parse_section, Configuration, ConfigurationError, Overrides,
+};
+use crate::process::set_bucket_region;
+use crate::s3::S3Url;
+use std::collections::BTreeMap;
+use std::{fs, io};
+use thiserror::Error;
+
+/// Section of the configuration file that defines aliases
+pub(crate) const ALIASES_SECTION: &str = "aliases";
+
+const SCHEME: &str = "horst3://";
+
+#[derive(Debug, Error)]
+pub enum AliasError {
+    #[error("unknown alias \"{0}\" in {1}")]
+    UnknownAlias(String, String),
+}
+
+/// A logical name for an S3 location
+#[derive(Clone, Debug)]
+pub struct Alias {
+    /// Bucket and key prefix the name stands for
+    pub url: S3Url,
+    /// Region of the bucket, if not the default region
+    pub region: Option<String>,
+}
+
+impl Alias {
+    /// Parse `s3://<bucket>/<prefix>[@<region>]`
+    pub(crate) fn parse(s: &str) -> Option<Alias> {
+        let (url, region) = match s.rsplit_once('@') {
+            Some((url, region)) if !region.contains('/') => (url, Some(region)),
+            _ => (s, None),
+        };
+        if region == Some("") {
+            return None;
+        }
+        Some(Alias {
+            url: url.parse().ok()?,
+            region: region.map(str::to_string),
+        })
+    }
+}
+
+/// Aliases from the `[aliases]` section of the configuration file
+///
+/// Each line of the section maps a name to a bucket, a key prefix and
+/// optionally a region:
+///
+/// ```text
+/// [aliases]
+/// models = s3://ml-models-prod/releases@eu-west-1
+/// ```
+///
+/// so that `horst3://models/foo.bin` means
+/// `s3://ml-models-prod/releases/foo.bin`. Scripts can then use the
+/// same URLs with configuration files that point at different buckets.
+#[derive(Clone, Debug, Default)]
+pub struct Aliases {
+    aliases: BTreeMap<String, Alias>,
+}
+
+impl Aliases {
+    /// Get the aliases from the contents of a configuration file
+    ///
+    /// Invalid aliases are skipped; `check_config` reports them.
+    pub fn parse(s: &str) -> Aliases {
+        Aliases {
+            aliases: parse_section(s, ALIASES_SECTION)
+                .into_iter()
+                .filter_map(|(name, val)| {
+                    Some((name.to_string(), Alias::parse(val)?))
+                })
+                .collect(),
+        }
+    }
+
+    /// Read the aliases from the configuration file, taking overrides
+    /// into account
+    ///
+    /// There are no aliases if the default configuration file doesn't
+    /// exist.
+    pub fn read_with_overrides(
+        overrides: &Overrides,
+    ) -> Result<Aliases, ConfigurationError> {
+        let path = Configuration::path_with_overrides(overrides)?;
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(Aliases::parse(&contents)),
+            Err(err)
+                if err.kind() == io::ErrorKind::NotFound
+                    && overrides.config_path.is_none() =>
+            {
+                Ok(Aliases::default())
+            }
+            Err(err) => Err(ConfigurationError::ReadFailed(path, err)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Alias> {
+        self.aliases.get(name)
+    }
+
+    /// Turn a `horst3://<alias>/<path>` URL into the S3 URL it stands
+    /// for
+    ///
+    /// Other URLs are returned unchanged. The alias's region is passed
+    /// to the aws commands run for its bucket.
+    pub fn expand(&self, url: &str) -> Result<String, AliasError> {
+        let rest = match url.strip_prefix(SCHEME) {
+            Some(rest) => rest,
+            None => return Ok(url.to_string()),
+        };
+        let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let alias = self.get(name).ok_or_else(|| {
+            AliasError::UnknownAlias(name.to_string(), url.to_string())
+        })?;
+        if let Some(region) = &alias.region {
+            set_bucket_region(&alias.url.bucket, region);
+        }
+        let prefix = &alias.url.key;
+        let key = if prefix.is_empty() || path.is_empty() {
+            format!("{}{}", prefix, path)
+        } else {
+            format!("{}/{}", prefix.trim_end_matches('/'), path)
+        };
+        Ok(S3Url::new(alias.url.bucket.clone(), key).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let aliases = Aliases::parse(
+            "cache_size_limit = 1GB\n\
+             [aliases]\n\
+             models = s3://ml-models/releases@eu-west-1\n\
+             scratch = s3://scratch\n\
+             broken = models\n",
+        );
+        assert_eq!(
+            aliases.get("models").unwrap().region.as_deref(),
+            Some("eu-west-1")
+        );
+        assert!(aliases.get("broken").is_none());
+        assert!(aliases.get("cache_size_limit").is_none());
+
+        let expand = |url| aliases.expand(url).unwrap();
+        assert_eq!(
+            expand("horst3://models/v2/foo.bin"),
+            "s3://ml-models/releases/v2/foo.bin"
+        );
+        assert_eq!(expand("horst3://models"), "s3://ml-models/releases");
+        assert_eq!(expand("horst3://scratch/a"), "s3://scratch/a");
+        assert_eq!(expand("s3://bucket/key"), "s3://bucket/key");
+        assert!(matches!(
+            aliases.expand("horst3://datasets/a"),
+            Err(AliasError::UnknownAlias(name, _)) if name == "datasets"
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Rotate the log once it grows past this size
+const MAX_SIZE_IN_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated logs to keep, named `audit.log.1` (the newest)
+/// through `audit.log.5`
+const ROTATED_LOGS: u32 = 5;
+
+/// A change made to the contents of the cache
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AuditAction {
+    Insert,
+    Evict,
+    Remove,
+    Quarantine,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            AuditAction::Insert => "insert",
+            AuditAction::Evict => "evict",
+            AuditAction::Remove => "remove",
+            AuditAction::Quarantine => "quarantine",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Append-only record of the changes made to the cache
+///
+/// Each line is `<unix time> <user> <action> <md5sum> <size>`,
+/// separated by tabs.
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    max_size_in_bytes: u64,
+}
+
+impl AuditLog {
+    pub(crate) fn new(path: PathBuf) -> AuditLog {
+        AuditLog {
+            path,
+            max_size_in_bytes: MAX_SIZE_IN_BYTES,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    /// Shift the rotated logs up by one, dropping the oldest, and
+    /// move the current log to `.1`
+    fn rotate(&self) -> io::Result<()> {
+        for index in (1..ROTATED_LOGS).rev() {
+            match fs::rename(
+                self.rotated_path(index),
+                self.rotated_path(index + 1),
+            ) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(err)
+                }
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+
+    /// Add a line to the log, rotating it first if it's too large
+    pub(crate) fn append(
+        &self,
+        time: u64,
+        action: AuditAction,
+        md5sum: &str,
+        size: u64,
+    ) -> io::Result<()> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) if metadata.len() >= self.max_size_in_bytes => {
+                self.rotate()?
+            }
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err)
+            }
+            _ => {}
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        // Write the whole line at once so that lines from concurrent
+        // writers don't get interleaved
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            time,
+            current_user(),
+            action,
+            md5sum,
+            size
+        );
+        file.write_all(line.as_bytes())
+    }
+}
+
+/// Get the name of the user running this process
+fn current_user() -> String {
+    ["USER", "USERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path().join("audit.log"));
+        log.append(1, AuditAction::Insert, "abc", 10).unwrap();
+        log.append(2, AuditAction::Evict, "abc", 10).unwrap();
+        let contents = fs::read_to_string(log.path()).unwrap();
+        let user = current_user();
+        assert_eq!(
+            contents,
+            format!("1\t{0}\tinsert\tabc\t10\n2\t{0}\tevict\tabc\t10\n", user)
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = AuditLog::new(dir.path().join("audit.log"));
+        log.max_size_in_bytes = 1;
+        for time in 0..(ROTATED_LOGS as u64 + 2) {
+            log.append(time, AuditAction::Remove, "abc", 10).unwrap();
+        }
+        let first_field = |path: PathBuf| {
+            let contents = fs::read_to_string(path).unwrap();
+            contents.split('\t').next().unwrap().to_string()
+        };
+        assert_eq!(first_field(log.path().to_path_buf()), "6");
+        assert_eq!(first_field(log.rotated_path(1)), "5");
+        assert_eq!(first_field(log.rotated_path(ROTATED_LOGS)), "1");
+        assert!(!log.rotated_path(ROTATED_LOGS + 1).exists());
+    }
+}
@@ -0,0 +1,2641 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use horst3::{
+    cache_metrics, check_config, format_manifest, list_manifest,
+    parse_duration_as_secs, parse_manifest, parse_size_as_bytes, put_metrics,
+    set_aws_limits, set_credentials_refresh_command, AliasError, Aliases,
+    AwsLimits, Backfill, Cache, CacheEntry, CacheError, CheckStatus,
+    Configuration, ConfigurationError, DownloadOptions, DownloadOutcome,
+    DownloadSummary, EntryFilter, HttpError, HttpFile, Inventory,
+    InventoryError, LatestBy, LocalError, LocalFile, LogFormat, LogTarget,
+    ManifestEntry, ManifestError, Metric, MetricsError, OutputMode, Overrides,
+    Overwrite, Prefetch, Progress, S3Error, S3Url, SshError, SshFile, Stats,
+    SyncError, TemplateError, UploadOptions, Variables,
+};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use log::{warn, LevelFilter};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use std::{fs, io, process, thread};
+use thiserror::Error;
+
+const EXIT_FAILURE: i32 = 1;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_ACCESS_DENIED: i32 = 4;
+const EXIT_CACHE_ERROR: i32 = 5;
+const EXIT_NETWORK_ERROR: i32 = 6;
+const EXIT_CHECKSUM_MISMATCH: i32 = 7;
+const EXIT_OFFLINE: i32 = 8;
+
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  success
+  1  other error
+  2  invalid arguments
+  3  object or bucket not found in S3
+  4  missing or expired credentials, or access denied
+  5  local cache error
+  6  network error
+  7  checksum mismatch
+  8  offline and the object isn't cached, or the command needs S3";
+
+/// S3 caching tool
+#[derive(Parser)]
+#[command(version, after_help = EXIT_CODES_HELP)]
+struct Cli {
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Log more details (-v for info, -vv for debug, -vvv for trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log errors and hide progress bars
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Read this configuration file instead of the default one
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Override the cache directory
+    #[arg(long, global = true, value_name = "DIR")]
+    cache_dir: Option<String>,
+
+    /// Override the cache size limit (e.g. 10GB)
+    #[arg(long, global = true, value_name = "SIZE", value_parser = check_size)]
+    cache_size_limit: Option<String>,
+
+    /// Never contact S3: only serve objects that are already cached
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// Set a variable for {name} placeholders in URLs (repeatable);
+    /// placeholders without one use the environment variable
+    #[arg(long = "var", global = true, value_name = "NAME=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum IfExists {
+    /// Replace the destination
+    Overwrite,
+    /// Fail
+    Error,
+    /// Leave the destination alone if its md5sum matches the object's
+    SkipUnchanged,
+    /// Keep the destination as <dest>.bak, then replace it
+    Backup,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LinkArg {
+    /// Symbolic link (Unix only)
+    Symlink,
+    /// Hard link, or a copy if the cache is on another filesystem
+    Hardlink,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// JSON, for scripts
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download an object through the cache
+    Get {
+        /// Object URL (s3://<bucket>/<key>), file:///<path> for a file
+        /// on a local or network filesystem, or
+        /// ssh://[<user>@]<host>[:<port>]/<path> for a file on an SSH
+        /// host, or an http:// or https:// URL for a file in an
+        /// artifact repository
+        url: String,
+        /// Destination path
+        dest: PathBuf,
+        /// Download directly without using the cache
+        #[arg(long)]
+        no_cache: bool,
+        /// Download the object again even if it is cached, and don't
+        /// use a cached head-object result
+        #[arg(long)]
+        refresh: bool,
+        /// Check the md5sum of the destination after downloading
+        #[arg(long)]
+        verify: bool,
+        /// What to do if the destination already exists
+        #[arg(long, value_enum, default_value_t = IfExists::Overwrite)]
+        if_exists: IfExists,
+        /// Fail if the destination already exists (same as
+        /// --if-exists error)
+        #[arg(long, conflicts_with = "if_exists")]
+        no_clobber: bool,
+        /// Set the modification time to the object's
+        #[arg(long)]
+        preserve_mtime: bool,
+        /// File mode in octal, e.g. 755
+        #[arg(long, value_parser = parse_mode)]
+        mode: Option<u32>,
+        /// Link the destination to the cache entry instead of copying
+        /// it. The entry isn't evicted while the link exists, so don't
+        /// modify the destination.
+        #[arg(long, value_enum, conflicts_with_all = ["no_cache", "mode", "preserve_mtime"])]
+        link: Option<LinkArg>,
+    },
+    /// Upload a file with its md5sum stored in the object metadata
+    Put {
+        /// Source path
+        src: PathBuf,
+        /// Object URL (s3://<bucket>/<key>)
+        url: String,
+        /// Also add the file to the local cache
+        #[arg(long)]
+        write_through: bool,
+        /// Don't upload if the object already exists with the same
+        /// md5sum
+        #[arg(long)]
+        skip_if_exists: bool,
+    },
+    /// Write an object to stdout through the cache
+    Cat {
+        /// Object URL (s3://<bucket>/<key>)
+        url: String,
+    },
+    /// List objects under an S3 prefix
+    Ls {
+        /// Prefix URL (s3://<bucket>/<prefix>)
+        url: String,
+    },
+    /// Find the newest object under a prefix whose key matches a
+    /// pattern
+    ///
+    /// The URL of the object is printed, or it is downloaded through
+    /// the cache if a destination is given.
+    Latest {
+        /// Prefix URL (s3://<bucket>/<prefix>)
+        url: String,
+        /// Pattern for the rest of the key after the prefix, where `*`
+        /// matches anything (e.g. "*/app.tar")
+        pattern: String,
+        /// Destination path
+        dest: Option<PathBuf>,
+        /// Pick the object whose key sorts last instead of the most
+        /// recently modified one
+        #[arg(long)]
+        by_key: bool,
+    },
+    /// Print a URL that allows anyone to download an object
+    Presign {
+        /// Object URL (s3://<bucket>/<key>)
+        url: String,
+        /// How long the URL is valid (e.g. 30m, 12h, 7d)
+        #[arg(long, default_value = "1h", value_parser = parse_duration)]
+        expires: u64,
+    },
+    /// Show an object's metadata
+    Head {
+        /// Object URL (s3://<bucket>/<key>)
+        url: String,
+    },
+    /// Delete an object and its local cache entries
+    Rm {
+        /// Object URL (s3://<bucket>/<key>)
+        url: String,
+        /// Only delete the local cache entries, without contacting S3
+        #[arg(long)]
+        cache_only: bool,
+    },
+    /// Synchronize an S3 prefix and a local directory
+    ///
+    /// The direction is determined by which argument is an s3:// URL.
+    Sync {
+        /// Source (s3://<bucket>/<prefix> or a directory)
+        src: String,
+        /// Destination (a directory or s3://<bucket>/<prefix>)
+        dest: String,
+        /// Delete destination files that don't exist in the source
+        #[arg(long)]
+        delete: bool,
+        /// Take the objects to download from the S3 Inventory report
+        /// with this manifest.json URL instead of listing the prefix
+        #[arg(long)]
+        inventory: Option<String>,
+    },
+    /// Print a manifest of the objects under an S3 prefix
+    ///
+    /// The manifest lists each object's URL, md5sum and size, and can
+    /// be passed to warm.
+    Manifest {
+        /// Prefix URL (s3://<bucket>/<prefix>)
+        url: String,
+    },
+    /// Download the objects listed in a manifest into the cache
+    ///
+    /// Each line of the manifest is an object URL, optionally followed
+    /// by the object's md5sum and size. Alternatively, --inventory
+    /// warms every object in an S3 Inventory report.
+    Warm {
+        /// Manifest file
+        #[arg(required_unless_present = "inventory")]
+        manifest: Option<PathBuf>,
+        /// manifest.json URL of an S3 Inventory report
+        #[arg(long, conflicts_with = "manifest")]
+        inventory: Option<String>,
+        /// Number of objects to download in parallel
+        #[arg(long, short, default_value_t = 4)]
+        jobs: usize,
+    },
+    /// Download new versions of frequently used objects into the cache
+    ///
+    /// Objects requested through the cache at least --min-accesses
+    /// times are checked against S3 and fetched again if they changed.
+    /// With --interval this repeats until killed, so it can run as a
+    /// daemon that keeps the cache warm.
+    Refresh {
+        /// Only objects requested at least this many times
+        #[arg(long, default_value_t = 2)]
+        min_accesses: u64,
+        /// Repeat after this long (e.g. 30m, 12h)
+        #[arg(long, value_parser = parse_duration)]
+        interval: Option<u64>,
+    },
+    /// Add md5sum metadata to objects uploaded without horst3
+    ///
+    /// Objects under the prefix that have no md5sum metadata can't be
+    /// cached. The md5sum is taken from the ETag where possible, or
+    /// else by downloading the object, and stored by copying the
+    /// object onto itself with the new metadata.
+    Backfill {
+        /// Prefix URL (s3://<bucket>/<prefix>)
+        url: String,
+        /// Number of objects to process in parallel
+        #[arg(long, short, default_value_t = 4)]
+        jobs: usize,
+        /// Compute the md5sums without changing any objects
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Run as a Git LFS custom transfer agent
+    ///
+    /// LFS objects are stored in S3 under the prefix, named by their
+    /// oid, and downloaded through the cache. To use it for a
+    /// repository without an LFS server:
+    ///
+    ///   git config lfs.standalonetransferagent horst3
+    ///   git config lfs.customtransfer.horst3.path horst3
+    ///   git config lfs.customtransfer.horst3.args "lfs-agent <url>"
+    LfsAgent {
+        /// Prefix URL (s3://<bucket>/<prefix>)
+        url: String,
+    },
+    /// Check the setup for common problems
+    ///
+    /// Checks the configuration file, that the cache directory is
+    /// writable, the free disk space, the aws CLI and its credentials,
+    /// the remote cache server and the clock, and says how to fix any
+    /// problems found.
+    Doctor,
+    /// Inspect and manage the local cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Manage the configuration file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+impl Command {
+    /// Check if the command always talks to S3 rather than serving
+    /// objects from the cache, so it can't run offline
+    fn needs_s3(&self) -> bool {
+        match self {
+            Command::Put { .. }
+            | Command::Sync { .. }
+            | Command::Ls { .. }
+            | Command::Latest { .. }
+            | Command::Head { .. }
+            | Command::Manifest { .. }
+            | Command::Refresh { .. }
+            | Command::Backfill { .. } => true,
+            Command::Rm { cache_only, .. } => !cache_only,
+            Command::Warm { inventory, .. } => inventory.is_some(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Write the default configuration file
+    Init {
+        /// Overwrite an existing configuration file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show the effective configuration and where each value came from
+    Show,
+    /// Check a configuration file for errors
+    Check {
+        /// File to check (defaults to the configuration file)
+        path: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Show cache usage and hit/miss counters
+    Stats,
+    /// Send cache usage and hit/miss counters to CloudWatch
+    ///
+    /// The namespace is set by cloudwatch_namespace in the
+    /// configuration. Run this periodically, e.g. from cron, to graph
+    /// the cache in CloudWatch dashboards.
+    PublishMetrics,
+    /// Delete cache entries, by default all of them
+    Purge {
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Show which entries would be deleted without deleting them
+        #[arg(long)]
+        dry_run: bool,
+        /// Also delete pinned and linked entries
+        #[arg(long)]
+        force: bool,
+    },
+    /// List cache entries
+    Ls {
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Sort entries by this field
+        #[arg(long, value_enum, default_value_t = SortKey::Access)]
+        sort: SortKey,
+        /// Reverse the sort order
+        #[arg(long)]
+        reverse: bool,
+    },
+    /// Re-hash cache entries and report corrupt ones
+    Verify {
+        /// Move corrupt entries to the quarantine directory
+        #[arg(long)]
+        fix: bool,
+        /// Number of entries to hash in parallel
+        #[arg(long, short, default_value_t = 4)]
+        jobs: usize,
+    },
+    /// List corrupt entries that were moved out of the cache
+    Quarantine {
+        /// Delete the quarantined entries
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Show disk usage grouped by bucket and by age
+    Du,
+    /// Delete leftover temporary files and entries older than the TTL
+    Gc,
+    /// Keep entries in the cache until they are unpinned
+    Pin {
+        /// md5sums or object URLs (s3://<bucket>/<key>)
+        #[arg(required = true)]
+        targets: Vec<String>,
+    },
+    /// Allow pinned entries to be evicted again
+    Unpin {
+        /// md5sums or object URLs (s3://<bucket>/<key>)
+        #[arg(required = true)]
+        targets: Vec<String>,
+    },
+}
+
+#[derive(Args)]
+struct FilterArgs {
+    /// Only entries not accessed in this many days
+    #[arg(long = "older-than", value_name = "DAYS", value_parser = parse_days)]
+    older_than_in_s: Option<u64>,
+    /// Only entries larger than this size (e.g. 100MB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    larger_than: Option<u64>,
+    /// Only entries downloaded from this bucket
+    #[arg(long)]
+    bucket: Option<String>,
+}
+
+impl FilterArgs {
+    fn to_filter(&self) -> EntryFilter {
+        EntryFilter {
+            older_than_in_s: self.older_than_in_s,
+            larger_than_in_bytes: self.larger_than,
+            bucket: self.bucket.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SortKey {
+    Hash,
+    Size,
+    Access,
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    parse_size_as_bytes(s).ok_or_else(|| format!("invalid size: {}", s))
+}
+
+fn parse_duration(s: &str) -> Result<u64, String> {
+    parse_duration_as_secs(s).ok_or_else(|| format!("invalid duration: {}", s))
+}
+
+/// Parse a number of days as seconds
+fn parse_days(s: &str) -> Result<u64, String> {
+    s.parse::<u64>()
+        .ok()
+        .and_then(|days| days.checked_mul(24 * 60 * 60))
+        .ok_or_else(|| format!("invalid number of days: {}", s))
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid mode: {}", s))
+}
+
+/// Validate a size but keep it in its original form
+fn check_size(s: &str) -> Result<String, String> {
+    parse_size(s).map(|_| s.to_string())
+}
+
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    Variables::parse_assignment(s)
+        .ok_or_else(|| format!("expected NAME=VALUE, got {:?}", s))
+}
+
+/// Options shared by all commands
+struct Context {
+    output: OutputFormat,
+    quiet: bool,
+    overrides: Overrides,
+    /// Names that horst3:// URLs can use in place of buckets
+    aliases: Aliases,
+    /// Values for {name} placeholders in URLs
+    variables: Variables,
+    /// Set by SIGUSR1 to ask for a stats snapshot
+    stats_requested: Arc<AtomicBool>,
+}
+
+impl Context {
+    fn is_json(&self) -> bool {
+        self.output == OutputFormat::Json
+    }
+
+    fn show_progress(&self) -> bool {
+        !self.is_json() && !self.quiet
+    }
+
+    fn open_cache(&self) -> Result<Cache, Error> {
+        let conf = Configuration::open_with_overrides(&self.overrides)?;
+        set_aws_limits(AwsLimits {
+            max_processes: conf.max_aws_processes,
+            timeout: conf.aws_timeout_in_s.map(Duration::from_secs),
+        });
+        set_credentials_refresh_command(
+            conf.credentials_refresh_command.clone(),
+        );
+        Ok(Cache::open_with_configuration(conf)?)
+    }
+
+    /// Fill in the URL's {name} placeholders, then turn a
+    /// horst3://<alias>/... URL into the S3 URL it stands for
+    fn expand_url(&self, url: &str) -> Result<String, Error> {
+        let url = self.variables.expand(url)?;
+        Ok(self.aliases.expand(&url)?)
+    }
+
+    /// Parse an S3 URL, which may use an alias and placeholders
+    fn s3_url(&self, url: &str) -> Result<S3Url, Error> {
+        Ok(self.expand_url(url)?.parse()?)
+    }
+
+    /// Check if a stats snapshot was requested since the last check
+    fn take_stats_request(&self) -> bool {
+        self.stats_requested.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[cfg(unix)]
+fn register_stats_signal(flag: &Arc<AtomicBool>) {
+    use signal_hook::consts::SIGUSR1;
+
+    if let Err(err) = signal_hook::flag::register(SIGUSR1, flag.clone()) {
+        warn!("failed to register SIGUSR1 handler: {}", err);
+    }
+}
+
+#[cfg(not(unix))]
+fn register_stats_signal(_flag: &Arc<AtomicBool>) {}
+
+/// Log cache usage, counters since `start` and the objects being
+/// transferred
+///
+/// The snapshot is logged as a warning so that it shows up at the
+/// default log level, wherever `log_target` sends the log.
+fn log_stats_snapshot(cache: &Cache, start: &Stats, in_flight: &[String]) {
+    let (entries, stats) = match (cache.entries(), cache.stats()) {
+        (Ok(entries), Ok(stats)) => (entries, stats),
+        (Err(err), _) | (_, Err(err)) => {
+            warn!("failed to get stats: {}", error_chain(&err));
+            return;
+        }
+    };
+    let since_start = Stats {
+        hits: stats.hits.saturating_sub(start.hits),
+        misses: stats.misses.saturating_sub(start.misses),
+        hit_bytes: stats.hit_bytes.saturating_sub(start.hit_bytes),
+        miss_bytes: stats.miss_bytes.saturating_sub(start.miss_bytes),
+        evictions: stats.evictions.saturating_sub(start.evictions),
+        evicted_bytes: stats.evicted_bytes.saturating_sub(start.evicted_bytes),
+        ..Default::default()
+    };
+    let usage_bytes: u64 = entries.iter().map(|entry| entry.size).sum();
+    warn!(
+        "usage: {} / {} ({} entries)",
+        HumanBytes(usage_bytes),
+        HumanBytes(cache.size_limit()),
+        entries.len()
+    );
+    warn!(
+        "since start: {} hits, {} misses ({:.1}% hit ratio), {} evictions",
+        since_start.hits,
+        since_start.misses,
+        since_start.hit_ratio() * 100.0,
+        since_start.evictions
+    );
+    if in_flight.is_empty() {
+        warn!("in flight: none");
+    } else {
+        warn!("in flight: {}", in_flight.join(", "));
+    }
+}
+
+/// Run `f` in another thread, logging a stats snapshot each time one
+/// is requested with SIGUSR1 until it finishes
+///
+/// `in_flight` is the list of objects `f` is transferring.
+fn with_stats_snapshots<T, F>(
+    ctx: &Context,
+    cache: &Cache,
+    start: &Stats,
+    in_flight: &Mutex<Vec<String>>,
+    f: F,
+) -> T
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    thread::scope(|scope| {
+        let handle = scope.spawn(f);
+        while !handle.is_finished() {
+            if ctx.take_stats_request() {
+                log_stats_snapshot(cache, start, &in_flight.lock().unwrap());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        handle
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+#[derive(Debug, Error)]
+enum Error {
+    #[error(transparent)]
+    AliasError(#[from] AliasError),
+    #[error(transparent)]
+    CacheError(#[from] CacheError),
+    #[error("config file already exists: {}", .0.display())]
+    ConfigExists(PathBuf),
+    #[error(transparent)]
+    ConfigurationError(#[from] ConfigurationError),
+    #[error("{0} corrupt cache entries")]
+    CorruptEntries(usize),
+    #[error("{0} checks failed")]
+    DoctorFailed(usize),
+    #[error("{0} problems in config file")]
+    InvalidConfig(usize),
+    #[error(transparent)]
+    HttpError(#[from] HttpError),
+    #[error(transparent)]
+    InventoryError(#[from] InventoryError),
+    #[error("--inventory only applies when syncing from S3")]
+    InventoryNeedsS3Source,
+    #[error(transparent)]
+    LocalError(#[from] LocalError),
+    #[error(transparent)]
+    SshError(#[from] SshError),
+    #[error("invalid manifest")]
+    ManifestError(#[from] ManifestError),
+    #[error(transparent)]
+    MetricsError(#[from] MetricsError),
+    #[error("no md5sum available for {0}")]
+    NoMd5sum(String),
+    #[error("this command needs S3, which isn't contacted offline")]
+    Offline,
+    #[error("failed to read {}", .0.display())]
+    ReadError(PathBuf, #[source] io::Error),
+    #[error(transparent)]
+    SyncError(#[from] SyncError),
+    #[error(transparent)]
+    TemplateError(#[from] TemplateError),
+    #[error("one side of a sync must be an S3 URL")]
+    SyncNeedsS3Url,
+    #[error("failed to backfill {0} objects")]
+    BackfillFailed(usize),
+    #[error("failed to refresh {0} objects")]
+    RefreshFailed(usize),
+    #[error("failed to warm {0} objects")]
+    WarmFailed(usize),
+    #[error("failed to serialize JSON")]
+    JsonError(#[source] serde_json::Error),
+    #[error("failed to talk to git-lfs")]
+    LfsIoError(#[source] io::Error),
+    #[error(transparent)]
+    S3Error(#[from] S3Error),
+}
+
+impl Error {
+    /// Get the process exit code for this error
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::CacheError(_) => EXIT_CACHE_ERROR,
+            Error::CorruptEntries(_) => EXIT_CHECKSUM_MISMATCH,
+            Error::Offline => EXIT_OFFLINE,
+            Error::HttpError(err) => match err {
+                HttpError::CacheError(_) => EXIT_CACHE_ERROR,
+                HttpError::ChecksumMismatch(_) => EXIT_CHECKSUM_MISMATCH,
+                _ => EXIT_FAILURE,
+            },
+            Error::LocalError(err) => match err {
+                LocalError::CacheError(_) => EXIT_CACHE_ERROR,
+                LocalError::ChecksumMismatch(_) => EXIT_CHECKSUM_MISMATCH,
+                LocalError::ReadError(_, err)
+                    if err.kind() == io::ErrorKind::NotFound =>
+                {
+                    EXIT_NOT_FOUND
+                }
+                _ => EXIT_FAILURE,
+            },
+            Error::SshError(err) => match err {
+                SshError::CacheError(_) => EXIT_CACHE_ERROR,
+                SshError::ChecksumMismatch(_) => EXIT_CHECKSUM_MISMATCH,
+                _ => EXIT_FAILURE,
+            },
+            Error::S3Error(err)
+            | Error::InventoryError(InventoryError::S3Error(err))
+            | Error::SyncError(SyncError::S3Error(err)) => match err {
+                S3Error::AccessDenied(_)
+                | S3Error::CredentialsExpired(_)
+                | S3Error::CredentialsRefreshFailed(_) => EXIT_ACCESS_DENIED,
+                S3Error::CacheError(_) => EXIT_CACHE_ERROR,
+                S3Error::ChecksumMismatch(_) => EXIT_CHECKSUM_MISMATCH,
+                S3Error::NetworkError(_) => EXIT_NETWORK_ERROR,
+                S3Error::NotFound(_) => EXIT_NOT_FOUND,
+                S3Error::Offline(_) => EXIT_OFFLINE,
+                _ => EXIT_FAILURE,
+            },
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+/// Format an error followed by the chain of errors that caused it
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut out = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        out.push_str(": ");
+        out.push_str(&err.to_string());
+        source = err.source();
+    }
+    out
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<(), Error> {
+    let out = serde_json::to_string_pretty(value).map_err(Error::JsonError)?;
+    println!("{}", out);
+    Ok(())
+}
+
+/// Create a progress bar for a single file transfer
+fn transfer_bar(ctx: &Context) -> ProgressBar {
+    if !ctx.show_progress() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec})",
+        )
+        .expect("valid template")
+        .progress_chars("=> "),
+    );
+    bar
+}
+
+fn update_transfer_bar(bar: &ProgressBar, progress: Progress) {
+    if let Some(total) = progress.total {
+        bar.set_length(total);
+    }
+    bar.set_position(progress.transferred);
+}
+
+fn print_transfer_summary(bytes_from_cache: u64, bytes_from_network: u64) {
+    eprintln!(
+        "{} from cache, {} from network",
+        HumanBytes(bytes_from_cache),
+        HumanBytes(bytes_from_network)
+    );
+}
+
+#[derive(Serialize)]
+struct GetReport {
+    url: String,
+    path: PathBuf,
+    outcome: DownloadOutcome,
+    from_cache: bool,
+    bytes: u64,
+}
+
+fn get(
+    ctx: &Context,
+    url: &str,
+    dest: &Path,
+    options: DownloadOptions,
+) -> Result<(), Error> {
+    let url = &ctx.expand_url(url)?;
+    if url.starts_with("file://") {
+        return get_local(ctx, url, dest);
+    }
+    if url.starts_with("ssh://") {
+        return get_ssh(ctx, url, dest);
+    }
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return get_http(ctx, url, dest);
+    }
+    let url = ctx.s3_url(url)?;
+    let cache = Arc::new(ctx.open_cache()?);
+    let bar = transfer_bar(ctx);
+    bar.set_message(url.key.clone());
+    let mut progress = |progress| update_transfer_bar(&bar, progress);
+    let options = DownloadOptions {
+        progress: Some(&mut progress),
+        ..options
+    };
+    let result = url.download_with_options(&cache, dest, options);
+    bar.finish_and_clear();
+    let summary = result?;
+    print_get_report(ctx, &url.to_string(), dest, summary)?;
+    if let Some(prefetch) = url.prefetch_siblings(cache) {
+        // Wait for the prefetch before exiting. It logs its own
+        // failures, since the download itself succeeded.
+        let _ = prefetch.join();
+    }
+    Ok(())
+}
+
+/// Copy a file from a local or network filesystem through the cache
+///
+/// The download options only apply to S3 objects. Local files are
+/// always hashed when they're cached, so there is nothing to verify.
+fn get_local(ctx: &Context, url: &str, dest: &Path) -> Result<(), Error> {
+    let file = LocalFile::from_url(url)?;
+    let cache = ctx.open_cache()?;
+    let summary = file.download(&cache, dest)?;
+    print_get_report(ctx, url, dest, summary)
+}
+
+/// Copy a file from an SSH host through the cache
+///
+/// As with local files, the download options only apply to S3 objects.
+fn get_ssh(ctx: &Context, url: &str, dest: &Path) -> Result<(), Error> {
+    let file = SshFile::from_url(url)?;
+    let cache = ctx.open_cache()?;
+    let summary = file.download(&cache, dest)?;
+    print_get_report(ctx, url, dest, summary)
+}
+
+/// Download a file from an HTTP artifact repository through the cache
+///
+/// As with local files, the download options only apply to S3 objects.
+fn get_http(ctx: &Context, url: &str, dest: &Path) -> Result<(), Error> {
+    let file = HttpFile::from_url(url)?;
+    let cache = ctx.open_cache()?;
+    let summary = file.download(&cache, dest)?;
+    print_get_report(ctx, url, dest, summary)
+}
+
+fn print_get_report(
+    ctx: &Context,
+    url: &str,
+    dest: &Path,
+    summary: DownloadSummary,
+) -> Result<(), Error> {
+    let report = GetReport {
+        url: url.to_string(),
+        path: dest.to_path_buf(),
+        outcome: summary.outcome,
+        from_cache: summary.from_cache(),
+        bytes: summary.bytes,
+    };
+    match ctx.output {
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Text
+            if report.outcome == DownloadOutcome::AlreadyPresent =>
+        {
+            eprintln!("{} is up to date", report.path.display())
+        }
+        OutputFormat::Text if report.from_cache => {
+            print_transfer_summary(report.bytes, 0)
+        }
+        OutputFormat::Text => print_transfer_summary(0, report.bytes),
+    }
+    Ok(())
+}
+
+fn cat(ctx: &Context, url: &str) -> Result<(), Error> {
+    let url = ctx.s3_url(url)?;
+    let cache = ctx.open_cache()?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    url.write_with_cache(&cache, &mut out)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct HeadReport {
+    url: String,
+    size: u64,
+    last_modified: String,
+    storage_class: String,
+    etag: String,
+    md5sum: Option<String>,
+}
+
+fn head(ctx: &Context, url: &str) -> Result<(), Error> {
+    let url = ctx.s3_url(url)?;
+    let metadata = url.metadata()?;
+    let report = HeadReport {
+        url: url.to_string(),
+        size: metadata.size,
+        last_modified: metadata.last_modified,
+        storage_class: metadata.storage_class,
+        etag: metadata.etag,
+        md5sum: metadata.md5sum,
+    };
+    if ctx.is_json() {
+        return print_json(&report);
+    }
+    println!("size:          {}", report.size);
+    println!("last modified: {}", report.last_modified);
+    println!("storage class: {}", report.storage_class);
+    println!("etag:          {}", report.etag);
+    match &report.md5sum {
+        Some(md5sum) => println!("md5sum:        {}", md5sum),
+        None => println!(
+            "md5sum:        none (the object can't be cached; upload it \
+             with horst3 put to fix that)"
+        ),
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ObjectReport {
+    key: String,
+    size: u64,
+    last_modified: String,
+    /// None if the md5sum can't be determined from the listing
+    cached: Option<bool>,
+}
+
+fn ls(ctx: &Context, url: &str) -> Result<(), Error> {
+    let url = ctx.s3_url(url)?;
+    let objects = url.list()?;
+    let cache = ctx.open_cache()?;
+    let reports: Vec<_> = objects
+        .into_iter()
+        .map(|object| ObjectReport {
+            // The ETag is the md5sum for objects that weren't uploaded
+            // in multiple parts
+            cached: object.etag_md5sum().map(|md5sum| cache.contains(md5sum)),
+            key: object.key,
+            size: object.size,
+            last_modified: object.last_modified,
+        })
+        .collect();
+    if ctx.is_json() {
+        return print_json(&reports);
+    }
+    for report in reports {
+        let cached = match report.cached {
+            Some(true) => "cached",
+            Some(false) => "-",
+            None => "?",
+        };
+        println!(
+            "{:24}  {:>12}  {:6}  {}",
+            report.last_modified, report.size, cached, report.key
+        );
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ManifestReport {
+    url: String,
+    md5sum: Option<String>,
+    size: Option<u64>,
+}
+
+fn manifest(ctx: &Context, url: &str) -> Result<(), Error> {
+    let url = ctx.s3_url(url)?;
+    let entries = list_manifest(&url)?;
+    if ctx.is_json() {
+        let reports: Vec<_> = entries
+            .into_iter()
+            .map(|entry| ManifestReport {
+                url: entry.url.to_string(),
+                md5sum: entry.md5sum,
+                size: entry.size,
+            })
+            .collect();
+        return print_json(&reports);
+    }
+    print!("{}", format_manifest(&entries));
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct LatestReport {
+    url: String,
+}
+
+fn latest(
+    ctx: &Context,
+    url: &str,
+    pattern: &str,
+    dest: Option<&Path>,
+    by: LatestBy,
+) -> Result<(), Error> {
+    let url = ctx.s3_url(url)?;
+    let latest = url.latest(pattern, by)?.to_string();
+    if let Some(dest) = dest {
+        return get(ctx, &latest, dest, DownloadOptions::default());
+    }
+    if ctx.is_json() {
+        return print_json(&LatestReport { url: latest });
+    }
+    println!("{}", latest);
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct PresignReport {
+    url: String,
+    presigned_url: String,
+    expires_in_s: u64,
+}
+
+fn presign(ctx: &Context, url: &str, expires_in_s: u64) -> Result<(), Error> {
+    let url = ctx.s3_url(url)?;
+    let presigned_url = url.presign(expires_in_s)?;
+    if ctx.is_json() {
+        return print_json(&PresignReport {
+            url: url.to_string(),
+            presigned_url,
+            expires_in_s,
+        });
+    }
+    println!("{}", presigned_url);
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct RmReport {
+    url: String,
+    deleted_object: bool,
+    evicted: Vec<CacheEntry>,
+}
+
+fn rm(ctx: &Context, url: &str, cache_only: bool) -> Result<(), Error> {
+    let url = ctx.s3_url(url)?;
+    let cache = ctx.open_cache()?;
+    let md5sum = if cache_only {
+        // Only invalidate what the cache knows about, without asking S3
+        cache.lookup_url(&url.to_string())?
+    } else if cache.is_offline() {
+        return Err(Error::Offline);
+    } else {
+        // The object may already be gone, in which case its entries can
+        // still be found by source URL
+        match url.md5sum() {
+            Ok(md5sum) => md5sum,
+            Err(S3Error::NotFound(_)) => None,
+            Err(err) => return Err(err.into()),
+        }
+    };
+    if !cache_only {
+        url.delete()?;
+    }
+    let evicted = cache.invalidate(&url.to_string(), md5sum.as_deref())?;
+    let report = RmReport {
+        url: url.to_string(),
+        deleted_object: !cache_only,
+        evicted,
+    };
+    if ctx.is_json() {
+        return print_json(&report);
+    }
+    if report.deleted_object {
+        println!("deleted {}", report.url);
+    }
+    for entry in &report.evicted {
+        println!("evicted {}", entry.md5sum);
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct PutReport {
+    url: String,
+    path: PathBuf,
+    md5sum: String,
+    cached: bool,
+    skipped: bool,
+}
+
+fn put(
+    ctx: &Context,
+    src: &Path,
+    url: &str,
+    write_through: bool,
+    skip_if_exists: bool,
+) -> Result<(), Error> {
+    let url = ctx.s3_url(url)?;
+    let cache = if write_through {
+        Some(ctx.open_cache()?)
+    } else {
+        None
+    };
+    let options = UploadOptions {
+        write_through: cache.as_ref(),
+        skip_if_exists,
+    };
+    let summary = url.upload_with_options(src, options)?;
+    if ctx.is_json() {
+        print_json(&PutReport {
+            url: url.to_string(),
+            path: src.to_path_buf(),
+            md5sum: summary.md5sum,
+            cached: summary.cached,
+            skipped: summary.skipped,
+        })?;
+    } else if summary.skipped {
+        eprintln!("{} is up to date", url.to_string());
+    }
+    Ok(())
+}
+
+fn sync(
+    ctx: &Context,
+    src: &str,
+    dest: &str,
+    delete: bool,
+    inventory: Option<&str>,
+) -> Result<(), Error> {
+    let src = &ctx.expand_url(src)?;
+    let dest = &ctx.expand_url(dest)?;
+    let bars = MultiProgress::new();
+    let total_bar = bars.add(if !ctx.show_progress() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new_spinner()
+    });
+    let file_bar = bars.add(transfer_bar(ctx));
+    let mut current_key = String::new();
+    let mut num_files = 0;
+    let mut total_bytes = 0;
+    let mut progress = |key: &str, progress: Progress| {
+        if key != current_key {
+            current_key = key.to_string();
+            file_bar.set_message(current_key.clone());
+            num_files += 1;
+        }
+        update_transfer_bar(&file_bar, progress);
+        if progress.total == Some(progress.transferred) {
+            total_bytes += progress.transferred;
+            total_bar.set_message(format!(
+                "{} files, {}",
+                num_files,
+                HumanBytes(total_bytes)
+            ));
+        }
+    };
+
+    let result = if src.starts_with("s3://") {
+        let prefix = ctx.s3_url(src)?;
+        let cache = ctx.open_cache()?;
+        match inventory {
+            Some(inventory) => {
+                let objects = Inventory::read(&ctx.s3_url(inventory)?)?
+                    .objects_under(&prefix)?;
+                horst3::sync_down_listed(
+                    &cache,
+                    &prefix,
+                    objects,
+                    Path::new(dest),
+                    delete,
+                    &mut progress,
+                )
+            }
+            None => horst3::sync_down(
+                &cache,
+                &prefix,
+                Path::new(dest),
+                delete,
+                &mut progress,
+            ),
+        }
+    } else if inventory.is_some() {
+        return Err(Error::InventoryNeedsS3Source);
+    } else if dest.starts_with("s3://") {
+        let prefix = ctx.s3_url(dest)?;
+        horst3::sync_up(Path::new(src), &prefix, delete, &mut progress)
+    } else {
+        return Err(Error::SyncNeedsS3Url);
+    };
+    file_bar.finish_and_clear();
+    total_bar.finish_and_clear();
+    let summary = result?;
+
+    if ctx.is_json() {
+        return print_json(&summary);
+    }
+    println!(
+        "{} transferred, {} unchanged, {} deleted",
+        summary.transferred, summary.unchanged, summary.deleted
+    );
+    print_transfer_summary(
+        summary.bytes_from_cache,
+        summary.bytes_from_network,
+    );
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct WarmFailure {
+    url: String,
+    error: String,
+}
+
+#[derive(Default, Serialize)]
+struct WarmReport {
+    fetched: usize,
+    already_cached: usize,
+    not_cacheable: usize,
+    failed: Vec<WarmFailure>,
+    bytes_fetched: u64,
+}
+
+/// Get the objects to warm from a manifest file or an S3 Inventory
+/// report
+fn warm_entries(
+    ctx: &Context,
+    manifest: Option<&Path>,
+    inventory: Option<&str>,
+) -> Result<Vec<ManifestEntry>, Error> {
+    if let Some(inventory) = inventory {
+        let Inventory { bucket, objects } =
+            Inventory::read(&ctx.s3_url(inventory)?)?;
+        return Ok(objects
+            .into_iter()
+            .map(|object| ManifestEntry {
+                url: S3Url::new(bucket.clone(), object.key),
+                md5sum: None,
+                size: Some(object.size),
+            })
+            .collect());
+    }
+    // clap requires one of the two
+    let manifest = manifest.expect("manifest or inventory");
+    let contents = fs::read_to_string(manifest)
+        .map_err(|err| Error::ReadError(manifest.to_path_buf(), err))?;
+    Ok(parse_manifest(&contents)?)
+}
+
+fn warm(
+    ctx: &Context,
+    manifest: Option<&Path>,
+    inventory: Option<&str>,
+    jobs: usize,
+) -> Result<(), Error> {
+    let mut entries = warm_entries(ctx, manifest, inventory)?;
+    // Entries with the same contents download into the same temporary
+    // file, so only warm each of them once
+    let mut seen = HashSet::new();
+    entries.retain(|entry| {
+        seen.insert(
+            entry
+                .md5sum
+                .clone()
+                .unwrap_or_else(|| entry.url.to_string()),
+        )
+    });
+    let cache = ctx.open_cache()?;
+
+    let bar = if !ctx.show_progress() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(entries.len() as u64)
+    };
+    bar.set_style(
+        ProgressStyle::with_template("[{bar:30}] {pos}/{len} {msg}")
+            .expect("valid template")
+            .progress_chars("=> "),
+    );
+    let queue = Mutex::new(entries.iter());
+    let report = Mutex::new(WarmReport::default());
+    let start = cache.stats()?;
+    let in_flight = Mutex::new(Vec::new());
+    with_stats_snapshots(ctx, &cache, &start, &in_flight, || {
+        thread::scope(|scope| {
+            for _ in 0..jobs.max(1) {
+                scope.spawn(|| loop {
+                    let entry = match queue.lock().unwrap().next() {
+                        Some(entry) => entry,
+                        None => break,
+                    };
+                    let url = entry.url.to_string();
+                    in_flight.lock().unwrap().push(url.clone());
+                    let result =
+                        entry.url.prefetch(&cache, entry.md5sum.as_deref());
+                    in_flight.lock().unwrap().retain(|other| *other != url);
+                    let mut report = report.lock().unwrap();
+                    match result {
+                        Ok(Prefetch::AlreadyCached) => {
+                            report.already_cached += 1
+                        }
+                        Ok(Prefetch::Fetched(num_bytes)) => {
+                            report.fetched += 1;
+                            report.bytes_fetched += num_bytes;
+                        }
+                        Ok(Prefetch::NotCacheable) => report.not_cacheable += 1,
+                        Err(err) => report.failed.push(WarmFailure {
+                            url: entry.url.to_string(),
+                            error: error_chain(&err),
+                        }),
+                    }
+                    bar.set_message(
+                        HumanBytes(report.bytes_fetched).to_string(),
+                    );
+                    bar.inc(1);
+                });
+            }
+        })
+    });
+    bar.finish_and_clear();
+    let report = report.into_inner().unwrap();
+
+    if ctx.is_json() {
+        print_json(&report)?;
+    } else {
+        for failure in &report.failed {
+            eprintln!("failed: {} ({})", failure.url, failure.error);
+        }
+        println!(
+            "fetched {} ({}), {} already cached, {} not cacheable, {} failed",
+            report.fetched,
+            HumanBytes(report.bytes_fetched),
+            report.already_cached,
+            report.not_cacheable,
+            report.failed.len()
+        );
+    }
+    if !report.failed.is_empty() {
+        return Err(Error::WarmFailed(report.failed.len()));
+    }
+    Ok(())
+}
+
+#[derive(Default, Serialize)]
+struct RefreshReport {
+    checked: usize,
+    refreshed: usize,
+    failed: Vec<WarmFailure>,
+    bytes_fetched: u64,
+}
+
+/// Check the sources of frequently used entries for new versions
+///
+/// The cache is only held open for one pass, so that other commands
+/// can use it between passes.
+fn refresh_once(
+    ctx: &Context,
+    min_accesses: u64,
+    start: &Stats,
+) -> Result<RefreshReport, Error> {
+    let cache = ctx.open_cache()?;
+    let filter = EntryFilter {
+        min_accesses: Some(min_accesses),
+        ..Default::default()
+    };
+    let mut sources: Vec<String> = cache
+        .select(&filter)?
+        .into_iter()
+        .filter_map(|entry| entry.source)
+        // Entries copied from local files, SSH hosts or HTTP
+        // repositories are refreshed when they're next requested instead
+        .filter(|source| source.starts_with("s3://"))
+        .collect();
+    sources.sort_unstable();
+    sources.dedup();
+
+    let in_flight = Mutex::new(Vec::new());
+    let report = with_stats_snapshots(ctx, &cache, start, &in_flight, || {
+        let mut report = RefreshReport::default();
+        for source in sources {
+            report.checked += 1;
+            *in_flight.lock().unwrap() = vec![source.clone()];
+            let result = source
+                .parse::<S3Url>()
+                .and_then(|url| url.prefetch(&cache, None));
+            in_flight.lock().unwrap().clear();
+            match result {
+                Ok(Prefetch::Fetched(num_bytes)) => {
+                    report.refreshed += 1;
+                    report.bytes_fetched += num_bytes;
+                }
+                Ok(Prefetch::AlreadyCached) | Ok(Prefetch::NotCacheable) => {}
+                Err(err) => report.failed.push(WarmFailure {
+                    url: source,
+                    error: error_chain(&err),
+                }),
+            }
+        }
+        report
+    });
+    Ok(report)
+}
+
+fn refresh(
+    ctx: &Context,
+    min_accesses: u64,
+    interval: Option<u64>,
+) -> Result<(), Error> {
+    let start = ctx.open_cache()?.stats()?;
+    loop {
+        let report = refresh_once(ctx, min_accesses, &start)?;
+        if ctx.is_json() {
+            print_json(&report)?;
+        } else {
+            for failure in &report.failed {
+                eprintln!("failed: {} ({})", failure.url, failure.error);
+            }
+            println!(
+                "checked {}, refreshed {} ({}), {} failed",
+                report.checked,
+                report.refreshed,
+                HumanBytes(report.bytes_fetched),
+                report.failed.len()
+            );
+        }
+        match interval {
+            Some(interval) => {
+                // The cache isn't open between passes, so there are no
+                // stats to show
+                for _ in 0..interval {
+                    if ctx.take_stats_request() {
+                        warn!("idle until the next refresh pass");
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+            None if !report.failed.is_empty() => {
+                return Err(Error::RefreshFailed(report.failed.len()))
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+struct BackfillReport {
+    already_set: usize,
+    from_etag: usize,
+    downloaded: usize,
+    failed: Vec<WarmFailure>,
+}
+
+fn backfill(
+    ctx: &Context,
+    url: &str,
+    jobs: usize,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let prefix = ctx.s3_url(url)?;
+    let objects = prefix.list()?;
+
+    let bar = if !ctx.show_progress() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(objects.len() as u64)
+    };
+    bar.set_style(
+        ProgressStyle::with_template("[{bar:30}] {pos}/{len}")
+            .expect("valid template")
+            .progress_chars("=> "),
+    );
+    let queue = Mutex::new(objects.iter());
+    let report = Mutex::new(BackfillReport::default());
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let object = match queue.lock().unwrap().next() {
+                    Some(object) => object,
+                    None => break,
+                };
+                let url = S3Url::new(prefix.bucket.clone(), object.key.clone());
+                let result = url.backfill_md5sum(dry_run);
+                let mut report = report.lock().unwrap();
+                match result {
+                    Ok(Backfill::AlreadySet) => report.already_set += 1,
+                    Ok(Backfill::FromEtag(_)) => report.from_etag += 1,
+                    Ok(Backfill::Downloaded(_)) => report.downloaded += 1,
+                    Err(err) => report.failed.push(WarmFailure {
+                        url: url.to_string(),
+                        error: error_chain(&err),
+                    }),
+                }
+                bar.inc(1);
+            });
+        }
+    });
+    bar.finish_and_clear();
+    let report = report.into_inner().unwrap();
+
+    if ctx.is_json() {
+        print_json(&report)?;
+    } else {
+        for failure in &report.failed {
+            eprintln!("failed: {} ({})", failure.url, failure.error);
+        }
+        println!(
+            "{} {} from ETags, {} downloaded, {} already set, {} failed",
+            if dry_run { "would set" } else { "set" },
+            report.from_etag,
+            report.downloaded,
+            report.already_set,
+            report.failed.len()
+        );
+    }
+    if !report.failed.is_empty() {
+        return Err(Error::BackfillFailed(report.failed.len()));
+    }
+    Ok(())
+}
+
+/// A request from git-lfs, one per line of stdin
+#[derive(Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum LfsRequest {
+    Init,
+    Download { oid: String },
+    Upload { oid: String, path: PathBuf },
+    Terminate,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LfsProgress {
+    event: &'static str,
+    oid: String,
+    bytes_so_far: u64,
+    bytes_since_last: u64,
+}
+
+#[derive(Serialize)]
+struct LfsComplete {
+    event: &'static str,
+    oid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<LfsTransferError>,
+}
+
+#[derive(Serialize)]
+struct LfsTransferError {
+    code: i32,
+    message: String,
+}
+
+/// Write a response to git-lfs
+fn write_lfs_response<T: Serialize>(
+    out: &mut dyn Write,
+    response: &T,
+) -> Result<(), Error> {
+    let line = serde_json::to_string(response).map_err(Error::JsonError)?;
+    writeln!(out, "{}", line)
+        .and_then(|_| out.flush())
+        .map_err(Error::LfsIoError)
+}
+
+/// Download an LFS object through the cache to a temporary file,
+/// which git-lfs moves into place
+fn lfs_download(
+    cache: &Cache,
+    url: &S3Url,
+    oid: &str,
+    out: &mut dyn Write,
+) -> Result<PathBuf, Error> {
+    let path = std::env::temp_dir().join(format!("horst3-lfs-{}", oid));
+    let mut last = 0;
+    let mut progress_error = None;
+    let mut progress = |progress: Progress| {
+        let response = LfsProgress {
+            event: "progress",
+            oid: oid.to_string(),
+            bytes_so_far: progress.transferred,
+            bytes_since_last: progress.transferred.saturating_sub(last),
+        };
+        last = progress.transferred;
+        if let Err(err) = write_lfs_response(out, &response) {
+            progress_error.get_or_insert(err);
+        }
+    };
+    let options = DownloadOptions {
+        progress: Some(&mut progress),
+        ..Default::default()
+    };
+    url.download_with_options(cache, &path, options)?;
+    match progress_error {
+        Some(err) => Err(err),
+        None => Ok(path),
+    }
+}
+
+/// Upload an object for git-lfs, writing it through to the cache
+fn lfs_upload(cache: &Cache, url: &S3Url, path: &Path) -> Result<(), Error> {
+    if cache.is_offline() {
+        return Err(Error::Offline);
+    }
+    // Objects are named by their contents, so an existing one is the
+    // same
+    let options = UploadOptions {
+        write_through: Some(cache),
+        skip_if_exists: true,
+    };
+    url.upload_with_options(path, options)?;
+    Ok(())
+}
+
+/// Handle git-lfs requests on stdin until it asks to terminate
+///
+/// Failed transfers are reported to git-lfs rather than ending the
+/// agent, so that git-lfs can retry them.
+fn lfs_agent(ctx: &Context, prefix: &str) -> Result<(), Error> {
+    let prefix = ctx.s3_url(prefix)?;
+    let cache = ctx.open_cache()?;
+    let object_url = |oid: &str| {
+        let key = if prefix.key.is_empty() || prefix.key.ends_with('/') {
+            format!("{}{}", prefix.key, oid)
+        } else {
+            format!("{}/{}", prefix.key, oid)
+        };
+        S3Url::new(prefix.bucket.clone(), key)
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for line in io::stdin().lock().lines() {
+        let line = line.map_err(Error::LfsIoError)?;
+        let request: LfsRequest =
+            serde_json::from_str(&line).map_err(Error::JsonError)?;
+        let (oid, result) = match request {
+            LfsRequest::Init => {
+                writeln!(out, "{{}}")
+                    .and_then(|_| out.flush())
+                    .map_err(Error::LfsIoError)?;
+                continue;
+            }
+            LfsRequest::Terminate => return Ok(()),
+            LfsRequest::Download { oid } => {
+                let result =
+                    lfs_download(&cache, &object_url(&oid), &oid, &mut out)
+                        .map(Some);
+                (oid, result)
+            }
+            LfsRequest::Upload { oid, path } => {
+                let result =
+                    lfs_upload(&cache, &object_url(&oid), &path).map(|_| None);
+                (oid, result)
+            }
+        };
+        let response = match result {
+            Ok(path) => LfsComplete {
+                event: "complete",
+                oid: oid.clone(),
+                path,
+                error: None,
+            },
+            Err(err) => {
+                warn!("failed to transfer {}: {}", oid, error_chain(&err));
+                LfsComplete {
+                    event: "complete",
+                    oid: oid.clone(),
+                    path: None,
+                    error: Some(LfsTransferError {
+                        code: err.exit_code(),
+                        message: error_chain(&err),
+                    }),
+                }
+            }
+        };
+        write_lfs_response(&mut out, &response)?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct StatsReport {
+    entries: usize,
+    usage_bytes: u64,
+    limit_bytes: u64,
+    #[serde(flatten)]
+    stats: Stats,
+    hit_ratio: f64,
+}
+
+fn cache_stats(ctx: &Context) -> Result<(), Error> {
+    let cache = ctx.open_cache()?;
+    let entries = cache.entries()?;
+    let stats = cache.stats()?;
+    let report = StatsReport {
+        entries: entries.len(),
+        usage_bytes: entries.iter().map(|entry| entry.size).sum(),
+        limit_bytes: cache.size_limit(),
+        hit_ratio: stats.hit_ratio(),
+        stats,
+    };
+    if ctx.is_json() {
+        print_json(&report)?;
+    } else {
+        println!("entries:     {}", report.entries);
+        println!(
+            "usage:       {} / {} bytes",
+            report.usage_bytes, report.limit_bytes
+        );
+        println!("hits:        {}", report.stats.hits);
+        println!("misses:      {}", report.stats.misses);
+        println!("hit ratio:   {:.1}%", report.hit_ratio * 100.0);
+        println!("bytes saved: {}", report.stats.hit_bytes);
+        println!("evictions:   {}", report.stats.evictions);
+        for (name, transfers) in [
+            ("hit", &report.stats.hit_transfers),
+            ("miss", &report.stats.miss_transfers),
+            ("direct", &report.stats.direct_transfers),
+        ] {
+            let duration = &transfers.duration_ms;
+            if let Some(p90) = duration.quantile(0.9) {
+                println!(
+                    "{:<6} time: mean {:.0} ms, p90 under {} ms ({} downloads)",
+                    name,
+                    duration.mean(),
+                    p90.saturating_add(1),
+                    duration.count()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct MetricsReport {
+    namespace: String,
+    metrics: Vec<Metric>,
+}
+
+fn cache_publish_metrics(ctx: &Context) -> Result<(), Error> {
+    let cache = ctx.open_cache()?;
+    let usage_bytes = cache.entries()?.iter().map(|entry| entry.size).sum();
+    let report = MetricsReport {
+        namespace: cache.cloudwatch_namespace().to_string(),
+        metrics: cache_metrics(&cache.stats()?, usage_bytes),
+    };
+    put_metrics(&report.namespace, &report.metrics)?;
+    if ctx.is_json() {
+        print_json(&report)?;
+    } else {
+        println!(
+            "published {} metrics to {}",
+            report.metrics.len(),
+            report.namespace
+        );
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct PurgeReport {
+    purged: Vec<CacheEntry>,
+    bytes_freed: u64,
+    dry_run: bool,
+}
+
+fn cache_purge(
+    ctx: &Context,
+    filter: &EntryFilter,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let cache = ctx.open_cache()?;
+    let purged = if dry_run {
+        cache.select(filter)?
+    } else {
+        cache.purge(filter)?
+    };
+    let report = PurgeReport {
+        bytes_freed: purged.iter().map(|entry| entry.size).sum(),
+        purged,
+        dry_run,
+    };
+    if ctx.is_json() {
+        return print_json(&report);
+    }
+    if dry_run {
+        for entry in &report.purged {
+            println!(
+                "would purge {} ({} bytes, {})",
+                entry.md5sum,
+                entry.size,
+                entry.source.as_deref().unwrap_or("unknown source")
+            );
+        }
+        println!(
+            "would purge {} entries, freeing {} bytes",
+            report.purged.len(),
+            report.bytes_freed
+        );
+    } else {
+        println!(
+            "purged {} entries, freed {} bytes",
+            report.purged.len(),
+            report.bytes_freed
+        );
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format a number of seconds as a short age such as "3d"
+fn format_age(secs: u64) -> String {
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m", secs / 60)
+    } else if secs < 24 * 60 * 60 {
+        format!("{}h", secs / (60 * 60))
+    } else {
+        format!("{}d", secs / (24 * 60 * 60))
+    }
+}
+
+fn cache_ls(
+    ctx: &Context,
+    filter: &EntryFilter,
+    sort: SortKey,
+    reverse: bool,
+) -> Result<(), Error> {
+    let cache = ctx.open_cache()?;
+    let now = now();
+    let mut entries: Vec<_> = cache
+        .entries()?
+        .into_iter()
+        .filter(|entry| filter.matches(entry, now))
+        .collect();
+    match sort {
+        SortKey::Hash => entries.sort_by(|a, b| a.md5sum.cmp(&b.md5sum)),
+        SortKey::Size => entries.sort_by_key(|entry| entry.size),
+        SortKey::Access => entries.sort_by_key(|entry| entry.last_access),
+    }
+    if reverse {
+        entries.reverse();
+    }
+    if ctx.is_json() {
+        return print_json(&entries);
+    }
+    for entry in entries {
+        println!(
+            "{:32}  {:>12}  {:>5}  {}{}{}",
+            entry.md5sum,
+            entry.size,
+            format_age(now.saturating_sub(entry.last_access)),
+            entry.source.as_deref().unwrap_or("-"),
+            if entry.pinned { " (pinned)" } else { "" },
+            if entry.linked { " (linked)" } else { "" }
+        );
+        for alias in &entry.aliases {
+            if Some(alias) != entry.source.as_ref() {
+                println!("{:56}also {}", "", alias);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct VerifyReport {
+    checked: usize,
+    corrupt: Vec<String>,
+    fixed: bool,
+}
+
+fn cache_verify(ctx: &Context, fix: bool, jobs: usize) -> Result<(), Error> {
+    let cache = ctx.open_cache()?;
+    let entries = cache.entries()?;
+    // Each md5sum has to be computed in one go, but different entries
+    // can be hashed at the same time
+    let queue = Mutex::new(entries.iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let (index, entry) = match queue.lock().unwrap().next() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = cache.verify(&entry.md5sum);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    let mut corrupt = Vec::new();
+    for (index, result) in results {
+        if result? {
+            continue;
+        }
+        let entry = &entries[index];
+        if !ctx.is_json() {
+            println!(
+                "corrupt: {} ({})",
+                entry.md5sum,
+                entry.source.as_deref().unwrap_or("unknown source")
+            );
+        }
+        if fix {
+            cache.quarantine(&entry.md5sum, "cache verify")?;
+        }
+        corrupt.push(entry.md5sum.clone());
+    }
+    let report = VerifyReport {
+        checked: entries.len(),
+        corrupt,
+        fixed: fix,
+    };
+    if ctx.is_json() {
+        print_json(&report)?;
+    } else {
+        println!(
+            "checked {} entries, {} corrupt",
+            report.checked,
+            report.corrupt.len()
+        );
+    }
+    if !report.corrupt.is_empty() && !fix {
+        return Err(Error::CorruptEntries(report.corrupt.len()));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct UsageGroup {
+    name: String,
+    entries: usize,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct DuReport {
+    by_bucket: Vec<UsageGroup>,
+    by_age: Vec<UsageGroup>,
+}
+
+/// Add an entry to the group with the given name, creating it if needed
+fn add_usage(groups: &mut Vec<UsageGroup>, name: &str, entry: &CacheEntry) {
+    let index = match groups.iter().position(|group| group.name == name) {
+        Some(index) => index,
+        None => {
+            groups.push(UsageGroup {
+                name: name.to_string(),
+                entries: 0,
+                bytes: 0,
+            });
+            groups.len() - 1
+        }
+    };
+    groups[index].entries += 1;
+    groups[index].bytes += entry.size;
+}
+
+fn cache_du(ctx: &Context) -> Result<(), Error> {
+    const DAY: u64 = 24 * 60 * 60;
+    let ages = [("<1d", DAY), ("<7d", 7 * DAY), ("<30d", 30 * DAY)];
+
+    let cache = ctx.open_cache()?;
+    let now = now();
+    let mut by_bucket = Vec::new();
+    // Start with all age groups so that the output is always in order
+    let mut by_age: Vec<_> = ages
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(["older"])
+        .map(|name| UsageGroup {
+            name: name.to_string(),
+            entries: 0,
+            bytes: 0,
+        })
+        .collect();
+    for entry in cache.entries()? {
+        let bucket = entry
+            .source
+            .as_ref()
+            .and_then(|source| source.parse::<S3Url>().ok())
+            .map(|url| url.bucket)
+            .unwrap_or_else(|| "(unknown)".to_string());
+        add_usage(&mut by_bucket, &bucket, &entry);
+
+        let age = now.saturating_sub(entry.last_access);
+        let age_name = ages
+            .iter()
+            .find(|(_, limit)| age < *limit)
+            .map(|(name, _)| *name)
+            .unwrap_or("older");
+        add_usage(&mut by_age, age_name, &entry);
+    }
+    by_bucket.sort_by_key(|group| Reverse(group.bytes));
+
+    let report = DuReport { by_bucket, by_age };
+    if ctx.is_json() {
+        return print_json(&report);
+    }
+    println!("by bucket:");
+    for group in &report.by_bucket {
+        println!(
+            "  {:>10}  {:>6} entries  {}",
+            HumanBytes(group.bytes).to_string(),
+            group.entries,
+            group.name
+        );
+    }
+    println!("by last access:");
+    for group in &report.by_age {
+        println!(
+            "  {:>10}  {:>6} entries  {}",
+            HumanBytes(group.bytes).to_string(),
+            group.entries,
+            group.name
+        );
+    }
+    Ok(())
+}
+
+fn cache_quarantine(ctx: &Context, clear: bool) -> Result<(), Error> {
+    let cache = ctx.open_cache()?;
+    let entries = if clear {
+        cache.clear_quarantine()?
+    } else {
+        cache.quarantined()?
+    };
+    if ctx.is_json() {
+        return print_json(&entries);
+    }
+    let now = now();
+    for entry in &entries {
+        println!(
+            "{:32}  {:>12}  {:>5}  {} (contents {}, found by {})",
+            entry.md5sum,
+            entry.size,
+            format_age(now.saturating_sub(entry.time)),
+            entry.source.as_deref().unwrap_or("-"),
+            entry.actual_md5sum,
+            entry.reason
+        );
+    }
+    if clear {
+        println!("deleted {} quarantined entries", entries.len());
+    }
+    Ok(())
+}
+
+fn cache_gc(ctx: &Context) -> Result<(), Error> {
+    let cache = ctx.open_cache()?;
+    let summary = cache.gc()?;
+    if ctx.is_json() {
+        return print_json(&summary);
+    }
+    for entry in &summary.expired {
+        println!(
+            "expired {} ({})",
+            entry.md5sum,
+            entry.source.as_deref().unwrap_or("unknown source")
+        );
+    }
+    println!(
+        "deleted {} temporary files, {} expired entries, {} orphaned \
+         metadata files, {} stale links, {} forgotten aliases; freed {}",
+        summary.temporary_files,
+        summary.expired.len(),
+        summary.orphaned_metadata,
+        summary.stale_links,
+        summary.forgotten_aliases,
+        HumanBytes(summary.bytes_freed)
+    );
+    Ok(())
+}
+
+/// Get the md5sum of a cache entry given either the md5sum itself or
+/// the URL of the object
+fn resolve_md5sum(
+    ctx: &Context,
+    cache: &Cache,
+    target: &str,
+) -> Result<String, Error> {
+    let target = ctx.expand_url(target)?;
+    if !target.starts_with("s3://") {
+        return Ok(target);
+    }
+    // URLs downloaded through the cache don't need a request to S3
+    if let Some(md5sum) = cache.lookup_url(&target)? {
+        return Ok(md5sum);
+    }
+    if cache.is_offline() {
+        return Err(Error::Offline);
+    }
+    let url: S3Url = target.parse()?;
+    url.md5sum()?
+        .ok_or_else(|| Error::NoMd5sum(url.to_string()))
+}
+
+#[derive(Serialize)]
+struct PinReport {
+    md5sum: String,
+    pinned: bool,
+}
+
+fn cache_pin(
+    ctx: &Context,
+    targets: &[String],
+    pinned: bool,
+) -> Result<(), Error> {
+    let cache = ctx.open_cache()?;
+    let mut reports = Vec::new();
+    for target in targets {
+        let md5sum = resolve_md5sum(ctx, &cache, target)?;
+        cache.set_pinned(&md5sum, pinned)?;
+        if !ctx.is_json() {
+            let verb = if pinned { "pinned" } else { "unpinned" };
+            println!("{} {}", verb, md5sum);
+        }
+        reports.push(PinReport { md5sum, pinned });
+    }
+    if ctx.is_json() {
+        print_json(&reports)?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ConfigInitReport {
+    path: PathBuf,
+}
+
+fn config_init(ctx: &Context, force: bool) -> Result<(), Error> {
+    let path = Configuration::path_with_overrides(&ctx.overrides)?;
+    if path.exists() && !force {
+        return Err(Error::ConfigExists(path));
+    }
+    Configuration::write_default(&path)?;
+    if ctx.is_json() {
+        return print_json(&ConfigInitReport { path });
+    }
+    println!("wrote {}", path.display());
+    Ok(())
+}
+
+fn config_show(ctx: &Context) -> Result<(), Error> {
+    let settings = Configuration::read_settings_with_overrides(&ctx.overrides)?;
+    if ctx.is_json() {
+        return print_json(&settings);
+    }
+    for setting in settings {
+        println!(
+            "{} = {}  # {}",
+            setting.key,
+            setting.value.as_deref().unwrap_or(""),
+            setting.source
+        );
+    }
+    Ok(())
+}
+
+fn doctor(ctx: &Context) -> Result<(), Error> {
+    let checks = horst3::run_checks(&ctx.overrides);
+    if ctx.is_json() {
+        print_json(&checks)?;
+    } else {
+        for check in &checks {
+            let status = match check.status {
+                CheckStatus::Ok => "ok",
+                CheckStatus::Warning => "warning",
+                CheckStatus::Failed => "FAILED",
+            };
+            println!("{:<7}  {}: {}", status, check.name, check.message);
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Failed)
+        .count();
+    if failed > 0 {
+        return Err(Error::DoctorFailed(failed));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ConfigCheckReport {
+    path: PathBuf,
+    problems: Vec<String>,
+}
+
+fn config_check(ctx: &Context, path: Option<&Path>) -> Result<(), Error> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => Configuration::path_with_overrides(&ctx.overrides)?,
+    };
+    let contents = fs::read_to_string(&path)
+        .map_err(|err| Error::ReadError(path.clone(), err))?;
+    let problems = check_config(&contents);
+    if ctx.is_json() {
+        print_json(&ConfigCheckReport {
+            path: path.clone(),
+            problems: problems.clone(),
+        })?;
+    } else {
+        for problem in &problems {
+            println!("{}: {}", path.display(), problem);
+        }
+    }
+    if !problems.is_empty() {
+        return Err(Error::InvalidConfig(problems.len()));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    error: String,
+}
+
+/// Sends log messages to syslog or the systemd journal through their
+/// Unix sockets
+#[cfg(unix)]
+struct SocketLogger {
+    socket: std::os::unix::net::UnixDatagram,
+    target: LogTarget,
+    level: LevelFilter,
+}
+
+#[cfg(unix)]
+impl SocketLogger {
+    fn connect(
+        target: LogTarget,
+        level: LevelFilter,
+    ) -> io::Result<SocketLogger> {
+        let path = match target {
+            LogTarget::Journald => "/run/systemd/journal/socket",
+            _ => "/dev/log",
+        };
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(SocketLogger {
+            socket,
+            target,
+            level,
+        })
+    }
+
+    /// Format a message in the journal's native protocol
+    ///
+    /// Values containing newlines are sent with their length instead of
+    /// being terminated by a newline.
+    fn journald_message(record: &log::Record, priority: u8) -> Vec<u8> {
+        let mut fields = vec![
+            ("MESSAGE", record.args().to_string()),
+            ("PRIORITY", priority.to_string()),
+            ("SYSLOG_IDENTIFIER", "horst3".to_string()),
+            ("SYSLOG_PID", process::id().to_string()),
+            ("HORST3_TARGET", record.target().to_string()),
+        ];
+        if let Some(file) = record.file() {
+            fields.push(("CODE_FILE", file.to_string()));
+        }
+        if let Some(line) = record.line() {
+            fields.push(("CODE_LINE", line.to_string()));
+        }
+        let mut message = Vec::new();
+        for (name, value) in fields {
+            message.extend_from_slice(name.as_bytes());
+            if value.contains('\n') {
+                message.push(b'\n');
+                message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                message.push(b'=');
+            }
+            message.extend_from_slice(value.as_bytes());
+            message.push(b'\n');
+        }
+        message
+    }
+}
+
+#[cfg(unix)]
+impl log::Log for SocketLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let priority = match record.level() {
+            log::Level::Error => 3,
+            log::Level::Warn => 4,
+            log::Level::Info => 6,
+            log::Level::Debug | log::Level::Trace => 7,
+        };
+        let message = match self.target {
+            LogTarget::Journald => {
+                SocketLogger::journald_message(record, priority)
+            }
+            // Facility 1 is user-level messages
+            _ => format!(
+                "<{}>horst3[{}]: {}",
+                8 + priority,
+                process::id(),
+                record.args()
+            )
+            .into_bytes(),
+        };
+        // There is nowhere left to report a failure to log
+        let _ = self.socket.send(&message);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Send log messages to syslog or the journal, returning false if
+/// that isn't possible
+#[cfg(unix)]
+fn init_socket_logging(target: LogTarget, level: LevelFilter) -> bool {
+    match SocketLogger::connect(target, level) {
+        Ok(logger) => {
+            log::set_max_level(level);
+            log::set_boxed_logger(Box::new(logger)).is_ok()
+        }
+        Err(err) => {
+            eprintln!("failed to connect to {:?}: {}", target, err);
+            false
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn init_socket_logging(target: LogTarget, _level: LevelFilter) -> bool {
+    eprintln!("logging to {:?} is only supported on Unix", target);
+    false
+}
+
+/// A log message in the JSON log format
+#[derive(Serialize)]
+struct JsonLogLine {
+    timestamp: String,
+    level: String,
+    target: String,
+    message: String,
+    pid: u32,
+    file: Option<String>,
+    line: Option<u32>,
+}
+
+/// Set up logging to the configured target, falling back to stderr
+///
+/// Warnings and errors are logged by default. RUST_LOG takes precedence
+/// over the flags when logging to stderr, but is ignored for syslog and
+/// the journal. The format only applies to stderr; syslog and the
+/// journal have their own.
+fn init_logging(
+    verbose: u8,
+    quiet: bool,
+    target: LogTarget,
+    format: LogFormat,
+) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    if target != LogTarget::Stderr && init_socket_logging(target, level) {
+        return;
+    }
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).parse_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = JsonLogLine {
+                timestamp: buf.timestamp_millis().to_string(),
+                level: record.level().to_string(),
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+                pid: process::id(),
+                file: record.file().map(str::to_string),
+                line: record.line(),
+            };
+            let json =
+                serde_json::to_string(&line).map_err(io::Error::other)?;
+            writeln!(buf, "{}", json)
+        });
+    }
+    builder.init();
+}
+
+/// Export spans to an OpenTelemetry collector if one is configured
+#[cfg(feature = "otel")]
+fn init_otel() -> Option<horst3::OtelGuard> {
+    std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+    match horst3::init_otel("horst3") {
+        Ok(guard) => Some(guard),
+        Err(err) => {
+            warn!("failed to set up OpenTelemetry: {}", error_chain(&err));
+            None
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let overrides = Overrides {
+        config_path: cli.config.clone(),
+        cache_path: cli.cache_dir.clone(),
+        cache_size_limit: cli.cache_size_limit.clone(),
+        offline: cli.offline,
+    };
+    let ctx = Context {
+        output: cli.output,
+        quiet: cli.quiet,
+        // As with the settings below, problems with the configuration
+        // file are reported by the command itself
+        aliases: Aliases::read_with_overrides(&overrides).unwrap_or_default(),
+        variables: {
+            let mut variables = Variables::new();
+            for (name, value) in &cli.vars {
+                variables.insert(name.clone(), value.clone());
+            }
+            variables
+        },
+        overrides,
+        stats_requested: Arc::new(AtomicBool::new(false)),
+    };
+    // Problems with the configuration file are reported by the command
+    // itself, once logging is set up
+    let (log_target, log_format, offline) =
+        Configuration::read_settings_with_overrides(&ctx.overrides)
+            .map(|settings| {
+                let conf = Configuration::from_settings(&settings);
+                (conf.log_target, conf.log_format, conf.offline)
+            })
+            .unwrap_or((
+                LogTarget::default(),
+                LogFormat::default(),
+                cli.offline,
+            ));
+    init_logging(cli.verbose, cli.quiet, log_target, log_format);
+    #[cfg(feature = "otel")]
+    let otel = init_otel();
+    register_stats_signal(&ctx.stats_requested);
+    let result = match &cli.command {
+        command if offline && command.needs_s3() => Err(Error::Offline),
+        Command::Get {
+            url,
+            dest,
+            no_cache,
+            refresh,
+            verify,
+            if_exists,
+            no_clobber,
+            preserve_mtime,
+            mode,
+            link,
+        } => {
+            let options = DownloadOptions {
+                bypass_cache: *no_cache,
+                force_refresh: *refresh,
+                verify: *verify,
+                overwrite: match if_exists {
+                    _ if *no_clobber => Overwrite::Never,
+                    IfExists::Overwrite => Overwrite::Always,
+                    IfExists::Error => Overwrite::Never,
+                    IfExists::SkipUnchanged => Overwrite::SkipUnchanged,
+                    IfExists::Backup => Overwrite::Backup,
+                },
+                preserve_mtime: *preserve_mtime,
+                mode: *mode,
+                output: match link {
+                    None => OutputMode::Copy,
+                    Some(LinkArg::Symlink) => OutputMode::Symlink,
+                    Some(LinkArg::Hardlink) => OutputMode::Hardlink,
+                },
+                ..Default::default()
+            };
+            get(&ctx, url, dest, options)
+        }
+        Command::Put {
+            src,
+            url,
+            write_through,
+            skip_if_exists,
+        } => put(&ctx, src, url, *write_through, *skip_if_exists),
+        Command::Sync {
+            src,
+            dest,
+            delete,
+            inventory,
+        } => sync(&ctx, src, dest, *delete, inventory.as_deref()),
+        Command::Cat { url } => cat(&ctx, url),
+        Command::Head { url } => head(&ctx, url),
+        Command::Ls { url } => ls(&ctx, url),
+        Command::Rm { url, cache_only } => rm(&ctx, url, *cache_only),
+        Command::Latest {
+            url,
+            pattern,
+            dest,
+            by_key,
+        } => {
+            let by = if *by_key {
+                LatestBy::Key
+            } else {
+                LatestBy::LastModified
+            };
+            latest(&ctx, url, pattern, dest.as_deref(), by)
+        }
+        Command::Presign { url, expires } => presign(&ctx, url, *expires),
+        Command::Manifest { url } => manifest(&ctx, url),
+        Command::Warm {
+            manifest,
+            inventory,
+            jobs,
+        } => warm(&ctx, manifest.as_deref(), inventory.as_deref(), *jobs),
+        Command::Refresh {
+            min_accesses,
+            interval,
+        } => refresh(&ctx, *min_accesses, *interval),
+        Command::Backfill { url, jobs, dry_run } => {
+            backfill(&ctx, url, *jobs, *dry_run)
+        }
+        Command::LfsAgent { url } => lfs_agent(&ctx, url),
+        Command::Doctor => doctor(&ctx),
+        Command::Cache { command } => match command {
+            CacheCommand::Stats => cache_stats(&ctx),
+            CacheCommand::PublishMetrics => cache_publish_metrics(&ctx),
+            CacheCommand::Purge {
+                filter,
+                dry_run,
+                force,
+            } => {
+                let mut filter = filter.to_filter();
+                if !force {
+                    filter.pinned = Some(false);
+                    filter.linked = Some(false);
+                }
+                cache_purge(&ctx, &filter, *dry_run)
+            }
+            CacheCommand::Ls {
+                filter,
+                sort,
+                reverse,
+            } => cache_ls(&ctx, &filter.to_filter(), *sort, *reverse),
+            CacheCommand::Verify { fix, jobs } => {
+                cache_verify(&ctx, *fix, *jobs)
+            }
+            CacheCommand::Quarantine { clear } => {
+                cache_quarantine(&ctx, *clear)
+            }
+            CacheCommand::Du => cache_du(&ctx),
+            CacheCommand::Gc => cache_gc(&ctx),
+            CacheCommand::Pin { targets } => cache_pin(&ctx, targets, true),
+            CacheCommand::Unpin { targets } => cache_pin(&ctx, targets, false),
+        },
+        Command::Config { command } => match command {
+            ConfigCommand::Init { force } => config_init(&ctx, *force),
+            ConfigCommand::Show => config_show(&ctx),
+            ConfigCommand::Check { path } => {
+                config_check(&ctx, path.as_deref())
+            }
+        },
+    };
+    if let Err(err) = result {
+        let error = error_chain(&err);
+        if ctx.is_json() {
+            // Errors are part of the output so that scripts only have
+            // to parse stdout
+            let _ = print_json(&ErrorReport { error });
+        } else {
+            eprintln!("error: {}", error);
+        }
+        // process::exit skips destructors, so flush spans first
+        #[cfg(feature = "otel")]
+        drop(otel);
+        process::exit(err.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A context for an offline cache in `dir`
+    fn offline_context(dir: &Path) -> Context {
+        let config_path = dir.join("horst3.conf");
+        fs::write(&config_path, "").unwrap();
+        Context {
+            output: OutputFormat::Text,
+            quiet: true,
+            overrides: Overrides {
+                config_path: Some(config_path),
+                cache_path: Some(dir.join("cache").display().to_string()),
+                cache_size_limit: None,
+                offline: true,
+            },
+            aliases: Aliases::default(),
+            variables: Variables::new(),
+            stats_requested: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[test]
+    fn test_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = offline_context(dir.path());
+        let url = "s3://bucket/key";
+
+        assert!(matches!(rm(&ctx, url, false), Err(Error::Offline)));
+        assert!(rm(&ctx, url, true).is_ok());
+
+        let targets = [url.to_string()];
+        assert!(matches!(
+            cache_pin(&ctx, &targets, true),
+            Err(Error::Offline)
+        ));
+
+        let cache = ctx.open_cache().unwrap();
+        let src = dir.path().join("src");
+        fs::write(&src, "hello").unwrap();
+        assert!(matches!(
+            lfs_upload(&cache, &url.parse().unwrap(), &src),
+            Err(Error::Offline)
+        ));
+    }
+}
@@ -1,40 +1,276 @@
+use crate::audit::{AuditAction, AuditLog};
+use crate::clock::{Clock, SystemClock};
 use crate::configuration::{Configuration, ConfigurationError};
-use lockfile::Lockfile;
+use crate::failover::FailoverStore;
+use crate::hash::md5sum_file;
+use crate::head_cache::HeadCache;
+use crate::key_index::KeyIndex;
+use crate::metadata::EntryMetadata;
+use crate::quarantine::{QuarantinedEntry, QUARANTINE_DIR};
+use crate::remote::{RemoteCache, RemotePolicy};
+use crate::s3::{
+    BypassReason, DownloadSummary, HeadObject, HeadObjectMetadata, OutputMode,
+    S3Error, S3Url,
+};
+use crate::shared;
+use crate::stats::Stats;
+use crate::store::{AwsCli, ObjectStore, Offline};
+use filetime::FileTime;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, SystemTimeError};
-use std::{fs, io};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTimeError};
+use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum CacheError {
-    ConfigurationError(ConfigurationError),
-    CopyError(io::Error),
-    LockError(io::Error),
-    MakeSpaceError(io::Error),
-    ScanError(io::Error),
-    TimestampError(SystemTimeError),
-    TouchError(io::Error),
+    #[error("failed to load the configuration")]
+    ConfigurationError(#[from] ConfigurationError),
+    #[error("failed to copy {}", .0.display())]
+    CopyError(PathBuf, #[source] io::Error),
+    #[error("failed to create cache directory {}", .0.display())]
+    CreateDirError(PathBuf, #[source] io::Error),
+    #[error("failed to link {} to the cache", .0.display())]
+    LinkError(PathBuf, #[source] io::Error),
+    #[error("failed to hash {}", .0.display())]
+    HashError(PathBuf, #[source] io::Error),
+    #[error("failed to add {} to the cache", .0.display())]
+    InsertError(PathBuf, #[source] io::Error),
+    #[error("failed to lock {}", .0.display())]
+    LockError(PathBuf, #[source] io::Error),
+    #[error("failed to access metadata file {}", .0.display())]
+    MetadataError(PathBuf, #[source] io::Error),
+    #[error("{0} is not in the cache")]
+    NotInCache(String),
+    #[error("failed to quarantine {}", .0.display())]
+    QuarantineError(PathBuf, #[source] io::Error),
+    #[error("failed to remove {}", .0.display())]
+    RemoveError(PathBuf, #[source] io::Error),
+    #[error("failed to scan {}", .0.display())]
+    ScanError(PathBuf, #[source] io::Error),
+    #[error("failed to share {} with the group", .0.display())]
+    ShareError(PathBuf, #[source] io::Error),
+    #[error("failed to write to audit log {}", .0.display())]
+    AuditError(PathBuf, #[source] io::Error),
+    #[error("failed to set up a per-user cache in {}", .0.display())]
+    UserDirError(PathBuf, #[source] io::Error),
+    #[error("failed to access stats file {}", .0.display())]
+    StatsError(PathBuf, #[source] io::Error),
+    #[error("system clock is before the Unix epoch")]
+    TimestampError(#[from] SystemTimeError),
+    #[error("failed to set access time of {}", .0.display())]
+    TouchError(PathBuf, #[source] io::Error),
+}
+
+/// Make an error constructor that records the path involved, for use
+/// with `map_err`
+fn with_path(
+    path: &Path,
+    variant: fn(PathBuf, io::Error) -> CacheError,
+) -> impl FnOnce(io::Error) -> CacheError + '_ {
+    move |err| variant(path.to_path_buf(), err)
+}
+
+/// An object stored in the cache
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CacheEntry {
+    pub md5sum: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Last access time in seconds since the Unix epoch
+    pub last_access: u64,
+    /// URL the object was downloaded from, if known
+    pub source: Option<String>,
+    /// All URLs known to have the object's contents, including
+    /// `source`
+    pub aliases: Vec<String>,
+    /// Pinned entries are never evicted
+    pub pinned: bool,
+    /// Number of times the object was requested through the cache
+    pub accesses: u64,
+    /// Replica the object was downloaded from because the source's
+    /// bucket failed, if any
+    pub served_by: Option<String>,
+    /// Linked entries are not evicted while a download's link to them
+    /// exists
+    pub linked: bool,
+}
+
+/// Criteria for selecting cache entries
+///
+/// An entry matches if it matches all of the criteria that are set, so
+/// the default filter matches everything.
+#[derive(Clone, Debug, Default)]
+pub struct EntryFilter {
+    /// Only entries last accessed more than this many seconds ago
+    pub older_than_in_s: Option<u64>,
+    /// Only entries larger than this many bytes
+    pub larger_than_in_bytes: Option<u64>,
+    /// Only entries downloaded from this bucket
+    pub bucket: Option<String>,
+    /// Only entries that are (or aren't) pinned
+    pub pinned: Option<bool>,
+    /// Only entries that are (or aren't) linked
+    pub linked: Option<bool>,
+    /// Only entries requested at least this many times
+    pub min_accesses: Option<u64>,
+}
+
+impl EntryFilter {
+    /// Check if an entry matches, given the current time in seconds
+    /// since the Unix epoch
+    pub fn matches(&self, entry: &CacheEntry, now: u64) -> bool {
+        if let Some(age) = self.older_than_in_s {
+            if entry.last_access.saturating_add(age) >= now {
+                return false;
+            }
+        }
+        if let Some(size) = self.larger_than_in_bytes {
+            if entry.size <= size {
+                return false;
+            }
+        }
+        if let Some(bucket) = &self.bucket {
+            let source_bucket = entry
+                .source
+                .as_ref()
+                .and_then(|source| source.parse::<S3Url>().ok())
+                .map(|url| url.bucket);
+            if source_bucket.as_ref() != Some(bucket) {
+                return false;
+            }
+        }
+        if let Some(pinned) = self.pinned {
+            if entry.pinned != pinned {
+                return false;
+            }
+        }
+        if let Some(linked) = self.linked {
+            if entry.linked != linked {
+                return false;
+            }
+        }
+        if let Some(accesses) = self.min_accesses {
+            if entry.accesses < accesses {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// What `Cache::gc` reclaimed
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct GcSummary {
+    /// Leftover temporary files from interrupted downloads
+    pub temporary_files: usize,
+    /// Entries that weren't accessed within the TTL
+    pub expired: Vec<CacheEntry>,
+    /// Metadata files whose entry no longer exists
+    pub orphaned_metadata: usize,
+    /// Recorded links to entries that were deleted or replaced
+    pub stale_links: usize,
+    /// URLs forgotten because their contents are no longer cached
+    pub forgotten_aliases: usize,
+    /// Total bytes freed
+    pub bytes_freed: u64,
 }
 
 pub struct Cache {
     conf: Configuration,
-    #[allow(dead_code)]
-    lock: Lockfile,
+    clock: Arc<dyn Clock>,
+    store: Arc<dyn ObjectStore>,
+    audit_log: Option<AuditLog>,
+    /// Shared by all downloads so that they skip a broken server
+    /// together
+    remote: Option<RemoteCache>,
+    head_cache: Option<HeadCache>,
 }
 
-fn get_current_timestamp_in_s() -> Result<u64, CacheError> {
-    let d = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .map_err(CacheError::TimestampError)?;
-    Ok(d.as_secs())
+/// A lock on one entry, held while it's being added to the cache
+///
+/// It's released when dropped, or by the OS if the process dies.
+pub(crate) struct EntryLock {
+    #[allow(dead_code)]
+    file: File,
 }
 
 /// Set a file's atime without changing its mtime
+///
+/// The atime is always set explicitly rather than relying on reads to
+/// update it, since many filesystems (and Windows by default) don't.
 fn set_file_atime(path: &Path, atime: u64) -> Result<(), CacheError> {
-    let (_, mtime) =
-        utime::get_file_times(path).map_err(CacheError::TouchError)?;
-    utime::set_file_times(path, atime, mtime)
-        .map_err(CacheError::TouchError)?;
-    Ok(())
+    filetime::set_file_atime(path, FileTime::from_unix_time(atime as i64, 0))
+        .map_err(with_path(path, CacheError::TouchError))
+}
+
+/// Move `src_path` to `dst_path` on another filesystem by way of
+/// `staging_path`, which must be on the same filesystem as `dst_path`
+fn move_across_filesystems(
+    src_path: &Path,
+    staging_path: &Path,
+    dst_path: &Path,
+) -> io::Result<()> {
+    fs::copy(src_path, staging_path)?;
+    File::open(staging_path)?.sync_all()?;
+    fs::rename(staging_path, dst_path)?;
+    fs::remove_file(src_path)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symlinks are only supported on Unix",
+    ))
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// Without inode numbers, assume that a file at the link's path is
+/// still the hardlink so that the entry stays protected
+#[cfg(not(unix))]
+fn is_same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+/// Check if `link` is still a symlink or hardlink to `entry_path`
+fn is_live_link(link: &Path, entry_path: &Path) -> bool {
+    let link_metadata = match fs::symlink_metadata(link) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    if link_metadata.file_type().is_symlink() {
+        return match (fs::read_link(link), fs::canonicalize(entry_path)) {
+            (Ok(target), Ok(entry_path)) => target == entry_path,
+            _ => false,
+        };
+    }
+    match fs::metadata(entry_path) {
+        Ok(entry_metadata) => is_same_file(&link_metadata, &entry_metadata),
+        Err(_) => false,
+    }
+}
+
+/// Check if a file in the cache directory is a cached object
+///
+/// Objects are named by their md5sum. Everything else (temporary
+/// files, stats, the `lock` file of older versions) has either a
+/// reserved name or an extension.
+fn is_entry_name(name: &str) -> bool {
+    name != "lock" && !name.contains('.')
 }
 
 impl Cache {
@@ -44,35 +280,346 @@ impl Cache {
         Cache::open_with_configuration(conf)
     }
 
-    fn open_with_configuration(conf: Configuration) -> Result<Cache, CacheError> {
-        let lock = Lockfile::create(conf.cache_path.join("lock"))
-            .map_err(CacheError::LockError)?;
-        Ok(Cache { conf, lock })
+    /// Open the cache described by a configuration
+    ///
+    /// The cache directory is created if it doesn't exist yet. With
+    /// `per_user_cache` the cache is a subdirectory of `cache_path`
+    /// named after the current user's ID.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(path = %conf.cache_path.display()))
+    )]
+    pub fn open_with_configuration(
+        mut conf: Configuration,
+    ) -> Result<Cache, CacheError> {
+        let mut dirs = vec![&mut conf.cache_path];
+        if let Some(temp_path) = &mut conf.temp_path {
+            dirs.push(temp_path);
+        }
+        for dir in dirs {
+            if conf.per_user_cache {
+                *dir = shared::user_dir(dir)
+                    .map_err(with_path(dir, CacheError::UserDirError))?;
+            }
+            fs::create_dir_all(&dir)
+                .map_err(with_path(dir, CacheError::CreateDirError))?;
+            if conf.shared_cache {
+                shared::share_dir(dir, conf.shared_cache_group.as_deref())
+                    .map_err(with_path(dir, CacheError::ShareError))?;
+            }
+        }
+        let audit_log = if conf.audit_log {
+            Some(AuditLog::new(conf.cache_path.join("audit.log")))
+        } else {
+            None
+        };
+        let remote_cache_url =
+            conf.remote_cache_url.as_deref().filter(|_| !conf.offline);
+        let remote = remote_cache_url.map(|url| {
+            RemoteCache::new(
+                url,
+                RemotePolicy {
+                    retries: conf.remote_cache_retries,
+                    timeout: conf
+                        .remote_cache_timeout_in_s
+                        .map(Duration::from_secs),
+                    max_failures: conf.remote_cache_max_failures,
+                },
+            )
+        });
+        let store: Arc<dyn ObjectStore> = if conf.bucket_replicas.is_empty() {
+            Arc::new(AwsCli)
+        } else {
+            Arc::new(FailoverStore::new(
+                Arc::new(AwsCli),
+                conf.bucket_replicas.clone(),
+            ))
+        };
+        let head_cache = conf.head_cache_ttl_in_s.map(|ttl_in_s| {
+            let path = if conf.head_cache_on_disk {
+                Some(conf.cache_path.join("heads.txt"))
+            } else {
+                None
+            };
+            HeadCache::new(ttl_in_s, path)
+        });
+        Ok(Cache {
+            conf,
+            clock: Arc::new(SystemClock),
+            store,
+            audit_log,
+            remote,
+            head_cache,
+        })
     }
 
-    fn root(&self) -> &Path {
+    /// Use a different clock for access times and the TTL
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Cache {
+        self.clock = clock;
+        self
+    }
+
+    /// Download objects from a different store than S3
+    pub fn with_object_store(mut self, store: Arc<dyn ObjectStore>) -> Cache {
+        self.store = store;
+        self
+    }
+
+    pub(crate) fn object_store(&self) -> &dyn ObjectStore {
+        if self.conf.offline {
+            return &Offline;
+        }
+        self.store.as_ref()
+    }
+
+    /// Check if the cache is in offline mode, where S3 is never
+    /// contacted
+    pub fn is_offline(&self) -> bool {
+        self.conf.offline
+    }
+
+    /// Make up an object's head from the cache, without asking S3
+    ///
+    /// Only URLs in the key index whose contents are still cached can be
+    /// found. Just the size and md5sum are known, so the last-modified
+    /// time is left empty.
+    fn offline_head(&self, url: &S3Url) -> Result<HeadObject, S3Error> {
+        let key = url.to_string();
+        let md5sum = match self.lookup_url(&key)? {
+            Some(md5sum) if self.contains(&md5sum) => md5sum,
+            _ => return Err(S3Error::Offline(key)),
+        };
+        let path = self.path(&md5sum);
+        let size = fs::metadata(&path)
+            .map_err(with_path(&path, CacheError::MetadataError))?
+            .len();
+        Ok(HeadObject {
+            last_modified: String::new(),
+            content_length: size,
+            etag: format!("\"{}\"", md5sum),
+            storage_class: None,
+            metadata: HeadObjectMetadata {
+                md5sum: Some(md5sum),
+            },
+        })
+    }
+
+    /// Get an object's head-object result
+    ///
+    /// With `head_cache_ttl` set, a result fetched less than that long
+    /// ago is reused unless `refresh` is set. Offline, the result comes
+    /// from the cache's key index instead.
+    pub(crate) fn head_object(
+        &self,
+        url: &S3Url,
+        refresh: bool,
+    ) -> Result<HeadObject, S3Error> {
+        if self.conf.offline {
+            return self.offline_head(url);
+        }
+        let head_cache = match &self.head_cache {
+            Some(head_cache) => head_cache,
+            None => return self.fetch_head(url),
+        };
+        let key = url.to_string();
+        let now = self.now_in_s()?;
+        if !refresh {
+            match head_cache.get(&key, now) {
+                Ok(Some(head)) => {
+                    debug!("using cached head of {}", key);
+                    return Ok(head);
+                }
+                Ok(None) => {}
+                Err(err) => warn!("failed to read cached heads: {}", err),
+            }
+        }
+        let head = self.fetch_head(url)?;
+        match head_cache.insert(&key, &head, now) {
+            Ok(()) => {
+                if let Some(path) = head_cache.path() {
+                    if let Err(err) = self.share(path) {
+                        warn!("{}", err);
+                    }
+                }
+            }
+            Err(err) => warn!("failed to cache head of {}: {}", key, err),
+        }
+        Ok(head)
+    }
+
+    /// Ask S3 for an object's head, taking the md5sum from a checksum
+    /// file if the object doesn't have one and `checksum_sidecars` is
+    /// set
+    fn fetch_head(&self, url: &S3Url) -> Result<HeadObject, S3Error> {
+        let mut head = self.store.head_object(url)?;
+        if head.metadata.md5sum.is_none() && self.conf.checksum_sidecars {
+            head.metadata.md5sum = url.sidecar_md5sum(self.store.as_ref());
+        }
+        Ok(head)
+    }
+
+    /// Check if the md5sum of objects without one is taken from
+    /// checksum files next to them
+    pub fn checksum_sidecars(&self) -> bool {
+        self.conf.checksum_sidecars
+    }
+
+    /// Forget an object's cached head-object result, e.g. because it
+    /// was just replaced
+    pub(crate) fn forget_head(&self, url: &S3Url) {
+        if let Some(head_cache) = &self.head_cache {
+            let key = url.to_string();
+            let now = match self.now_in_s() {
+                Ok(now) => now,
+                Err(err) => {
+                    warn!("{}", err);
+                    return;
+                }
+            };
+            if let Err(err) = head_cache.remove(&key, now) {
+                warn!("failed to forget head of {}: {}", key, err);
+            }
+        }
+    }
+
+    fn now_in_s(&self) -> Result<u64, CacheError> {
+        Ok(self.clock.now_in_s()?)
+    }
+
+    /// Make a file writable by the group if the cache is shared
+    fn share(&self, path: &Path) -> Result<(), CacheError> {
+        if self.conf.shared_cache {
+            shared::share_file(path)
+                .map_err(with_path(path, CacheError::ShareError))?;
+        }
+        Ok(())
+    }
+
+    /// Record a change to the cache if the audit log is enabled
+    fn audit(
+        &self,
+        action: AuditAction,
+        md5sum: &str,
+        size: u64,
+    ) -> Result<(), CacheError> {
+        if let Some(audit_log) = &self.audit_log {
+            let now = self.now_in_s()?;
+            audit_log
+                .append(now, action, md5sum, size)
+                .map_err(with_path(audit_log.path(), CacheError::AuditError))?;
+            self.share(audit_log.path())?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn root(&self) -> &Path {
         &self.conf.cache_path
     }
 
+    /// Directory for downloads in progress
+    pub(crate) fn temp_root(&self) -> &Path {
+        self.conf.temp_path.as_deref().unwrap_or(self.root())
+    }
+
+    fn stats_path(&self) -> PathBuf {
+        self.root().join("stats.txt")
+    }
+
+    fn key_index_path(&self) -> PathBuf {
+        self.root().join("keys.txt")
+    }
+
+    fn key_index(&self) -> Result<KeyIndex, CacheError> {
+        let path = self.key_index_path();
+        KeyIndex::load(&path)
+            .map_err(with_path(&path, CacheError::MetadataError))
+    }
+
+    fn save_key_index(&self, index: &KeyIndex) -> Result<(), CacheError> {
+        let path = self.key_index_path();
+        index
+            .save(&path)
+            .map_err(with_path(&path, CacheError::MetadataError))?;
+        self.share(&path)
+    }
+
+    pub fn size_limit(&self) -> u64 {
+        self.conf.cache_size_limit_in_bytes
+    }
+
+    pub fn ttl_in_s(&self) -> Option<u64> {
+        self.conf.cache_ttl_in_s
+    }
+
+    pub fn remote_cache_url(&self) -> Option<&str> {
+        self.conf.remote_cache_url.as_deref()
+    }
+
+    pub(crate) fn remote_cache(&self) -> Option<&RemoteCache> {
+        self.remote.as_ref()
+    }
+
+    pub fn prefetch_siblings(&self) -> usize {
+        self.conf.prefetch_siblings
+    }
+
+    pub fn prefetch_patterns(&self) -> &[String] {
+        &self.conf.prefetch_patterns
+    }
+
+    pub fn cloudwatch_namespace(&self) -> &str {
+        &self.conf.cloudwatch_namespace
+    }
+
     pub fn path(&self, md5sum: &str) -> PathBuf {
         self.root().join(md5sum)
     }
 
     pub fn temporary_path(&self, md5sum: &str) -> PathBuf {
         let name = format!("{}.tmp", md5sum);
+        self.temp_root().join(name)
+    }
+
+    fn metadata_path(&self, md5sum: &str) -> PathBuf {
+        let name = format!("{}.meta", md5sum);
         self.root().join(name)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), ret)
+    )]
     pub fn contains(&self, md5sum: &str) -> bool {
         self.path(md5sum).exists()
     }
 
     fn touch(&self, md5sum: &str) -> Result<(), CacheError> {
         let path = self.path(md5sum);
-        let now = get_current_timestamp_in_s()?;
-        set_file_atime(&path, now)
+        let now = self.now_in_s()?;
+        match set_file_atime(&path, now) {
+            // Only the owner of a file can set its times, so in a
+            // shared cache other users' entries keep their access time
+            Err(CacheError::TouchError(_, err))
+                if self.conf.shared_cache
+                    && err.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                debug!("can't update access time of {}: {}", md5sum, err);
+                Ok(())
+            }
+            result => result,
+        }
     }
 
+    /// Copy an object out of the cache
+    ///
+    /// This relies on `fs::copy` using the cheapest copy the platform
+    /// offers: on macOS it clones the file with `fclonefileat` when
+    /// both paths are on the same APFS volume, and on Linux
+    /// `copy_file_range` shares extents on filesystems with reflink
+    /// support. Both fall back to copying the data.
+    ///
+    /// A symlink at `dst_path` is replaced rather than copied through,
+    /// since it may point into the cache.
     pub fn copy(
         &self,
         md5sum: &str,
@@ -80,64 +627,879 @@ impl Cache {
     ) -> Result<(), CacheError> {
         let src_path = self.path(md5sum);
         self.touch(md5sum)?;
-        fs::copy(src_path, dst_path).map_err(CacheError::CopyError)?;
+        if let Ok(metadata) = fs::symlink_metadata(dst_path) {
+            if metadata.file_type().is_symlink() {
+                fs::remove_file(dst_path)
+                    .map_err(with_path(dst_path, CacheError::CopyError))?;
+            }
+        }
+        fs::copy(&src_path, dst_path)
+            .map_err(with_path(&src_path, CacheError::CopyError))?;
         Ok(())
     }
 
-    fn get_least_recently_used(&self) -> Result<Vec<(u64, PathBuf)>, CacheError> {
-        let mut lru = Vec::new();
+    /// Put an object at `dst_path`, replacing any file there
+    ///
+    /// Unless `mode` is `OutputMode::Copy`, the destination shares the
+    /// cache entry, so it must not be modified. Use `add_link` to
+    /// protect the entry from eviction once the link is in its final
+    /// place.
+    pub fn place(
+        &self,
+        md5sum: &str,
+        dst_path: &Path,
+        mode: OutputMode,
+    ) -> Result<(), CacheError> {
+        if mode == OutputMode::Copy {
+            return self.copy(md5sum, dst_path);
+        }
+        let src_path = self.path(md5sum);
+        self.touch(md5sum)?;
+        match fs::remove_file(dst_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(CacheError::LinkError(dst_path.to_path_buf(), err));
+            }
+            _ => {}
+        }
+        let result = if mode == OutputMode::Symlink {
+            fs::canonicalize(&src_path)
+                .and_then(|target| symlink(&target, dst_path))
+        } else {
+            fs::hard_link(&src_path, dst_path)
+        };
+        match result {
+            Err(err) if mode == OutputMode::Hardlink => {
+                debug!(
+                    "failed to hardlink {} ({}), copying instead",
+                    dst_path.display(),
+                    err
+                );
+                fs::copy(&src_path, dst_path)
+                    .map_err(with_path(&src_path, CacheError::CopyError))?;
+                Ok(())
+            }
+            result => {
+                result.map_err(with_path(dst_path, CacheError::LinkError))
+            }
+        }
+    }
+
+    /// Record a link to an entry, so that the entry isn't evicted while
+    /// the link exists
+    pub fn add_link(
+        &self,
+        md5sum: &str,
+        link_path: &Path,
+    ) -> Result<(), CacheError> {
+        let link_path = if link_path.is_absolute() {
+            link_path.to_path_buf()
+        } else {
+            std::env::current_dir()
+                .map_err(with_path(link_path, CacheError::LinkError))?
+                .join(link_path)
+        };
+        self.update_metadata(md5sum, |metadata| {
+            if !metadata.links.contains(&link_path) {
+                metadata.links.push(link_path);
+            }
+        })
+    }
+
+    /// Write an object's contents, e.g. to stdout
+    ///
+    /// Returns the number of bytes written.
+    pub fn copy_to(
+        &self,
+        md5sum: &str,
+        out: &mut dyn Write,
+    ) -> Result<u64, CacheError> {
+        self.touch(md5sum)?;
+        let path = self.path(md5sum);
+        let mut file = File::open(&path)
+            .map_err(with_path(&path, CacheError::CopyError))?;
+        io::copy(&mut file, out)
+            .map_err(with_path(&path, CacheError::CopyError))
+    }
+
+    /// Add a copy of a local file to the cache
+    ///
+    /// Returns false if the file shouldn't be cached, either because
+    /// there is not enough space for it or because of its size.
+    pub fn insert(
+        &self,
+        md5sum: &str,
+        src_path: &Path,
+    ) -> Result<bool, CacheError> {
+        if self.contains(md5sum) {
+            return self.touch(md5sum).map(|_| true);
+        }
+        let metadata = fs::metadata(src_path)
+            .map_err(with_path(src_path, CacheError::InsertError))?;
+        if self.admit(metadata.len())?.is_some() {
+            return Ok(false);
+        }
+        let _lock = self.lock_entry(md5sum)?;
+        let tmp_path = self.temporary_path(md5sum);
+        fs::copy(src_path, &tmp_path)
+            .map_err(with_path(src_path, CacheError::InsertError))?;
+        self.finish_temporary(md5sum)?;
+        Ok(true)
+    }
+
+    /// Wait for any other download of an object into the cache to
+    /// finish, and keep new ones from starting until the lock is dropped
+    ///
+    /// The lock is on `<md5sum>.lock` in the temporary directory, so
+    /// it works across processes sharing the cache. Only downloads of
+    /// the same object wait for each other, and the caller should check
+    /// whether the object was cached in the meantime.
+    pub(crate) fn lock_entry(
+        &self,
+        md5sum: &str,
+    ) -> Result<EntryLock, CacheError> {
+        let path = self.lock_path(md5sum);
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .map_err(with_path(&path, CacheError::LockError))?;
+            self.share(&path)?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    info!("waiting for another download of {}", md5sum);
+                    file.lock()
+                        .map_err(with_path(&path, CacheError::LockError))?;
+                }
+                Err(TryLockError::Error(err)) => {
+                    return Err(CacheError::LockError(path, err))
+                }
+            }
+            // gc deletes lock files that aren't held, so the file may
+            // have been deleted before it was locked. Locking it then
+            // doesn't keep out a download that creates a new one.
+            let locked = file
+                .metadata()
+                .map_err(with_path(&path, CacheError::LockError))?;
+            match fs::metadata(&path) {
+                Ok(current) if is_same_file(&locked, &current) => {
+                    return Ok(EntryLock { file })
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(CacheError::LockError(path, err)),
+            }
+        }
+    }
+
+    fn lock_path(&self, md5sum: &str) -> PathBuf {
+        self.temp_root().join(format!("{}.lock", md5sum))
+    }
+
+    /// Check if another download holds the lock on an entry, without
+    /// waiting for it
+    fn is_entry_locked(&self, md5sum: &str) -> Result<bool, CacheError> {
+        let path = self.lock_path(md5sum);
+        let file = match OpenOptions::new().write(true).open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(false)
+            }
+            Err(err) => return Err(CacheError::LockError(path, err)),
+        };
+        match file.try_lock() {
+            Ok(()) => Ok(false),
+            Err(TryLockError::WouldBlock) => Ok(true),
+            Err(TryLockError::Error(err)) => {
+                Err(CacheError::LockError(path, err))
+            }
+        }
+    }
+
+    /// Move a completed temporary file into place as an entry
+    ///
+    /// Entries are named by their contents, so if the entry already
+    /// exists it is kept and the temporary file is deleted instead.
+    /// Replacing it would fail on Windows while the entry is open.
+    pub(crate) fn finish_temporary(
+        &self,
+        md5sum: &str,
+    ) -> Result<(), CacheError> {
+        let tmp_path = self.temporary_path(md5sum);
+        if self.contains(md5sum) {
+            fs::remove_file(&tmp_path)
+                .map_err(with_path(&tmp_path, CacheError::RemoveError))?;
+        } else {
+            let path = self.path(md5sum);
+            match fs::rename(&tmp_path, &path) {
+                Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                    // temp_path is on another filesystem. Copy next to
+                    // the entry first, so that the entry never appears
+                    // half-written.
+                    let staging_path =
+                        self.root().join(format!("{}.tmp", md5sum));
+                    move_across_filesystems(&tmp_path, &staging_path, &path)
+                }
+                result => result,
+            }
+            .map_err(with_path(&tmp_path, CacheError::InsertError))?;
+            self.share(&path)?;
+            let size = fs::metadata(&path)
+                .map_err(with_path(&path, CacheError::InsertError))?
+                .len();
+            self.audit(AuditAction::Insert, md5sum, size)?;
+        }
+        self.touch(md5sum)
+    }
+
+    /// Get the extra information stored alongside an object
+    pub fn metadata(&self, md5sum: &str) -> Result<EntryMetadata, CacheError> {
+        let path = self.metadata_path(md5sum);
+        EntryMetadata::load(&path)
+            .map_err(with_path(&path, CacheError::MetadataError))
+    }
+
+    fn update_metadata<F: FnOnce(&mut EntryMetadata)>(
+        &self,
+        md5sum: &str,
+        f: F,
+    ) -> Result<(), CacheError> {
+        let mut metadata = self.metadata(md5sum)?;
+        f(&mut metadata);
+        let path = self.metadata_path(md5sum);
+        metadata
+            .save(&path)
+            .map_err(with_path(&path, CacheError::MetadataError))?;
+        self.share(&path)
+    }
+
+    /// Record the URL an object was downloaded from
+    ///
+    /// The URL is also added to the object's aliases.
+    pub fn set_source(
+        &self,
+        md5sum: &str,
+        source: &str,
+    ) -> Result<(), CacheError> {
+        self.update_metadata(md5sum, |metadata| {
+            metadata.source = Some(source.to_string())
+        })?;
+        self.add_alias(md5sum, source)
+    }
+
+    /// Record that a URL has an object's contents
+    pub fn add_alias(&self, md5sum: &str, url: &str) -> Result<(), CacheError> {
+        let mut index = self.key_index()?;
+        if index.insert(url, md5sum) {
+            self.save_key_index(&index)?;
+        }
+        Ok(())
+    }
+
+    /// Get the md5sum of a URL's contents, if it was downloaded through
+    /// the cache before
+    ///
+    /// This doesn't ask S3, so the object may have changed since.
+    /// Check `contains` to find out whether the contents are still
+    /// cached.
+    pub fn lookup_url(&self, url: &str) -> Result<Option<String>, CacheError> {
+        Ok(self.key_index()?.get(url).map(str::to_string))
+    }
+
+    /// Record that an object was downloaded from a bucket's replica
+    pub fn set_served_by(
+        &self,
+        md5sum: &str,
+        replica: &str,
+    ) -> Result<(), CacheError> {
+        self.update_metadata(md5sum, |metadata| {
+            metadata.served_by = Some(replica.to_string())
+        })
+    }
+
+    /// Count a request for an object served through the cache
+    pub fn record_access(&self, md5sum: &str) -> Result<(), CacheError> {
+        self.update_metadata(md5sum, |metadata| metadata.accesses += 1)
+    }
+
+    /// Pin or unpin an object
+    ///
+    /// Pinned objects are skipped when making space for new objects.
+    pub fn set_pinned(
+        &self,
+        md5sum: &str,
+        pinned: bool,
+    ) -> Result<(), CacheError> {
+        if !self.contains(md5sum) {
+            return Err(CacheError::NotInCache(md5sum.to_string()));
+        }
+        self.update_metadata(md5sum, |metadata| metadata.pinned = pinned)
+    }
+
+    /// Delete an object and its metadata from the cache
+    pub fn remove(&self, md5sum: &str) -> Result<(), CacheError> {
+        self.remove_with_action(md5sum, AuditAction::Remove)
+    }
+
+    fn remove_with_action(
+        &self,
+        md5sum: &str,
+        action: AuditAction,
+    ) -> Result<(), CacheError> {
+        let path = self.path(md5sum);
+        let size = fs::metadata(&path)
+            .map_err(with_path(&path, CacheError::RemoveError))?
+            .len();
+        fs::remove_file(&path)
+            .map_err(with_path(&path, CacheError::RemoveError))?;
+        self.remove_metadata(md5sum)?;
+        self.audit(action, md5sum, size)
+    }
+
+    fn remove_metadata(&self, md5sum: &str) -> Result<(), CacheError> {
+        let metadata_path = self.metadata_path(md5sum);
+        match fs::remove_file(&metadata_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(CacheError::RemoveError(metadata_path, err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn quarantine_dir(&self) -> PathBuf {
+        self.root().join(QUARANTINE_DIR)
+    }
+
+    /// Move a corrupt entry out of the cache into the quarantine
+    /// directory, with a report saying what found the corruption
+    ///
+    /// Quarantined entries don't count towards the size limit. They are
+    /// kept until `clear_quarantine` is called.
+    pub fn quarantine(
+        &self,
+        md5sum: &str,
+        reason: &str,
+    ) -> Result<QuarantinedEntry, CacheError> {
+        let path = self.path(md5sum);
+        let size = fs::metadata(&path)
+            .map_err(with_path(&path, CacheError::QuarantineError))?
+            .len();
+        let actual_md5sum = md5sum_file(&path)
+            .map_err(with_path(&path, CacheError::HashError))?;
+        let dir = self.quarantine_dir();
+        fs::create_dir_all(&dir)
+            .map_err(with_path(&dir, CacheError::CreateDirError))?;
+        if self.conf.shared_cache {
+            shared::share_dir(&dir, self.conf.shared_cache_group.as_deref())
+                .map_err(with_path(&dir, CacheError::ShareError))?;
+        }
+        let time = self.now_in_s()?;
+        let entry = QuarantinedEntry {
+            md5sum: md5sum.to_string(),
+            actual_md5sum,
+            path: dir.join(format!("{}-{}", md5sum, time)),
+            size,
+            source: self.metadata(md5sum)?.source,
+            time,
+            reason: reason.to_string(),
+        };
+        fs::rename(&path, &entry.path)
+            .map_err(with_path(&path, CacheError::QuarantineError))?;
+        let report_path = QuarantinedEntry::report_path(&entry.path);
+        entry
+            .save()
+            .map_err(with_path(&report_path, CacheError::QuarantineError))?;
+        self.share(&report_path)?;
+        self.remove_metadata(md5sum)?;
+        self.audit(AuditAction::Quarantine, md5sum, size)?;
+        Ok(entry)
+    }
+
+    /// Get the quarantined entries, oldest first
+    pub fn quarantined(&self) -> Result<Vec<QuarantinedEntry>, CacheError> {
+        let dir = self.quarantine_dir();
+        let read_dir = match fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Vec::new())
+            }
+            Err(err) => return Err(CacheError::ScanError(dir, err)),
+        };
+        let mut entries = Vec::new();
+        for entry in read_dir {
+            let path = entry
+                .map_err(with_path(&dir, CacheError::ScanError))?
+                .path();
+            if !QuarantinedEntry::is_entry_path(&path) {
+                continue;
+            }
+            entries.push(
+                QuarantinedEntry::load(&path)
+                    .map_err(with_path(&path, CacheError::ScanError))?,
+            );
+        }
+        entries.sort_unstable_by_key(|entry| entry.time);
+        Ok(entries)
+    }
+
+    /// Delete all quarantined entries
+    pub fn clear_quarantine(
+        &self,
+    ) -> Result<Vec<QuarantinedEntry>, CacheError> {
+        let entries = self.quarantined()?;
+        for entry in &entries {
+            let report_path = QuarantinedEntry::report_path(&entry.path);
+            for path in [&entry.path, &report_path] {
+                fs::remove_file(path)
+                    .map_err(with_path(path, CacheError::RemoveError))?;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Get all objects matching the filter
+    ///
+    /// This is what `purge` would delete.
+    pub fn select(
+        &self,
+        filter: &EntryFilter,
+    ) -> Result<Vec<CacheEntry>, CacheError> {
+        let now = self.now_in_s()?;
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| filter.matches(entry, now))
+            .collect())
+    }
+
+    /// Delete all objects matching the filter
+    ///
+    /// Returns the entries that were deleted.
+    pub fn purge(
+        &self,
+        filter: &EntryFilter,
+    ) -> Result<Vec<CacheEntry>, CacheError> {
+        let purged = self.select(filter)?;
+        for entry in &purged {
+            self.remove(&entry.md5sum)?;
+        }
+        Ok(purged)
+    }
+
+    /// Delete the entries of an object
+    ///
+    /// An entry belongs to the object if it was downloaded from the
+    /// object's URL, or if it has the object's md5sum (when known).
+    /// Pinned entries are deleted too. Returns the deleted entries.
+    pub fn invalidate(
+        &self,
+        source: &str,
+        md5sum: Option<&str>,
+    ) -> Result<Vec<CacheEntry>, CacheError> {
+        let mut invalidated = Vec::new();
+        for entry in self.entries()? {
+            if entry.source.as_deref() == Some(source)
+                || Some(entry.md5sum.as_str()) == md5sum
+            {
+                self.remove(&entry.md5sum)?;
+                invalidated.push(entry);
+            }
+        }
+        Ok(invalidated)
+    }
+
+    /// Re-hash an object and check that it matches its md5sum
+    pub fn verify(&self, md5sum: &str) -> Result<bool, CacheError> {
+        let path = self.path(md5sum);
+        let actual = md5sum_file(&path)
+            .map_err(with_path(&path, CacheError::HashError))?;
+        Ok(actual == md5sum)
+    }
+
+    /// Get all objects currently in the cache
+    pub fn entries(&self) -> Result<Vec<CacheEntry>, CacheError> {
+        let index = self.key_index()?;
+        let aliases = index.urls_by_md5sum();
+        let mut entries = Vec::new();
         for entry in fs::read_dir(self.root())
-            .map_err(CacheError::ScanError)?
+            .map_err(with_path(self.root(), CacheError::ScanError))?
         {
-            let entry = entry.map_err(CacheError::ScanError)?;
-            if entry.file_name() == "lock" {
+            let entry =
+                entry.map_err(with_path(self.root(), CacheError::ScanError))?;
+            let md5sum = match entry.file_name().into_string() {
+                Ok(name) if is_entry_name(&name) => name,
+                _ => continue,
+            };
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // Removed by another thread or process since the
+                // directory was read
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(CacheError::ScanError(entry.path(), err))
+                }
+            };
+            if !metadata.is_file() {
                 continue;
             }
             let path = entry.path();
-            let (atime, _) = utime::get_file_times(&path)
-                .map_err(CacheError::ScanError)?;
-            lru.push((atime, path));
+            let atime = FileTime::from_last_access_time(&metadata);
+            let entry_metadata = self.metadata(&md5sum)?;
+            let linked = entry_metadata
+                .links
+                .iter()
+                .any(|link| is_live_link(link, &path));
+            let entry_aliases = aliases
+                .get(md5sum.as_str())
+                .map(|urls| urls.iter().map(|url| url.to_string()).collect())
+                .unwrap_or_default();
+            entries.push(CacheEntry {
+                md5sum,
+                path,
+                size: metadata.len(),
+                last_access: atime.unix_seconds().max(0) as u64,
+                source: entry_metadata.source,
+                aliases: entry_aliases,
+                pinned: entry_metadata.pinned,
+                accesses: entry_metadata.accesses,
+                served_by: entry_metadata.served_by,
+                linked,
+            });
         }
-        lru.sort_unstable();
-        Ok(lru)
+        Ok(entries)
     }
 
-    pub fn make_space(&self, num_bytes: u64) -> Result<bool, CacheError> {
+    /// Clean up the cache directory
+    ///
+    /// This deletes leftover temporary files, metadata of entries that
+    /// no longer exist, and entries that weren't accessed within the
+    /// configured TTL (except for pinned and linked entries). Links to
+    /// entries that no longer exist are forgotten, as are the aliases
+    /// of objects that are no longer cached.
+    pub fn gc(&self) -> Result<GcSummary, CacheError> {
+        let mut summary = GcSummary::default();
+
+        // Other processes may be downloading into the cache at the same
+        // time. Their temporary files are kept while they hold the lock
+        // on the entry, and so are the lock files they hold. The rest
+        // are left over from interrupted downloads.
+        // Metadata is only kept in the cache directory itself.
+        let mut entries = Vec::new();
+        let mut dirs = vec![self.root()];
+        if self.temp_root() != self.root() {
+            dirs.push(self.temp_root());
+        }
+        for dir in dirs {
+            entries.extend(
+                fs::read_dir(dir)
+                    .map_err(with_path(dir, CacheError::ScanError))?
+                    .map(|entry| (dir, entry)),
+            );
+        }
+        for (dir, entry) in entries {
+            let entry = entry.map_err(with_path(dir, CacheError::ScanError))?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let size = entry
+                .metadata()
+                .map_err(with_path(&entry.path(), CacheError::ScanError))?
+                .len();
+            if let Some(stem) = name.strip_suffix(".tmp") {
+                if self.is_entry_locked(stem)? {
+                    continue;
+                }
+                fs::remove_file(entry.path()).map_err(with_path(
+                    &entry.path(),
+                    CacheError::RemoveError,
+                ))?;
+                summary.temporary_files += 1;
+                summary.bytes_freed += size;
+            } else if name.ends_with(".lock") {
+                // The file is deleted while locked, and lock_entry
+                // starts over if it locked a deleted file
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(entry.path())
+                    .map_err(with_path(&entry.path(), CacheError::LockError))?;
+                match file.try_lock() {
+                    Ok(()) => {}
+                    Err(TryLockError::WouldBlock) => continue,
+                    Err(TryLockError::Error(err)) => {
+                        return Err(CacheError::LockError(entry.path(), err))
+                    }
+                }
+                fs::remove_file(entry.path()).map_err(with_path(
+                    &entry.path(),
+                    CacheError::RemoveError,
+                ))?;
+            } else if let Some(md5sum) = name.strip_suffix(".meta") {
+                if dir == self.root() && !self.contains(md5sum) {
+                    fs::remove_file(entry.path()).map_err(with_path(
+                        &entry.path(),
+                        CacheError::RemoveError,
+                    ))?;
+                    summary.orphaned_metadata += 1;
+                    summary.bytes_freed += size;
+                }
+            }
+        }
+
+        for entry in self.entries()? {
+            let links = self.metadata(&entry.md5sum)?.links;
+            let (live, stale): (Vec<_>, Vec<_>) = links
+                .into_iter()
+                .partition(|link| is_live_link(link, &entry.path));
+            if !stale.is_empty() {
+                summary.stale_links += stale.len();
+                self.update_metadata(&entry.md5sum, |metadata| {
+                    metadata.links = live
+                })?;
+            }
+        }
+
+        if let Some(ttl) = self.conf.cache_ttl_in_s {
+            let filter = EntryFilter {
+                older_than_in_s: Some(ttl),
+                pinned: Some(false),
+                linked: Some(false),
+                ..Default::default()
+            };
+            summary.expired = self.purge(&filter)?;
+            summary.bytes_freed +=
+                summary.expired.iter().map(|entry| entry.size).sum::<u64>();
+        }
+
+        let mut index = self.key_index()?;
+        summary.forgotten_aliases =
+            index.retain(|md5sum| self.contains(md5sum));
+        if summary.forgotten_aliases > 0 {
+            self.save_key_index(&index)?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Get the hit/miss counters
+    pub fn stats(&self) -> Result<Stats, CacheError> {
+        let path = self.stats_path();
+        Stats::load(&path).map_err(with_path(&path, CacheError::StatsError))
+    }
+
+    fn update_stats<F: FnOnce(&mut Stats)>(
+        &self,
+        f: F,
+    ) -> Result<(), CacheError> {
+        let mut stats = self.stats()?;
+        f(&mut stats);
+        let path = self.stats_path();
+        stats
+            .save(&path)
+            .map_err(with_path(&path, CacheError::StatsError))?;
+        self.share(&path)
+    }
+
+    /// Count a download that was served from the cache
+    pub fn record_hit(&self, num_bytes: u64) -> Result<(), CacheError> {
+        self.update_stats(|stats| {
+            stats.hits += 1;
+            stats.hit_bytes += num_bytes;
+        })
+    }
+
+    /// Count a download that was fetched into the cache
+    pub fn record_miss(&self, num_bytes: u64) -> Result<(), CacheError> {
+        self.update_stats(|stats| {
+            stats.misses += 1;
+            stats.miss_bytes += num_bytes;
+        })
+    }
+
+    /// Run a download, adding its duration and size to the stats
+    pub(crate) fn record_download<E>(
+        &self,
+        download: impl FnOnce() -> Result<DownloadSummary, E>,
+    ) -> Result<DownloadSummary, E> {
+        let start = Instant::now();
+        let summary = download()?;
+        let duration = start.elapsed();
+        if let Err(err) =
+            self.update_stats(|stats| stats.record_download(&summary, duration))
+        {
+            warn!("failed to update cache stats: {}", err);
+        }
+        Ok(summary)
+    }
+
+    /// Get the entries `make_space` would evict, without evicting them
+    ///
+    /// Returns None if the object can't fit in the cache even after
+    /// evicting all entries that aren't pinned or linked.
+    pub fn plan_eviction(
+        &self,
+        num_bytes: u64,
+    ) -> Result<Option<Vec<CacheEntry>>, CacheError> {
+        let limit = self.conf.cache_size_limit_in_bytes;
+
         // Check if object is bigger than the cache limit
-        if num_bytes > self.conf.cache_size_limit_in_bytes {
-            return Ok(false);
+        if num_bytes > limit {
+            return Ok(None);
         }
 
-        let map = self.get_least_recently_used()?;
+        let mut entries = self.entries()?;
+        entries.sort_unstable_by_key(|entry| entry.last_access);
+        let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
 
-        let mut num_bytes_freed = 0;
-        for (_, path) in map.iter() {
-            let metadata =
-                fs::metadata(path).map_err(CacheError::MakeSpaceError)?;
-            let size = metadata.len();
-            fs::remove_file(path).map_err(CacheError::MakeSpaceError)?;
-            num_bytes_freed += size;
-            if num_bytes_freed >= num_bytes {
-                return Ok(true);
+        // Evict least recently used entries until the object fits
+        let mut evicted = Vec::new();
+        for entry in entries
+            .into_iter()
+            .filter(|entry| !entry.pinned && !entry.linked)
+        {
+            if total_size + num_bytes <= limit {
+                break;
             }
+            total_size -= entry.size;
+            evicted.push(entry);
         }
 
-        return Ok(false);
+        // Pinned and linked entries may take up too much space
+        if total_size + num_bytes > limit {
+            return Ok(None);
+        }
+        Ok(Some(evicted))
+    }
+
+    /// Evict least recently used entries until an object of the given
+    /// size fits
+    ///
+    /// Returns false if the object can't fit in the cache. Nothing is
+    /// evicted in that case.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn make_space(&self, num_bytes: u64) -> Result<bool, CacheError> {
+        match self.plan_eviction(num_bytes)? {
+            Some(evicted) => {
+                if !evicted.is_empty() {
+                    let bytes: u64 = evicted.iter().map(|e| e.size).sum();
+                    debug!(
+                        "evicted {} bytes ({} entries) to make space for {} \
+                         bytes",
+                        bytes,
+                        evicted.len(),
+                        num_bytes
+                    );
+                    let count = evicted.len() as u64;
+                    if let Err(err) = self.update_stats(|stats| {
+                        stats.evictions += count;
+                        stats.evicted_bytes += bytes;
+                    }) {
+                        warn!("failed to update cache stats: {}", err);
+                    }
+                }
+                for entry in evicted {
+                    debug!("evicting {} ({} bytes)", entry.md5sum, entry.size);
+                    match self
+                        .remove_with_action(&entry.md5sum, AuditAction::Evict)
+                    {
+                        // Already evicted by another thread or process
+                        Err(CacheError::RemoveError(_, err))
+                            if err.kind() == io::ErrorKind::NotFound => {}
+                        result => result?,
+                    }
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Make space for an object of the given size if it should be
+    /// cached
+    ///
+    /// Returns why the object should bypass the cache instead: objects
+    /// outside `min_cached_size` and `max_cached_size`, and objects
+    /// that don't fit, aren't cached.
+    pub(crate) fn admit(
+        &self,
+        num_bytes: u64,
+    ) -> Result<Option<BypassReason>, CacheError> {
+        if let Some(reason) = self.size_bypass_reason(num_bytes) {
+            return Ok(Some(reason));
+        }
+        if !self.make_space(num_bytes)? {
+            return Ok(Some(BypassReason::TooLarge));
+        }
+        Ok(None)
+    }
+
+    /// Check why an object of the given size would bypass the cache,
+    /// like `admit` but without evicting anything
+    pub(crate) fn would_admit(
+        &self,
+        num_bytes: u64,
+    ) -> Result<Option<BypassReason>, CacheError> {
+        if let Some(reason) = self.size_bypass_reason(num_bytes) {
+            return Ok(Some(reason));
+        }
+        if self.plan_eviction(num_bytes)?.is_none() {
+            return Ok(Some(BypassReason::TooLarge));
+        }
+        Ok(None)
+    }
+
+    fn size_bypass_reason(&self, num_bytes: u64) -> Option<BypassReason> {
+        if let Some(min) = self.conf.min_cached_size_in_bytes {
+            if num_bytes < min {
+                return Some(BypassReason::BelowMinCachedSize);
+            }
+        }
+        if let Some(max) = self.conf.max_cached_size_in_bytes {
+            if num_bytes > max {
+                return Some(BypassReason::AboveMaxCachedSize);
+            }
+        }
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::configuration::test_configuration;
+    use crate::fake::FakeClock;
+    use std::sync::mpsc;
+    use std::thread;
+
+    impl Cache {
+        fn get_least_recently_used(
+            &self,
+        ) -> Result<Vec<(u64, PathBuf)>, CacheError> {
+            let mut lru: Vec<_> = self
+                .entries()?
+                .into_iter()
+                .map(|entry| (entry.last_access, entry.path))
+                .collect();
+            lru.sort_unstable();
+            Ok(lru)
+        }
+    }
+
+    fn open_test_cache(path: &Path, size_limit: u64) -> Cache {
+        let conf = Configuration {
+            cache_size_limit_in_bytes: size_limit,
+            cache_path: path.to_path_buf(),
+            ..test_configuration(path)
+        };
+        Cache::open_with_configuration(conf).unwrap()
+    }
 
     #[test]
     fn test_cache() {
         let dir = tempfile::tempdir().unwrap();
-        let conf = Configuration {
-            cache_size_limit_in_bytes: 2,
-            cache_path: dir.path().to_path_buf(),
-        };
-        let cache = Cache::open_with_configuration(conf).unwrap();
+        let cache = open_test_cache(dir.path(), 2);
         let mut lru = Vec::new();
         assert_eq!(cache.get_least_recently_used().unwrap(), lru);
 
@@ -154,11 +1516,414 @@ mod tests {
         assert_eq!(cache.get_least_recently_used().unwrap(), lru);
 
         // Can't make space for a file that's bigger than the cache
+        assert_eq!(cache.plan_eviction(3).unwrap(), None);
         assert_eq!(cache.make_space(3).unwrap(), false);
 
+        // Planning doesn't delete anything
+        let plan = cache.plan_eviction(1).unwrap().unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].path, lru[0].1);
+        assert_eq!(cache.get_least_recently_used().unwrap(), lru);
+
         // This should delete file1
         assert_eq!(cache.make_space(1).unwrap(), true);
         lru.remove(0);
         assert_eq!(cache.get_least_recently_used().unwrap(), lru);
+
+        // There's already room for this, nothing should be deleted
+        assert!(cache.make_space(1).unwrap());
+        assert_eq!(cache.get_least_recently_used().unwrap(), lru);
+
+        // Too big to insert
+        let src_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path().join("src");
+        fs::write(&src, "abc").unwrap();
+        assert!(!cache.insert("abc", &src).unwrap());
+        assert!(!cache.contains("abc"));
+
+        fs::write(&src, "a").unwrap();
+        assert!(cache.insert("abc", &src).unwrap());
+        assert!(cache.contains("abc"));
+        assert_eq!(fs::read_to_string(cache.path("abc")).unwrap(), "a");
+        let mut out = Vec::new();
+        assert_eq!(cache.copy_to("abc", &mut out).unwrap(), 1);
+        assert_eq!(out, b"a");
+
+        // Non-entry files are not part of the cache
+        fs::write(cache.temporary_path("def"), "a").unwrap();
+        cache.record_hit(1).unwrap();
+        let md5sums: Vec<_> = cache
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.md5sum)
+            .collect();
+        assert_eq!(md5sums.len(), 2);
+        assert!(md5sums.contains(&"abc".to_string()));
+        assert!(md5sums.contains(&"test2".to_string()));
+    }
+
+    #[test]
+    fn test_admit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = open_test_cache(dir.path(), 10);
+        cache.conf.min_cached_size_in_bytes = Some(2);
+        cache.conf.max_cached_size_in_bytes = Some(5);
+        assert_eq!(
+            cache.admit(1).unwrap(),
+            Some(BypassReason::BelowMinCachedSize)
+        );
+        assert_eq!(cache.admit(2).unwrap(), None);
+        assert_eq!(cache.admit(5).unwrap(), None);
+        assert_eq!(
+            cache.admit(6).unwrap(),
+            Some(BypassReason::AboveMaxCachedSize)
+        );
+
+        cache.conf.max_cached_size_in_bytes = None;
+        assert_eq!(cache.admit(11).unwrap(), Some(BypassReason::TooLarge));
+
+        let src_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path().join("src");
+        fs::write(&src, "a").unwrap();
+        assert!(!cache.insert("abc", &src).unwrap());
+        assert!(!cache.contains("abc"));
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(FakeClock::new(1000));
+        let cache = open_test_cache(dir.path(), 10).with_clock(clock);
+        assert!(cache.quarantined().unwrap().is_empty());
+
+        let md5sum = "5d41402abc4b2a76b9719d911017c592";
+        fs::write(cache.path(md5sum), "hellp").unwrap();
+        cache.set_source(md5sum, "s3://bucket/hello").unwrap();
+        let entry = cache.quarantine(md5sum, "cache verify").unwrap();
+        assert!(!cache.contains(md5sum));
+        assert!(cache.metadata(md5sum).unwrap().source.is_none());
+        assert!(cache.entries().unwrap().is_empty());
+        assert_eq!(entry.actual_md5sum, "c983190483df167d2a3841463c2a9341");
+        assert_eq!(entry.time, 1000);
+        assert_eq!(cache.quarantined().unwrap(), [entry]);
+
+        // Quarantined files aren't mistaken for leftovers, but the
+        // entry's URL is forgotten
+        assert_eq!(
+            cache.gc().unwrap(),
+            GcSummary {
+                forgotten_aliases: 1,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(cache.clear_quarantine().unwrap().len(), 1);
+        assert!(cache.quarantined().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_purge() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open_test_cache(dir.path(), 100);
+        let now = SystemClock.now_in_s().unwrap();
+
+        fs::write(cache.path("old"), "a").unwrap();
+        set_file_atime(&cache.path("old"), now - 10 * 86400).unwrap();
+        cache.set_source("old", "s3://bucket1/old").unwrap();
+
+        fs::write(cache.path("big"), "aaaa").unwrap();
+        cache.set_source("big", "s3://bucket2/big").unwrap();
+
+        fs::write(cache.path("new"), "a").unwrap();
+
+        let md5sums = |entries: Vec<CacheEntry>| {
+            let mut md5sums: Vec<_> =
+                entries.into_iter().map(|entry| entry.md5sum).collect();
+            md5sums.sort();
+            md5sums
+        };
+
+        let filter = EntryFilter {
+            older_than_in_s: Some(86400),
+            ..Default::default()
+        };
+        assert_eq!(md5sums(cache.select(&filter).unwrap()), ["old"]);
+        assert!(cache.contains("old"));
+        let ancient = EntryFilter {
+            older_than_in_s: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(cache.select(&ancient).unwrap().is_empty());
+        assert_eq!(md5sums(cache.purge(&filter).unwrap()), ["old"]);
+        assert!(!cache.metadata_path("old").exists());
+
+        let filter = EntryFilter {
+            bucket: Some("bucket2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(md5sums(cache.purge(&filter).unwrap()), ["big"]);
+
+        let filter = EntryFilter {
+            larger_than_in_bytes: Some(1),
+            ..Default::default()
+        };
+        assert!(cache.purge(&filter).unwrap().is_empty());
+
+        let filter = EntryFilter::default();
+        assert_eq!(md5sums(cache.purge(&filter).unwrap()), ["new"]);
+        assert!(cache.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_gc() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = open_test_cache(dir.path(), 100);
+        let now = SystemClock.now_in_s().unwrap();
+
+        fs::write(cache.path("old"), "a").unwrap();
+        set_file_atime(&cache.path("old"), now - 10 * 86400).unwrap();
+        fs::write(cache.path("pinned"), "a").unwrap();
+        set_file_atime(&cache.path("pinned"), now - 10 * 86400).unwrap();
+        cache.set_pinned("pinned", true).unwrap();
+        fs::write(cache.path("new"), "a").unwrap();
+        fs::write(cache.temporary_path("partial"), "ab").unwrap();
+        fs::write(cache.metadata_path("gone"), "source = x\n").unwrap();
+
+        // Without a TTL nothing expires
+        let summary = cache.gc().unwrap();
+        assert_eq!(summary.temporary_files, 1);
+        assert_eq!(summary.orphaned_metadata, 1);
+        assert!(summary.expired.is_empty());
+        assert!(!cache.temporary_path("partial").exists());
+        assert!(!cache.metadata_path("gone").exists());
+
+        cache.conf.cache_ttl_in_s = Some(86400);
+        let summary = cache.gc().unwrap();
+        assert_eq!(summary.temporary_files, 0);
+        assert_eq!(summary.expired.len(), 1);
+        assert_eq!(summary.expired[0].md5sum, "old");
+        assert_eq!(summary.bytes_freed, 1);
+        assert!(cache.contains("pinned"));
+        assert!(cache.contains("new"));
+    }
+
+    #[test]
+    fn test_lock_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open_test_cache(dir.path(), 10);
+        let lock = cache.lock_entry("abc").unwrap();
+        let other = cache.lock_entry("def").unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _lock = cache.lock_entry("abc").unwrap();
+                sender.send(()).unwrap();
+            });
+            assert_eq!(
+                receiver.recv_timeout(Duration::from_millis(100)),
+                Err(mpsc::RecvTimeoutError::Timeout)
+            );
+            drop(lock);
+            receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        });
+        drop(other);
+
+        assert!(dir.path().join("abc.lock").exists());
+        assert_eq!(cache.gc().unwrap().temporary_files, 0);
+        assert!(!dir.path().join("abc.lock").exists());
+    }
+
+    #[test]
+    fn test_lock_entry_across_caches() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open_test_cache(dir.path(), 10);
+        let other_cache = open_test_cache(dir.path(), 10);
+        let lock = cache.lock_entry("abc").unwrap();
+        fs::write(cache.temporary_path("abc"), "a").unwrap();
+
+        // gc keeps the files of downloads in progress
+        assert_eq!(other_cache.gc().unwrap().temporary_files, 0);
+        assert!(dir.path().join("abc.lock").exists());
+        assert!(cache.temporary_path("abc").exists());
+
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _lock = other_cache.lock_entry("abc").unwrap();
+                sender.send(()).unwrap();
+            });
+            assert_eq!(
+                receiver.recv_timeout(Duration::from_millis(100)),
+                Err(mpsc::RecvTimeoutError::Timeout)
+            );
+            drop(lock);
+            receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        });
+
+        assert_eq!(other_cache.gc().unwrap().temporary_files, 1);
+        assert!(!dir.path().join("abc.lock").exists());
+    }
+
+    #[test]
+    fn test_temp_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conf = open_test_cache(&dir.path().join("cache"), 100).conf;
+        conf.temp_path = Some(dir.path().join("temp"));
+        let cache = Cache::open_with_configuration(conf).unwrap();
+        let src_path = dir.path().join("src");
+        fs::write(&src_path, "a").unwrap();
+
+        assert_eq!(
+            cache.temporary_path("abc"),
+            dir.path().join("temp/abc.tmp")
+        );
+        assert!(cache.insert("abc", &src_path).unwrap());
+        assert!(cache.contains("abc"));
+        assert!(!cache.temporary_path("abc").exists());
+
+        fs::write(cache.temporary_path("partial"), "ab").unwrap();
+        let summary = cache.gc().unwrap();
+        assert_eq!(summary.temporary_files, 1);
+        assert!(!cache.temporary_path("partial").exists());
+    }
+
+    #[test]
+    fn test_move_across_filesystems() {
+        let dir = tempfile::tempdir().unwrap();
+        let src_path = dir.path().join("src");
+        let staging_path = dir.path().join("staging");
+        let dst_path = dir.path().join("dst");
+        fs::write(&src_path, "a").unwrap();
+        move_across_filesystems(&src_path, &staging_path, &dst_path).unwrap();
+        assert_eq!(fs::read(&dst_path).unwrap(), b"a");
+        assert!(!src_path.exists());
+        assert!(!staging_path.exists());
+    }
+
+    #[test]
+    fn test_fake_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(FakeClock::new(1_000_000));
+        let mut cache = open_test_cache(&dir.path().join("cache"), 2)
+            .with_clock(clock.clone());
+        let src_path = dir.path().join("src");
+        fs::write(&src_path, "a").unwrap();
+
+        cache.insert("a", &src_path).unwrap();
+        clock.set(1_000_010);
+        cache.insert("b", &src_path).unwrap();
+        let evicted = cache.plan_eviction(1).unwrap().unwrap();
+        assert_eq!(evicted[0].md5sum, "a");
+
+        // Accessing "a" makes "b" the least recently used
+        clock.advance(10);
+        cache.copy_to("a", &mut Vec::new()).unwrap();
+        let evicted = cache.plan_eviction(1).unwrap().unwrap();
+        assert_eq!(evicted[0].md5sum, "b");
+
+        cache.conf.cache_ttl_in_s = Some(15);
+        clock.advance(10);
+        let summary = cache.gc().unwrap();
+        assert_eq!(summary.expired.len(), 1);
+        assert_eq!(summary.expired[0].md5sum, "b");
+    }
+
+    #[test]
+    fn test_pin() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open_test_cache(dir.path(), 2);
+        assert!(cache.set_pinned("a", true).is_err());
+
+        fs::write(cache.path("a"), "a").unwrap();
+        set_file_atime(&cache.path("a"), 1).unwrap();
+        fs::write(cache.path("b"), "b").unwrap();
+        set_file_atime(&cache.path("b"), 2).unwrap();
+        cache.set_pinned("a", true).unwrap();
+
+        // The least recently used entry is pinned, so the next one is
+        // evicted instead
+        assert!(cache.make_space(1).unwrap());
+        assert!(cache.contains("a"));
+        assert!(!cache.contains("b"));
+
+        // There's no room without evicting the pinned entry
+        assert!(!cache.make_space(2).unwrap());
+        assert!(cache.contains("a"));
+
+        let filter = EntryFilter {
+            pinned: Some(false),
+            ..Default::default()
+        };
+        assert!(cache.purge(&filter).unwrap().is_empty());
+
+        cache.set_pinned("a", false).unwrap();
+        assert_eq!(cache.purge(&filter).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration {
+            cache_size_limit_in_bytes: 1,
+            audit_log: true,
+            ..test_configuration(dir.path())
+        };
+        let cache = Cache::open_with_configuration(conf)
+            .unwrap()
+            .with_clock(Arc::new(FakeClock::new(1_000_000)));
+        let src_path = dir.path().join("src");
+        fs::write(&src_path, "a").unwrap();
+
+        cache.insert("a", &src_path).unwrap();
+        cache.insert("b", &src_path).unwrap();
+        cache.remove("b").unwrap();
+
+        let log = fs::read_to_string(cache.root().join("audit.log")).unwrap();
+        let actions: Vec<_> = log
+            .lines()
+            .map(|line| {
+                let fields: Vec<_> = line.split('\t').collect();
+                (fields[0], fields[2], fields[3], fields[4])
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("1000000", "insert", "a", "1"),
+                ("1000000", "evict", "a", "1"),
+                ("1000000", "insert", "b", "1"),
+                ("1000000", "remove", "b", "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open_test_cache(dir.path(), 100);
+        fs::write(cache.path("a"), "a").unwrap();
+        cache.set_source("a", "s3://bucket/a").unwrap();
+        fs::write(cache.path("b"), "b").unwrap();
+        fs::write(cache.path("c"), "c").unwrap();
+
+        let invalidated = cache.invalidate("s3://bucket/a", Some("b")).unwrap();
+        let mut md5sums: Vec<_> =
+            invalidated.into_iter().map(|entry| entry.md5sum).collect();
+        md5sums.sort();
+        assert_eq!(md5sums, ["a", "b"]);
+        assert!(cache.contains("c"));
+        assert!(cache.invalidate("s3://bucket/a", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open_test_cache(dir.path(), 100);
+        let md5sum = "5d41402abc4b2a76b9719d911017c592";
+        fs::write(cache.path(md5sum), "hello").unwrap();
+        assert!(cache.verify(md5sum).unwrap());
+        fs::write(cache.path(md5sum), "hellp").unwrap();
+        assert!(!cache.verify(md5sum).unwrap());
     }
 }
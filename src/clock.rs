@@ -0,0 +1,22 @@
+use std::time::{SystemTime, SystemTimeError};
+
+/// Source of the current time
+///
+/// The cache uses this for access times, which decide what gets
+/// evicted, and for the TTL. Tests can swap in a fake with
+/// `Cache::with_clock`.
+pub trait Clock: Send + Sync {
+    /// Get the current time in seconds since the Unix epoch
+    fn now_in_s(&self) -> Result<u64, SystemTimeError>;
+}
+
+/// The real system clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_in_s(&self) -> Result<u64, SystemTimeError> {
+        let d = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        Ok(d.as_secs())
+    }
+}
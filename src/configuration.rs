@@ -1,30 +1,228 @@
-use std::collections::HashMap;
+use crate::alias::{Alias, ALIASES_SECTION};
+use crate::failover::Replica;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
+use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum ConfigurationError {
+    #[error("home directory not found")]
     HomeDirNotFound,
-    DefaultConfigError(io::Error),
+    #[error("failed to write default configuration to {}", .0.display())]
+    DefaultConfigError(PathBuf, #[source] io::Error),
+    #[error("failed to parse the configuration")]
     ParseFailed,
-    ReadFailed(io::Error),
+    #[error("failed to read configuration file {}", .0.display())]
+    ReadFailed(PathBuf, #[source] io::Error),
 }
 
 pub struct Configuration {
     pub cache_size_limit_in_bytes: u64,
     pub cache_path: PathBuf,
+    pub remote_cache_url: Option<String>,
+    /// Entries not accessed for this long are deleted by `Cache::gc`
+    pub cache_ttl_in_s: Option<u64>,
+    /// Make the cache usable by all members of a group (Unix only)
+    pub shared_cache: bool,
+    /// Group (name or ID) to give a shared cache directory
+    pub shared_cache_group: Option<String>,
+    /// Give each user their own cache in a subdirectory of
+    /// `cache_path`, with the size limit applied to each one (Unix only)
+    pub per_user_cache: bool,
+    /// Log inserts, evictions and removals to `audit.log` in the cache
+    /// directory
+    pub audit_log: bool,
+    /// Number of following objects in a numbered sequence (such as
+    /// `part-0002` after `part-0001`) to prefetch after a download
+    pub prefetch_siblings: usize,
+    /// Only prefetch siblings of URLs matching one of these patterns,
+    /// where `*` matches anything. All URLs match if this is empty.
+    pub prefetch_patterns: Vec<String>,
+    /// Namespace for metrics sent to CloudWatch
+    pub cloudwatch_namespace: String,
+    /// Maximum number of aws processes to run at once
+    pub max_aws_processes: usize,
+    /// Kill aws processes that run for longer than this
+    pub aws_timeout_in_s: Option<u64>,
+    /// Where the horst3 command sends its log messages
+    pub log_target: LogTarget,
+    /// How the horst3 command formats log messages on stderr
+    pub log_format: LogFormat,
+    /// Shell command to run when AWS credentials have expired, such as
+    /// "aws sso login", before retrying the request
+    pub credentials_refresh_command: Option<String>,
+    /// Number of times to retry a request to the remote cache server
+    /// that failed because the server couldn't be reached
+    pub remote_cache_retries: usize,
+    /// Give up on a request to the remote cache server if it can't
+    /// connect or receives no data for this long
+    pub remote_cache_timeout_in_s: Option<u64>,
+    /// Skip the remote cache server for a while after this many
+    /// requests in a row failed to reach it. 0 means never.
+    pub remote_cache_max_failures: usize,
+    /// Directory for downloads in progress, if not the cache directory
+    pub temp_path: Option<PathBuf>,
+    /// Replicas to fail over to when a bucket fails, by bucket name
+    pub bucket_replicas: BTreeMap<String, Replica>,
+    /// Download objects smaller than this directly instead of caching them
+    pub min_cached_size_in_bytes: Option<u64>,
+    /// Download objects larger than this directly instead of caching
+    /// them, so that they don't evict many smaller entries
+    pub max_cached_size_in_bytes: Option<u64>,
+    /// Reuse an object's head-object result for this long instead of
+    /// asking S3 again before each download
+    pub head_cache_ttl_in_s: Option<u64>,
+    /// Keep head-object results in `heads.txt` in the cache directory
+    /// so that they're shared between processes
+    pub head_cache_on_disk: bool,
+    /// Never contact S3: serve objects from the cache and fail for
+    /// objects that aren't cached
+    pub offline: bool,
+    /// Take the md5sum of objects that don't have one from a
+    /// `<url>.md5` file next to them, if there is one
+    pub checksum_sidecars: bool,
+}
+
+/// Where log messages go
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    /// The local syslog daemon, through /dev/log (Unix only)
+    Syslog,
+    /// The systemd journal, with structured fields (Unix only)
+    Journald,
+}
+
+impl LogTarget {
+    fn parse(s: &str) -> Option<LogTarget> {
+        match s {
+            "stderr" => Some(LogTarget::Stderr),
+            "syslog" => Some(LogTarget::Syslog),
+            "journald" => Some(LogTarget::Journald),
+            _ => None,
+        }
+    }
+}
+
+/// How log messages are formatted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl LogFormat {
+    fn parse(s: &str) -> Option<LogFormat> {
+        match s {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Where a configuration value came from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettingSource {
+    Default,
+    File(PathBuf),
+    CommandLine,
+}
+
+impl fmt::Display for SettingSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingSource::Default => write!(f, "default"),
+            SettingSource::File(path) => write!(f, "{}", path.display()),
+            SettingSource::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// A configuration value along with where it came from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Setting {
+    pub key: &'static str,
+    pub value: Option<String>,
+    pub source: SettingSource,
+}
+
+/// Values that take precedence over the configuration file
+///
+/// These come from command line flags and only apply to a single
+/// invocation.
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    /// Read this configuration file instead of the default one
+    pub config_path: Option<PathBuf>,
+    pub cache_path: Option<String>,
+    pub cache_size_limit: Option<String>,
+    /// Turn on offline mode
+    pub offline: bool,
+}
+
+impl Overrides {
+    /// Replace the value and source of each overridden setting
+    pub fn apply(&self, settings: &mut [Setting]) {
+        let offline = Some("true".to_string()).filter(|_| self.offline);
+        for setting in settings {
+            let value = match setting.key {
+                CACHE_PATH => &self.cache_path,
+                CACHE_SIZE_LIMIT => &self.cache_size_limit,
+                OFFLINE => &offline,
+                _ => &None,
+            };
+            if let Some(value) = value {
+                setting.value = Some(value.clone());
+                setting.source = SettingSource::CommandLine;
+            }
+        }
+    }
 }
 
 /// Parse the contents of a configuration file
 ///
 /// Lines where the first non-whitespace character is a '#' are
 /// ignored. Lines containing an '=' are parsed as <key> = <value>
-/// pairs and returned in a HashMap.
-fn parse_config(s: &str) -> HashMap<&str, &str> {
+/// pairs and returned in a HashMap. Lines after a `[<section>]` header
+/// belong to that section and are left out.
+pub(crate) fn parse_config(s: &str) -> HashMap<&str, &str> {
+    parse_lines(s, None)
+}
+
+/// Parse the <key> = <value> lines of a `[<section>]` of a
+/// configuration file
+pub(crate) fn parse_section<'a>(
+    s: &'a str,
+    section: &str,
+) -> HashMap<&'a str, &'a str> {
+    parse_lines(s, Some(section))
+}
+
+/// Get the name of a `[<section>]` header line
+fn section_header(line: &str) -> Option<&str> {
+    Some(line.strip_prefix('[')?.strip_suffix(']')?.trim())
+}
+
+fn parse_lines<'a>(
+    s: &'a str,
+    section: Option<&str>,
+) -> HashMap<&'a str, &'a str> {
     let mut map = HashMap::new();
+    let mut current_section = None;
     for line in s.lines() {
         let line = line.trim();
-        if !line.starts_with('#') {
+        if let Some(name) = section_header(line) {
+            current_section = Some(name);
+        } else if !line.starts_with('#') && current_section == section {
             let parts: Vec<&str> = line.splitn(2, '=').collect();
             if parts.len() == 2 {
                 let key = parts[0].trim();
@@ -41,8 +239,71 @@ const CACHE_PATH_DEFAULT: &str = "~/.cache/horst3";
 const CACHE_SIZE_LIMIT: &str = "cache_size_limit";
 const CACHE_SIZE_LIMIT_DEFAULT: &str = "16GiB";
 const CACHE_SIZE_LIMIT_DEFAULT_IN_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+const CACHE_TTL: &str = "cache_ttl";
+const REMOTE_CACHE_URL: &str = "remote_cache_url";
+const SHARED_CACHE: &str = "shared_cache";
+const SHARED_CACHE_GROUP: &str = "shared_cache_group";
+const PER_USER_CACHE: &str = "per_user_cache";
+const AUDIT_LOG: &str = "audit_log";
+const PREFETCH_SIBLINGS: &str = "prefetch_siblings";
+const PREFETCH_PATTERNS: &str = "prefetch_patterns";
+const CLOUDWATCH_NAMESPACE: &str = "cloudwatch_namespace";
+const CLOUDWATCH_NAMESPACE_DEFAULT: &str = "horst3";
+const MAX_AWS_PROCESSES: &str = "max_aws_processes";
+const MAX_AWS_PROCESSES_DEFAULT: usize = 16;
+const AWS_TIMEOUT: &str = "aws_timeout";
+const LOG_TARGET: &str = "log_target";
+const LOG_FORMAT: &str = "log_format";
+const CREDENTIALS_REFRESH_COMMAND: &str = "credentials_refresh_command";
+const REMOTE_CACHE_RETRIES: &str = "remote_cache_retries";
+const REMOTE_CACHE_TIMEOUT: &str = "remote_cache_timeout";
+const REMOTE_CACHE_MAX_FAILURES: &str = "remote_cache_max_failures";
+const TEMP_PATH: &str = "temp_path";
+const BUCKET_REPLICAS: &str = "bucket_replicas";
+const MIN_CACHED_SIZE: &str = "min_cached_size";
+const MAX_CACHED_SIZE: &str = "max_cached_size";
+const HEAD_CACHE_TTL: &str = "head_cache_ttl";
+const HEAD_CACHE_ON_DISK: &str = "head_cache_on_disk";
+const OFFLINE: &str = "offline";
+const CHECKSUM_SIDECARS: &str = "checksum_sidecars";
+
+/// All known keys and their default values
+const KEYS: &[(&str, Option<&str>)] = &[
+    (CACHE_PATH, Some(CACHE_PATH_DEFAULT)),
+    (CACHE_SIZE_LIMIT, Some(CACHE_SIZE_LIMIT_DEFAULT)),
+    (CACHE_TTL, None),
+    (REMOTE_CACHE_URL, None),
+    (SHARED_CACHE, Some("false")),
+    (SHARED_CACHE_GROUP, None),
+    (PER_USER_CACHE, Some("false")),
+    (AUDIT_LOG, Some("false")),
+    (PREFETCH_SIBLINGS, Some("0")),
+    (PREFETCH_PATTERNS, None),
+    (CLOUDWATCH_NAMESPACE, Some(CLOUDWATCH_NAMESPACE_DEFAULT)),
+    (MAX_AWS_PROCESSES, Some("16")),
+    (AWS_TIMEOUT, None),
+    (LOG_TARGET, Some("stderr")),
+    (LOG_FORMAT, Some("text")),
+    (CREDENTIALS_REFRESH_COMMAND, None),
+    (REMOTE_CACHE_RETRIES, Some("0")),
+    (REMOTE_CACHE_TIMEOUT, None),
+    (REMOTE_CACHE_MAX_FAILURES, Some("0")),
+    (TEMP_PATH, None),
+    (BUCKET_REPLICAS, None),
+    (MIN_CACHED_SIZE, None),
+    (MAX_CACHED_SIZE, None),
+    (HEAD_CACHE_TTL, None),
+    (HEAD_CACHE_ON_DISK, Some("false")),
+    (OFFLINE, Some("false")),
+    (CHECKSUM_SIDECARS, Some("false")),
+];
 
 fn write_default_config(path: &Path) -> Result<(), ConfigurationError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| {
+            ConfigurationError::DefaultConfigError(dir.to_path_buf(), err)
+        })?;
+    }
     let contents = format!(
         "{} = {}\n{} = {}\n",
         CACHE_PATH,
@@ -50,12 +311,14 @@ fn write_default_config(path: &Path) -> Result<(), ConfigurationError> {
         CACHE_SIZE_LIMIT,
         CACHE_SIZE_LIMIT_DEFAULT
     );
-    fs::write(path, contents)
-        .map_err(ConfigurationError::DefaultConfigError)?;
+    fs::write(path, contents).map_err(|err| {
+        ConfigurationError::DefaultConfigError(path.to_path_buf(), err)
+    })?;
     Ok(())
 }
 
-fn parse_size_as_bytes(s: &str) -> Option<u64> {
+/// Parse a size such as "16GiB" or "100 MB" as a number of bytes
+pub fn parse_size_as_bytes(s: &str) -> Option<u64> {
     let mut units = HashMap::new();
     units.insert("TiB", 1024u64 * 1024 * 1024 * 1024);
     units.insert("TB", 1000 * 1000 * 1000 * 1000);
@@ -87,27 +350,412 @@ fn parse_size_as_bytes(s: &str) -> Option<u64> {
     }
 }
 
+/// Parse a duration such as "30d" or "12h" as a number of seconds
+///
+/// The supported units are s, m, h and d. A number without a unit is
+/// in seconds.
+pub fn parse_duration_as_secs(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num_str, multiplier) = match s.chars().last()? {
+        's' => (&s[..s.len() - 1], 1),
+        'm' => (&s[..s.len() - 1], 60),
+        'h' => (&s[..s.len() - 1], 60 * 60),
+        'd' => (&s[..s.len() - 1], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    num_str
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|num| num.checked_mul(multiplier))
+}
+
+/// Parse "true" or "false"
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Expand a leading "~/" to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = dirs::home_dir() {
+            return home.join(rest);
+        }
+    }
+    PathBuf::from(path)
+}
+
+/// Parse a comma-separated list of `<bucket>:<replica>[@<region>]`
+fn parse_bucket_replicas(s: &str) -> Option<BTreeMap<String, Replica>> {
+    let mut replicas = BTreeMap::new();
+    for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (bucket, replica) = pair.split_once(':')?;
+        let bucket = bucket.trim();
+        if bucket.is_empty() {
+            return None;
+        }
+        replicas.insert(bucket.to_string(), Replica::parse(replica.trim())?);
+    }
+    Some(replicas)
+}
+
+/// Check the contents of a configuration file for problems
+///
+/// Returns a description of each problem found, so an empty list
+/// means the file is valid.
+pub fn check_config(s: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut section = None;
+    for (index, line) in s.lines().enumerate() {
+        let line_num = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = section_header(line) {
+            if name != ALIASES_SECTION {
+                problems.push(format!(
+                    "line {}: unknown section \"[{}]\"",
+                    line_num, name
+                ));
+            }
+            section = Some(name);
+            continue;
+        }
+        let parts: Vec<&str> = line.splitn(2, '=').collect();
+        if parts.len() != 2 {
+            problems
+                .push(format!("line {}: expected <key> = <value>", line_num));
+            continue;
+        }
+        let key = parts[0].trim();
+        let val = parts[1].trim();
+        if section == Some(ALIASES_SECTION) {
+            if key.is_empty() || key.contains('/') {
+                problems.push(format!(
+                    "line {}: invalid alias name \"{}\"",
+                    line_num, key
+                ));
+            } else if Alias::parse(val).is_none() {
+                problems.push(format!(
+                    "line {}: expected s3://<bucket>/<prefix>[@<region>], \
+                     got \"{}\"",
+                    line_num, val
+                ));
+            }
+        } else if section.is_some() {
+            // Already reported as an unknown section
+        } else if !KEYS.iter().any(|(known_key, _)| *known_key == key) {
+            problems
+                .push(format!("line {}: unknown key \"{}\"", line_num, key));
+        } else if [CACHE_SIZE_LIMIT, MIN_CACHED_SIZE, MAX_CACHED_SIZE]
+            .contains(&key)
+            && parse_size_as_bytes(val).is_none()
+        {
+            problems
+                .push(format!("line {}: invalid size \"{}\"", line_num, val));
+        } else if [CACHE_TTL, AWS_TIMEOUT, REMOTE_CACHE_TIMEOUT, HEAD_CACHE_TTL]
+            .contains(&key)
+            && parse_duration_as_secs(val).is_none()
+        {
+            problems.push(format!(
+                "line {}: invalid duration \"{}\"",
+                line_num, val
+            ));
+        } else if [
+            SHARED_CACHE,
+            PER_USER_CACHE,
+            AUDIT_LOG,
+            HEAD_CACHE_ON_DISK,
+            OFFLINE,
+            CHECKSUM_SIDECARS,
+        ]
+        .contains(&key)
+            && parse_bool(val).is_none()
+        {
+            problems.push(format!(
+                "line {}: expected true or false, got \"{}\"",
+                line_num, val
+            ));
+        } else if key == LOG_TARGET && LogTarget::parse(val).is_none() {
+            problems.push(format!(
+                "line {}: expected stderr, syslog or journald, got \"{}\"",
+                line_num, val
+            ));
+        } else if key == BUCKET_REPLICAS && parse_bucket_replicas(val).is_none()
+        {
+            problems.push(format!(
+                "line {}: expected <bucket>:<replica>[@<region>], ..., \
+                 got \"{}\"",
+                line_num, val
+            ));
+        } else if key == LOG_FORMAT && LogFormat::parse(val).is_none() {
+            problems.push(format!(
+                "line {}: expected text or json, got \"{}\"",
+                line_num, val
+            ));
+        } else if [
+            PREFETCH_SIBLINGS,
+            MAX_AWS_PROCESSES,
+            REMOTE_CACHE_RETRIES,
+            REMOTE_CACHE_MAX_FAILURES,
+        ]
+        .contains(&key)
+            && val.parse::<usize>().is_err()
+        {
+            problems
+                .push(format!("line {}: invalid number \"{}\"", line_num, val));
+        }
+    }
+    problems
+}
+
 impl Configuration {
-    pub fn open() -> Result<Configuration, ConfigurationError> {
+    /// Get the path of the configuration file
+    pub fn path() -> Result<PathBuf, ConfigurationError> {
         let home =
             dirs::home_dir().ok_or(ConfigurationError::HomeDirNotFound)?;
-        let conf_path = home.join(".config/horst3.conf");
-        if !conf_path.exists() {
-            write_default_config(&conf_path)?;
-        }
-        let contents = fs::read_to_string(conf_path)
-            .map_err(ConfigurationError::ReadFailed)?;
+        Ok(home.join(".config/horst3.conf"))
+    }
+
+    /// Write a configuration file containing the default settings
+    pub fn write_default(path: &Path) -> Result<(), ConfigurationError> {
+        write_default_config(path)
+    }
+
+    /// Read all settings from a configuration file
+    ///
+    /// Keys that aren't set in the file get their default value. If
+    /// the file doesn't exist, all keys get their default value.
+    pub fn read_settings(
+        path: &Path,
+    ) -> Result<Vec<Setting>, ConfigurationError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(ConfigurationError::ReadFailed(
+                    path.to_path_buf(),
+                    err,
+                ))
+            }
+        };
         let map = parse_config(&contents);
-        let cache_path = map.get(CACHE_PATH).unwrap_or(&CACHE_PATH_DEFAULT);
-        let cache_size_limit = map
-            .get(CACHE_SIZE_LIMIT)
-            .unwrap_or(&CACHE_SIZE_LIMIT_DEFAULT);
-        let cache_size_limit_in_bytes = parse_size_as_bytes(cache_size_limit)
+        Ok(KEYS
+            .iter()
+            .map(|(key, default)| match map.get(key) {
+                Some(val) => Setting {
+                    key,
+                    value: Some(val.to_string()),
+                    source: SettingSource::File(path.to_path_buf()),
+                },
+                None => Setting {
+                    key,
+                    value: default.map(|val| val.to_string()),
+                    source: SettingSource::Default,
+                },
+            })
+            .collect())
+    }
+
+    /// Get the configuration described by settings, such as those from
+    /// `read_settings_with_overrides`
+    ///
+    /// Invalid values are replaced by defaults.
+    pub fn from_settings(settings: &[Setting]) -> Configuration {
+        let get = |key| {
+            settings
+                .iter()
+                .find(|setting| setting.key == key)
+                .and_then(|setting| setting.value.as_deref())
+        };
+        let cache_path = get(CACHE_PATH).unwrap_or(CACHE_PATH_DEFAULT);
+        let cache_size_limit_in_bytes = get(CACHE_SIZE_LIMIT)
+            .and_then(parse_size_as_bytes)
             .unwrap_or(CACHE_SIZE_LIMIT_DEFAULT_IN_BYTES);
-        Ok(Configuration {
+        let remote_cache_url = get(REMOTE_CACHE_URL)
+            .filter(|url| !url.is_empty())
+            .map(|url| url.to_string());
+        let cache_ttl_in_s = get(CACHE_TTL).and_then(parse_duration_as_secs);
+        let shared_cache =
+            get(SHARED_CACHE).and_then(parse_bool).unwrap_or(false);
+        let shared_cache_group = get(SHARED_CACHE_GROUP)
+            .filter(|group| !group.is_empty())
+            .map(|group| group.to_string());
+        let per_user_cache =
+            get(PER_USER_CACHE).and_then(parse_bool).unwrap_or(false);
+        let audit_log = get(AUDIT_LOG).and_then(parse_bool).unwrap_or(false);
+        let prefetch_siblings = get(PREFETCH_SIBLINGS)
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(0);
+        let prefetch_patterns = get(PREFETCH_PATTERNS)
+            .map(|val| {
+                val.split(',')
+                    .map(|pattern| pattern.trim().to_string())
+                    .filter(|pattern| !pattern.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let cloudwatch_namespace = get(CLOUDWATCH_NAMESPACE)
+            .filter(|namespace| !namespace.is_empty())
+            .unwrap_or(CLOUDWATCH_NAMESPACE_DEFAULT)
+            .to_string();
+        let max_aws_processes = get(MAX_AWS_PROCESSES)
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(MAX_AWS_PROCESSES_DEFAULT);
+        let aws_timeout_in_s =
+            get(AWS_TIMEOUT).and_then(parse_duration_as_secs);
+        let log_target = get(LOG_TARGET)
+            .and_then(LogTarget::parse)
+            .unwrap_or_default();
+        let log_format = get(LOG_FORMAT)
+            .and_then(LogFormat::parse)
+            .unwrap_or_default();
+        let credentials_refresh_command = get(CREDENTIALS_REFRESH_COMMAND)
+            .filter(|command| !command.is_empty())
+            .map(|command| command.to_string());
+        let remote_cache_retries = get(REMOTE_CACHE_RETRIES)
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(0);
+        let remote_cache_timeout_in_s =
+            get(REMOTE_CACHE_TIMEOUT).and_then(parse_duration_as_secs);
+        let remote_cache_max_failures = get(REMOTE_CACHE_MAX_FAILURES)
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(0);
+        let temp_path = get(TEMP_PATH)
+            .filter(|path| !path.is_empty())
+            .map(expand_home);
+        let bucket_replicas = get(BUCKET_REPLICAS)
+            .and_then(parse_bucket_replicas)
+            .unwrap_or_default();
+        let min_cached_size_in_bytes =
+            get(MIN_CACHED_SIZE).and_then(parse_size_as_bytes);
+        let max_cached_size_in_bytes =
+            get(MAX_CACHED_SIZE).and_then(parse_size_as_bytes);
+        let head_cache_ttl_in_s =
+            get(HEAD_CACHE_TTL).and_then(parse_duration_as_secs);
+        let head_cache_on_disk = get(HEAD_CACHE_ON_DISK)
+            .and_then(parse_bool)
+            .unwrap_or(false);
+        let offline = get(OFFLINE).and_then(parse_bool).unwrap_or(false);
+        let checksum_sidecars =
+            get(CHECKSUM_SIDECARS).and_then(parse_bool).unwrap_or(false);
+        Configuration {
             cache_size_limit_in_bytes,
-            cache_path: Path::new(cache_path).to_path_buf(),
-        })
+            cache_path: expand_home(cache_path),
+            remote_cache_url,
+            cache_ttl_in_s,
+            shared_cache,
+            shared_cache_group,
+            per_user_cache,
+            audit_log,
+            prefetch_siblings,
+            prefetch_patterns,
+            cloudwatch_namespace,
+            max_aws_processes,
+            aws_timeout_in_s,
+            log_target,
+            log_format,
+            credentials_refresh_command,
+            remote_cache_retries,
+            remote_cache_timeout_in_s,
+            remote_cache_max_failures,
+            temp_path,
+            bucket_replicas,
+            min_cached_size_in_bytes,
+            max_cached_size_in_bytes,
+            head_cache_ttl_in_s,
+            head_cache_on_disk,
+            offline,
+            checksum_sidecars,
+        }
+    }
+
+    pub fn open() -> Result<Configuration, ConfigurationError> {
+        Configuration::open_with_overrides(&Overrides::default())
+    }
+
+    /// Get the path of the configuration file, taking overrides into
+    /// account
+    pub fn path_with_overrides(
+        overrides: &Overrides,
+    ) -> Result<PathBuf, ConfigurationError> {
+        match &overrides.config_path {
+            Some(path) => Ok(path.clone()),
+            None => Configuration::path(),
+        }
+    }
+
+    /// Read all settings, taking overrides into account
+    ///
+    /// Unlike the default configuration file, a configuration file
+    /// given as an override must exist.
+    pub fn read_settings_with_overrides(
+        overrides: &Overrides,
+    ) -> Result<Vec<Setting>, ConfigurationError> {
+        let conf_path = Configuration::path_with_overrides(overrides)?;
+        if overrides.config_path.is_some() {
+            fs::metadata(&conf_path).map_err(|err| {
+                ConfigurationError::ReadFailed(conf_path.clone(), err)
+            })?;
+        }
+        let mut settings = Configuration::read_settings(&conf_path)?;
+        overrides.apply(&mut settings);
+        Ok(settings)
+    }
+
+    /// Open the configuration, taking overrides into account
+    ///
+    /// The default configuration file is created if it doesn't exist.
+    pub fn open_with_overrides(
+        overrides: &Overrides,
+    ) -> Result<Configuration, ConfigurationError> {
+        if overrides.config_path.is_none() {
+            let conf_path = Configuration::path()?;
+            if !conf_path.exists() {
+                write_default_config(&conf_path)?;
+            }
+        }
+        let settings = Configuration::read_settings_with_overrides(overrides)?;
+        Ok(Configuration::from_settings(&settings))
+    }
+}
+
+/// A configuration for tests with the cache in `dir/cache`
+#[cfg(test)]
+pub(crate) fn test_configuration(dir: &Path) -> Configuration {
+    Configuration {
+        cache_size_limit_in_bytes: 100,
+        cache_path: dir.join("cache"),
+        remote_cache_url: None,
+        cache_ttl_in_s: None,
+        shared_cache: false,
+        shared_cache_group: None,
+        per_user_cache: false,
+        audit_log: false,
+        prefetch_siblings: 0,
+        prefetch_patterns: Vec::new(),
+        cloudwatch_namespace: "horst3".to_string(),
+        max_aws_processes: 16,
+        aws_timeout_in_s: None,
+        log_target: LogTarget::Stderr,
+        log_format: LogFormat::Text,
+        credentials_refresh_command: None,
+        remote_cache_retries: 0,
+        remote_cache_timeout_in_s: None,
+        remote_cache_max_failures: 0,
+        temp_path: None,
+        bucket_replicas: BTreeMap::new(),
+        min_cached_size_in_bytes: None,
+        max_cached_size_in_bytes: None,
+        head_cache_ttl_in_s: None,
+        head_cache_on_disk: false,
+        offline: false,
+        checksum_sidecars: false,
     }
 }
 
@@ -127,6 +775,279 @@ mod tests {
         assert_eq!(parse_config("a = b\nc = d\n# comment"), map);
     }
 
+    #[test]
+    fn test_check_config() {
+        assert!(check_config("").is_empty());
+        assert!(check_config("# comment\ncache_size_limit = 1GB").is_empty());
+        assert_eq!(
+            check_config("cache_ttl = 999999999999999999d"),
+            ["line 1: invalid duration \"999999999999999999d\""]
+        );
+        assert_eq!(
+            check_config(
+                "cache_size_limit = lots\nfoo = bar\nbaz\nshared_cache = yes\n\
+                 log_target = file\nremote_cache_retries = -1\n\
+                 log_format = xml\nbucket_replicas = data:\n\
+                 max_cached_size = big\n[aliases]\nmodels = models\na/b = s3://b\n[other]\nx = y"
+            ),
+            [
+                "line 1: invalid size \"lots\"",
+                "line 2: unknown key \"foo\"",
+                "line 3: expected <key> = <value>",
+                "line 4: expected true or false, got \"yes\"",
+                "line 5: expected stderr, syslog or journald, got \"file\"",
+                "line 6: invalid number \"-1\"",
+                "line 7: expected text or json, got \"xml\"",
+                "line 8: expected <bucket>:<replica>[@<region>], ..., got \
+                 \"data:\"",
+                "line 9: invalid size \"big\"",
+                "line 11: expected s3://<bucket>/<prefix>[@<region>], got \
+                 \"models\"",
+                "line 12: invalid alias name \"a/b\"",
+                "line 13: unknown section \"[other]\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_sections() {
+        let s = "cache_path = /a\n[aliases]\nmodels = s3://m\n";
+        assert_eq!(parse_config(s).len(), 1);
+        assert_eq!(parse_section(s, "aliases")["models"], "s3://m");
+    }
+
+    #[test]
+    fn test_parse_bucket_replicas() {
+        let replicas =
+            parse_bucket_replicas("data:data-eu@eu-west-1, models:models-2")
+                .unwrap();
+        assert_eq!(replicas.len(), 2);
+        assert_eq!(replicas["data"].bucket, "data-eu");
+        assert_eq!(replicas["data"].region.as_deref(), Some("eu-west-1"));
+        assert_eq!(replicas["models"].region, None);
+        assert!(parse_bucket_replicas("").unwrap().is_empty());
+        assert!(parse_bucket_replicas("data").is_none());
+        assert!(parse_bucket_replicas(":data-eu").is_none());
+    }
+
+    #[test]
+    fn test_read_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("horst3.conf");
+        fs::write(&path, "cache_size_limit = 1GB\n").unwrap();
+        let settings = Configuration::read_settings(&path).unwrap();
+        assert_eq!(
+            settings,
+            [
+                Setting {
+                    key: CACHE_PATH,
+                    value: Some(CACHE_PATH_DEFAULT.to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: CACHE_SIZE_LIMIT,
+                    value: Some("1GB".to_string()),
+                    source: SettingSource::File(path.clone()),
+                },
+                Setting {
+                    key: CACHE_TTL,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: REMOTE_CACHE_URL,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: SHARED_CACHE,
+                    value: Some("false".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: SHARED_CACHE_GROUP,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: PER_USER_CACHE,
+                    value: Some("false".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: AUDIT_LOG,
+                    value: Some("false".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: PREFETCH_SIBLINGS,
+                    value: Some("0".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: PREFETCH_PATTERNS,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: CLOUDWATCH_NAMESPACE,
+                    value: Some(CLOUDWATCH_NAMESPACE_DEFAULT.to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: MAX_AWS_PROCESSES,
+                    value: Some("16".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: AWS_TIMEOUT,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: LOG_TARGET,
+                    value: Some("stderr".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: LOG_FORMAT,
+                    value: Some("text".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: CREDENTIALS_REFRESH_COMMAND,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: REMOTE_CACHE_RETRIES,
+                    value: Some("0".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: REMOTE_CACHE_TIMEOUT,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: REMOTE_CACHE_MAX_FAILURES,
+                    value: Some("0".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: TEMP_PATH,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: BUCKET_REPLICAS,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: MIN_CACHED_SIZE,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: MAX_CACHED_SIZE,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: HEAD_CACHE_TTL,
+                    value: None,
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: HEAD_CACHE_ON_DISK,
+                    value: Some("false".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: OFFLINE,
+                    value: Some("false".to_string()),
+                    source: SettingSource::Default,
+                },
+                Setting {
+                    key: CHECKSUM_SIDECARS,
+                    value: Some("false".to_string()),
+                    source: SettingSource::Default,
+                },
+            ]
+        );
+        let conf = Configuration::from_settings(&settings);
+        assert_eq!(conf.cache_size_limit_in_bytes, 1000 * 1000 * 1000);
+        assert_eq!(conf.remote_cache_url, None);
+        assert!(!conf.shared_cache);
+        assert!(!conf.per_user_cache);
+        assert_eq!(conf.max_aws_processes, 16);
+        assert_eq!(conf.aws_timeout_in_s, None);
+        assert_eq!(conf.log_target, LogTarget::Stderr);
+        assert_eq!(conf.log_format, LogFormat::Text);
+        assert_eq!(conf.remote_cache_retries, 0);
+        assert_eq!(conf.remote_cache_timeout_in_s, None);
+        assert_eq!(conf.temp_path, None);
+        assert!(conf.bucket_replicas.is_empty());
+        assert_eq!(conf.max_cached_size_in_bytes, None);
+        assert_eq!(conf.head_cache_ttl_in_s, None);
+        assert!(!conf.head_cache_on_disk);
+        assert!(!conf.offline);
+        assert!(!conf.checksum_sidecars);
+
+        let settings =
+            Configuration::read_settings(&dir.path().join("missing")).unwrap();
+        assert!(settings
+            .iter()
+            .all(|setting| setting.source == SettingSource::Default));
+    }
+
+    #[test]
+    fn test_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("horst3.conf");
+        fs::write(&path, "cache_path = /a\ncache_size_limit = 1GB\n").unwrap();
+        let overrides = Overrides {
+            config_path: Some(path.clone()),
+            cache_path: Some("/b".to_string()),
+            cache_size_limit: None,
+            offline: true,
+        };
+        let settings =
+            Configuration::read_settings_with_overrides(&overrides).unwrap();
+        assert_eq!(settings[0].value.as_deref(), Some("/b"));
+        assert_eq!(settings[0].source, SettingSource::CommandLine);
+        assert_eq!(settings[1].value.as_deref(), Some("1GB"));
+        assert_eq!(settings[1].source, SettingSource::File(path));
+
+        let conf = Configuration::open_with_overrides(&overrides).unwrap();
+        assert_eq!(conf.cache_path, PathBuf::from("/b"));
+        assert_eq!(conf.cache_size_limit_in_bytes, 1000 * 1000 * 1000);
+        assert!(conf.offline);
+
+        let overrides = Overrides {
+            config_path: Some(dir.path().join("missing")),
+            ..Overrides::default()
+        };
+        assert!(Configuration::open_with_overrides(&overrides).is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_as_secs() {
+        assert_eq!(parse_duration_as_secs("30"), Some(30));
+        assert_eq!(parse_duration_as_secs("30s"), Some(30));
+        assert_eq!(parse_duration_as_secs("2m"), Some(2 * 60));
+        assert_eq!(parse_duration_as_secs("12h"), Some(12 * 60 * 60));
+        assert_eq!(parse_duration_as_secs("30 d"), Some(30 * 24 * 60 * 60));
+        assert_eq!(parse_duration_as_secs("d"), None);
+        assert_eq!(parse_duration_as_secs("1w"), None);
+        assert_eq!(
+            parse_duration_as_secs(&format!("{}s", u64::MAX)),
+            Some(u64::MAX)
+        );
+        assert_eq!(parse_duration_as_secs(&format!("{}d", u64::MAX)), None);
+    }
+
     #[test]
     fn test_parse_size_as_bytes() {
         assert_eq!(parse_size_as_bytes("16GiB"), Some(16 * 1024 * 1024 * 1024));
@@ -0,0 +1,401 @@
+use crate::cache::{Cache, CacheError};
+use crate::configuration::{check_config, Configuration, Overrides};
+use crate::process::aws_output;
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+/// Clocks further apart than this make access times and the TTL wrong
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// 2020-01-01, before which the clock is certainly wrong
+const EARLIEST_PLAUSIBLE_TIME_IN_S: u64 = 1_577_836_800;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    /// Things work, but not as well as they could
+    Warning,
+    Failed,
+}
+
+/// Result of one of the checks made by `run_checks`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Check {
+    /// What was checked, e.g. "configuration"
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// What was found, and for problems, how to fix them
+    pub message: String,
+}
+
+impl Check {
+    fn new(
+        name: &'static str,
+        status: CheckStatus,
+        message: impl Into<String>,
+    ) -> Check {
+        Check {
+            name,
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// Check the setup for the problems new users most often run into
+///
+/// This checks that the configuration file is valid, that the cache
+/// directory is writable, that there is disk space for the cache to
+/// fill up to its limit, that the aws CLI is installed and has working
+/// credentials, that the remote cache server is reachable if there is
+/// one, and that the clock is right. Checks that need the cache are
+/// skipped if it can't be opened.
+pub fn run_checks(overrides: &Overrides) -> Vec<Check> {
+    let mut checks = vec![check_configuration(overrides)];
+    let conf = match Configuration::read_settings_with_overrides(overrides) {
+        Ok(settings) => Configuration::from_settings(&settings),
+        Err(_) => return checks,
+    };
+    let cache_path = conf.cache_path.clone();
+    match Cache::open_with_configuration(conf) {
+        Ok(cache) => {
+            checks.push(check_writable(&cache));
+            checks.push(check_disk_space(&cache));
+            checks.push(check_clock(&cache));
+            checks.push(check_aws());
+            if let (Some(remote), Some(url)) =
+                (cache.remote_cache(), cache.remote_cache_url())
+            {
+                checks.push(match remote.check_reachable() {
+                    Ok(()) => Check::new(
+                        "remote cache",
+                        CheckStatus::Ok,
+                        format!("{} is reachable", url),
+                    ),
+                    Err(err) => Check::new(
+                        "remote cache",
+                        CheckStatus::Failed,
+                        format!(
+                            "{}. Check remote_cache_url, or remove it to \
+                             download from S3 only",
+                            describe(&err)
+                        ),
+                    ),
+                });
+            }
+        }
+        Err(err) => checks.push(cache_open_failure(&cache_path, &err)),
+    }
+    checks
+}
+
+fn check_configuration(overrides: &Overrides) -> Check {
+    let failed =
+        |message| Check::new("configuration", CheckStatus::Failed, message);
+    let path = match Configuration::path_with_overrides(overrides) {
+        Ok(path) => path,
+        Err(err) => return failed(describe(&err)),
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err)
+            if err.kind() == io::ErrorKind::NotFound
+                && overrides.config_path.is_none() =>
+        {
+            return Check::new(
+                "configuration",
+                CheckStatus::Ok,
+                format!("{} doesn't exist, using defaults", path.display()),
+            );
+        }
+        Err(err) => {
+            return failed(format!(
+                "failed to read {}: {}",
+                path.display(),
+                err
+            ))
+        }
+    };
+    let problems = check_config(&contents);
+    if problems.is_empty() {
+        Check::new(
+            "configuration",
+            CheckStatus::Ok,
+            format!("{} is valid", path.display()),
+        )
+    } else {
+        failed(format!(
+            "{}: {}. Invalid values fall back to the defaults",
+            path.display(),
+            problems.join("; ")
+        ))
+    }
+}
+
+fn cache_open_failure(cache_path: &Path, err: &CacheError) -> Check {
+    let message = format!(
+        "{}. Check that {} is writable, or set cache_path to a directory \
+         that is",
+        describe(err),
+        cache_path.display()
+    );
+    Check::new("cache directory", CheckStatus::Failed, message)
+}
+
+/// Path of the file written to check that a directory is writable
+///
+/// It has the .tmp extension, so `Cache::gc` deletes it if horst3 is
+/// interrupted before deleting it itself.
+fn probe_path(dir: &Path) -> PathBuf {
+    dir.join("doctor-probe.tmp")
+}
+
+fn check_writable(cache: &Cache) -> Check {
+    let mut dirs = vec![cache.root()];
+    if cache.temp_root() != cache.root() {
+        dirs.push(cache.temp_root());
+    }
+    for dir in &dirs {
+        let path = probe_path(dir);
+        if let Err(err) =
+            fs::write(&path, b"horst3").and_then(|()| fs::remove_file(&path))
+        {
+            return Check::new(
+                "cache directory",
+                CheckStatus::Failed,
+                format!(
+                    "failed to write to {}: {}. Fix its permissions, or \
+                     set cache_path or temp_path to a writable directory",
+                    dir.display(),
+                    err
+                ),
+            );
+        }
+    }
+    let dirs: Vec<String> =
+        dirs.iter().map(|dir| dir.display().to_string()).collect();
+    Check::new(
+        "cache directory",
+        CheckStatus::Ok,
+        format!("{} is writable", dirs.join(" and ")),
+    )
+}
+
+/// Get the space available to unprivileged users from the output of
+/// `df -Pk`
+fn parse_df_available(output: &str) -> Option<u64> {
+    let line = output.lines().nth(1)?;
+    let available_kib: u64 = line.split_whitespace().nth(3)?.parse().ok()?;
+    Some(available_kib * 1024)
+}
+
+fn check_disk_space(cache: &Cache) -> Check {
+    let warning =
+        |message| Check::new("disk space", CheckStatus::Warning, message);
+    let output = match Command::new("df").arg("-Pk").arg(cache.root()).output()
+    {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            return warning(format!(
+                "failed to check ({})",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+        Err(err) => return warning(format!("failed to run df: {}", err)),
+    };
+    let available =
+        match parse_df_available(&String::from_utf8_lossy(&output.stdout)) {
+            Some(available) => available,
+            None => return warning("failed to parse the output of df".into()),
+        };
+    let usage: u64 = match cache.entries() {
+        Ok(entries) => entries.iter().map(|entry| entry.size).sum(),
+        Err(err) => return warning(describe(&err)),
+    };
+    let limit = cache.size_limit();
+    if available.saturating_add(usage) < limit {
+        warning(format!(
+            "the cache can grow to {} bytes, but it uses {} and only {} \
+             are free. Free up space or lower cache_size_limit",
+            limit, usage, available
+        ))
+    } else {
+        Check::new(
+            "disk space",
+            CheckStatus::Ok,
+            format!("{} bytes free for a cache limit of {}", available, limit),
+        )
+    }
+}
+
+/// Check the system clock, and that the clock of the filesystem holding
+/// the cache agrees with it, which matters when it's on a file server
+fn check_clock(cache: &Cache) -> Check {
+    let failed = |message| Check::new("clock", CheckStatus::Failed, message);
+    let now = SystemTime::now();
+    let now_in_s = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if now_in_s < EARLIEST_PLAUSIBLE_TIME_IN_S {
+        return failed(
+            "the system clock is set before 2020. Set it, e.g. with NTP".into(),
+        );
+    }
+    let path = probe_path(cache.root());
+    let modified = fs::write(&path, b"horst3")
+        .and_then(|()| fs::metadata(&path))
+        .and_then(|metadata| metadata.modified());
+    let _ = fs::remove_file(&path);
+    let modified = match modified {
+        Ok(modified) => modified,
+        Err(err) => {
+            return failed(format!(
+                "failed to get a file time from {}: {}",
+                cache.root().display(),
+                err
+            ))
+        }
+    };
+    let skew = match modified.duration_since(now) {
+        Ok(ahead) => ahead,
+        Err(err) => err.duration(),
+    };
+    if skew > MAX_CLOCK_SKEW {
+        return Check::new(
+            "clock",
+            CheckStatus::Warning,
+            format!(
+                "the filesystem holding {} is {}s off from this machine's \
+                 clock, which throws off access times and cache_ttl. \
+                 Sync both clocks with NTP",
+                cache.root().display(),
+                skew.as_secs()
+            ),
+        );
+    }
+    Check::new("clock", CheckStatus::Ok, "the clock looks right")
+}
+
+/// Check that the aws CLI is installed and has working credentials
+///
+/// S3 rejects requests signed with a clock that is too far off, so
+/// this catches a wrong clock too.
+fn check_aws() -> Check {
+    let failed = |message| Check::new("aws", CheckStatus::Failed, message);
+    let output = match aws_output(Command::new("aws").args([
+        "sts",
+        "get-caller-identity",
+        "--query",
+        "Arn",
+        "--output",
+        "text",
+    ])) {
+        Ok(output) => output,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return failed(
+                "the aws CLI isn't installed or isn't in PATH. Install it \
+                 from https://aws.amazon.com/cli/"
+                    .into(),
+            )
+        }
+        Err(err) => {
+            return failed(format!("failed to run the aws CLI: {}", err))
+        }
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        return Check::new(
+            "aws",
+            CheckStatus::Ok,
+            format!("credentials are for {}", stdout.trim()),
+        );
+    }
+    let contains_any = |patterns: &[&str]| {
+        patterns.iter().any(|pattern| stderr.contains(pattern))
+    };
+    let hint = if contains_any(&["Unable to locate credentials"]) {
+        "No credentials are set up. Run \"aws configure\" or \"aws sso login\""
+    } else if contains_any(&["ExpiredToken", "has expired"]) {
+        "The credentials have expired. Log in again, or set \
+         credentials_refresh_command to do it automatically"
+    } else if contains_any(&["Signature expired", "RequestTimeTooSkewed"]) {
+        "The system clock is too far off for AWS to accept requests. \
+         Sync it with NTP"
+    } else if contains_any(&["Could not connect to the endpoint URL"]) {
+        "AWS can't be reached. Check the network connection and proxy \
+         settings"
+    } else {
+        "Check the credentials with \"aws sts get-caller-identity\""
+    };
+    failed(format!("{}. {}", stderr.trim(), hint))
+}
+
+/// Format an error followed by the errors that caused it
+fn describe(err: &dyn Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message += &format!(": {}", err);
+        source = err.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_df_available() {
+        let output = "Filesystem     1024-blocks      Used Available Capacity \
+                      Mounted on\n\
+                      /dev/sda1        41152736  20000000  19039200      52% /\n";
+        assert_eq!(parse_df_available(output), Some(19_039_200 * 1024));
+        assert_eq!(parse_df_available("Filesystem\n"), None);
+    }
+
+    #[test]
+    fn test_local_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("horst3.conf");
+        let cache_path = dir.path().join("cache");
+        let overrides = Overrides {
+            config_path: Some(config_path.clone()),
+            cache_path: Some(cache_path.display().to_string()),
+            cache_size_limit: None,
+            offline: false,
+        };
+        assert_eq!(check_configuration(&overrides).status, CheckStatus::Failed);
+
+        fs::write(&config_path, "cache_size_limit = lots\n").unwrap();
+        let check = check_configuration(&overrides);
+        assert_eq!(check.status, CheckStatus::Failed);
+        assert!(check.message.contains("line 1: invalid size"));
+
+        fs::write(&config_path, "cache_size_limit = 1GiB\n").unwrap();
+        assert_eq!(check_configuration(&overrides).status, CheckStatus::Ok);
+
+        let cache = Cache::open_with_configuration(
+            Configuration::open_with_overrides(&overrides).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(check_writable(&cache).status, CheckStatus::Ok);
+        assert_eq!(check_clock(&cache).status, CheckStatus::Ok);
+        assert!(!probe_path(&cache_path).exists());
+
+        // Another process can use the cache at the same time
+        let other_cache = Cache::open_with_configuration(
+            Configuration::open_with_overrides(&overrides).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(check_writable(&other_cache).status, CheckStatus::Ok);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::cancel::CancelToken;
+use crate::process::set_bucket_region;
+use crate::progress::Progress;
+use crate::s3::{HeadObject, S3Error, S3Url};
+use crate::store::ObjectStore;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long requests go straight to the replica after its bucket failed
+const FAILOVER_DURATION: Duration = Duration::from_secs(60);
+
+/// A copy of a bucket, usually in another region
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replica {
+    pub bucket: String,
+    /// Region of the replica bucket, if not the default region
+    pub region: Option<String>,
+}
+
+impl Replica {
+    /// Parse `<bucket>[@<region>]`
+    pub(crate) fn parse(s: &str) -> Option<Replica> {
+        let (bucket, region) = match s.split_once('@') {
+            Some((bucket, region)) => (bucket, Some(region)),
+            None => (s, None),
+        };
+        if bucket.is_empty() || region == Some("") {
+            return None;
+        }
+        Some(Replica {
+            bucket: bucket.to_string(),
+            region: region.map(str::to_string),
+        })
+    }
+}
+
+impl std::fmt::Display for Replica {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.region {
+            Some(region) => write!(f, "{} ({})", self.bucket, region),
+            None => write!(f, "{}", self.bucket),
+        }
+    }
+}
+
+/// Check if an error could be fixed by asking another region
+///
+/// Missing objects and bad credentials would fail in the replica too.
+fn is_regional(err: &S3Error) -> bool {
+    matches!(err, S3Error::NetworkError(_) | S3Error::CommandFailed(..))
+}
+
+/// Writer that counts the bytes written through it
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    written: u64,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Object store that retries requests in a bucket's replica when the
+/// bucket fails
+///
+/// Once a bucket fails, requests for it go straight to its replica for
+/// a minute, so that a region outage doesn't slow down every request.
+/// Downloads that fail part way through are resumed from the replica.
+pub struct FailoverStore {
+    inner: Arc<dyn ObjectStore>,
+    replicas: BTreeMap<String, Replica>,
+    /// Buckets whose requests go to the replica, and until when
+    failed: Mutex<HashMap<String, Instant>>,
+}
+
+impl FailoverStore {
+    /// Make requests through `inner`, failing over from each bucket in
+    /// `replicas` to its replica
+    ///
+    /// The replicas' regions are passed to the aws commands run for
+    /// their buckets.
+    pub fn new(
+        inner: Arc<dyn ObjectStore>,
+        replicas: BTreeMap<String, Replica>,
+    ) -> FailoverStore {
+        for replica in replicas.values() {
+            if let Some(region) = &replica.region {
+                set_bucket_region(&replica.bucket, region);
+            }
+        }
+        FailoverStore {
+            inner,
+            replicas,
+            failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn replica_url(&self, url: &S3Url) -> Option<(&Replica, S3Url)> {
+        let replica = self.replicas.get(&url.bucket)?;
+        Some((replica, S3Url::new(replica.bucket.clone(), url.key.clone())))
+    }
+
+    fn is_failed_over(&self, bucket: &str) -> bool {
+        let mut failed = self.failed.lock().unwrap();
+        match failed.get(bucket) {
+            Some(until) if Instant::now() < *until => true,
+            Some(_) => {
+                failed.remove(bucket);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Get the whole object, or the rest of it after `offset`
+    fn get_from(
+        &self,
+        url: &S3Url,
+        offset: u64,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        if offset == 0 {
+            self.inner.get_object(url, out, total, progress, cancel)
+        } else {
+            self.inner
+                .get_object_from(url, offset, out, total, progress, cancel)
+        }
+    }
+
+    fn fail_over(&self, url: &S3Url, replica: &Replica, err: &S3Error) {
+        warn!(
+            "{} failed ({}), using replica {} for {}s",
+            url.bucket,
+            err,
+            replica,
+            FAILOVER_DURATION.as_secs()
+        );
+        self.failed
+            .lock()
+            .unwrap()
+            .insert(url.bucket.clone(), Instant::now() + FAILOVER_DURATION);
+    }
+}
+
+impl ObjectStore for FailoverStore {
+    fn head_object(&self, url: &S3Url) -> Result<HeadObject, S3Error> {
+        let (replica, replica_url) = match self.replica_url(url) {
+            Some(replica) => replica,
+            None => return self.inner.head_object(url),
+        };
+        if self.is_failed_over(&url.bucket) {
+            return self.inner.head_object(&replica_url);
+        }
+        match self.inner.head_object(url) {
+            Err(err) if is_regional(&err) => {
+                self.fail_over(url, replica, &err);
+                self.inner.head_object(&replica_url)
+            }
+            result => result,
+        }
+    }
+
+    fn get_object(
+        &self,
+        url: &S3Url,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        self.get_object_from(url, 0, out, total, progress, cancel)
+    }
+
+    fn get_object_from(
+        &self,
+        url: &S3Url,
+        offset: u64,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        let (replica, replica_url) = match self.replica_url(url) {
+            Some(replica) => replica,
+            None => {
+                return self.get_from(url, offset, out, total, progress, cancel)
+            }
+        };
+        if self.is_failed_over(&url.bucket) {
+            return self.get_from(
+                &replica_url,
+                offset,
+                out,
+                total,
+                progress,
+                cancel,
+            );
+        }
+        let mut counting = CountingWriter {
+            inner: out,
+            written: 0,
+        };
+        match self.get_from(url, offset, &mut counting, total, progress, cancel)
+        {
+            Err(err) if is_regional(&err) => {
+                self.fail_over(url, replica, &err);
+                let offset = offset + counting.written;
+                self.get_from(
+                    &replica_url,
+                    offset,
+                    out,
+                    total,
+                    progress,
+                    cancel,
+                )
+            }
+            result => result,
+        }
+    }
+
+    fn failed_over_to(&self, url: &S3Url) -> Option<String> {
+        if !self.is_failed_over(&url.bucket) {
+            return None;
+        }
+        self.replicas.get(&url.bucket).map(Replica::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::MemoryStore;
+
+    /// Store where the "data" bucket fails like an unreachable region
+    struct PrimaryDown(Arc<MemoryStore>);
+
+    impl ObjectStore for PrimaryDown {
+        fn head_object(&self, url: &S3Url) -> Result<HeadObject, S3Error> {
+            if url.bucket == "data" {
+                return Err(S3Error::NetworkError(url.to_string()));
+            }
+            self.0.head_object(url)
+        }
+
+        fn get_object(
+            &self,
+            url: &S3Url,
+            out: &mut dyn Write,
+            total: Option<u64>,
+            progress: &mut dyn FnMut(Progress),
+            cancel: &CancelToken,
+        ) -> Result<(), S3Error> {
+            if url.bucket == "data" {
+                // Fail part way through
+                out.write_all(b"he").map_err(S3Error::WriteError)?;
+                return Err(S3Error::NetworkError(url.to_string()));
+            }
+            self.0.get_object(url, out, total, progress, cancel)
+        }
+    }
+
+    #[test]
+    fn test_replica_parse() {
+        assert_eq!(
+            Replica::parse("data-eu@eu-west-1").unwrap().to_string(),
+            "data-eu (eu-west-1)"
+        );
+        assert_eq!(Replica::parse("data-eu").unwrap().region, None);
+        assert_eq!(Replica::parse("@eu-west-1"), None);
+        assert_eq!(Replica::parse("data-eu@"), None);
+    }
+
+    #[test]
+    fn test_failover() {
+        let memory = Arc::new(MemoryStore::new());
+        let primary = S3Url::new("data".to_string(), "a.bin".to_string());
+        let copy = S3Url::new("data-eu".to_string(), "a.bin".to_string());
+        memory.insert(
+            &copy,
+            b"hello",
+            Some("5d41402abc4b2a76b9719d911017c592"),
+        );
+        let mut replicas = BTreeMap::new();
+        replicas.insert("data".to_string(), Replica::parse("data-eu").unwrap());
+
+        // The primary bucket doesn't have the object, which isn't a
+        // reason to fail over
+        let store = FailoverStore::new(memory.clone(), replicas.clone());
+        assert!(matches!(
+            store.head_object(&primary),
+            Err(S3Error::NotFound(_))
+        ));
+        assert_eq!(store.failed_over_to(&primary), None);
+
+        let store = FailoverStore::new(Arc::new(PrimaryDown(memory)), replicas);
+        let mut out = Vec::new();
+        store
+            .get_object(
+                &primary,
+                &mut out,
+                None,
+                &mut |_| {},
+                &CancelToken::new(),
+            )
+            .unwrap();
+        assert_eq!(out, b"hello");
+        assert_eq!(store.failed_over_to(&primary).unwrap(), "data-eu");
+        assert_eq!(store.head_object(&primary).unwrap().content_length, 5);
+
+        let other = S3Url::new("other".to_string(), "a.bin".to_string());
+        assert_eq!(store.failed_over_to(&other), None);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::cancel::CancelToken;
+use crate::clock::Clock;
+use crate::progress::Progress;
+use crate::s3::{HeadObject, HeadObjectMetadata, S3Error, S3Url};
+use crate::store::ObjectStore;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTimeError;
+
+/// A clock that only moves when told to
+#[derive(Debug, Default)]
+pub struct FakeClock {
+    now_in_s: AtomicU64,
+}
+
+impl FakeClock {
+    pub fn new(now_in_s: u64) -> FakeClock {
+        FakeClock {
+            now_in_s: AtomicU64::new(now_in_s),
+        }
+    }
+
+    pub fn set(&self, now_in_s: u64) {
+        self.now_in_s.store(now_in_s, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.now_in_s.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now_in_s(&self) -> Result<u64, SystemTimeError> {
+        Ok(self.now_in_s.load(Ordering::SeqCst))
+    }
+}
+
+struct MemoryObject {
+    data: Vec<u8>,
+    md5sum: Option<String>,
+}
+
+/// An object store that keeps objects in memory
+///
+/// Objects are keyed by URL. Like objects uploaded with
+/// `S3Url::upload`, objects inserted with an md5sum can be cached.
+#[derive(Default)]
+pub struct MemoryStore {
+    objects: Mutex<HashMap<String, MemoryObject>>,
+    get_count: AtomicUsize,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Add or replace an object
+    pub fn insert(&self, url: &S3Url, data: &[u8], md5sum: Option<&str>) {
+        let object = MemoryObject {
+            data: data.to_vec(),
+            md5sum: md5sum.map(str::to_string),
+        };
+        self.objects.lock().unwrap().insert(url.to_string(), object);
+    }
+
+    /// Number of times an object's contents were requested
+    pub fn get_count(&self) -> usize {
+        self.get_count.load(Ordering::SeqCst)
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn head_object(&self, url: &S3Url) -> Result<HeadObject, S3Error> {
+        let objects = self.objects.lock().unwrap();
+        let object = objects
+            .get(&url.to_string())
+            .ok_or_else(|| S3Error::NotFound(url.to_string()))?;
+        Ok(HeadObject {
+            last_modified: "1970-01-01T00:00:00.000Z".to_string(),
+            content_length: object.data.len() as u64,
+            etag: format!("\"{:x}\"", md5::compute(&object.data)),
+            storage_class: None,
+            metadata: HeadObjectMetadata {
+                md5sum: object.md5sum.clone(),
+            },
+        })
+    }
+
+    fn get_object(
+        &self,
+        url: &S3Url,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        self.get_object_from(url, 0, out, total, progress, cancel)
+    }
+
+    fn get_object_from(
+        &self,
+        url: &S3Url,
+        offset: u64,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        self.get_count.fetch_add(1, Ordering::SeqCst);
+        if cancel.is_cancelled() {
+            return Err(S3Error::Cancelled);
+        }
+        let objects = self.objects.lock().unwrap();
+        let object = objects
+            .get(&url.to_string())
+            .ok_or_else(|| S3Error::NotFound(url.to_string()))?;
+        let data = object.data.get(offset as usize..).unwrap_or_default();
+        out.write_all(data).map_err(S3Error::WriteError)?;
+        progress(Progress {
+            transferred: data.len() as u64,
+            total,
+            from_cache: false,
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use crate::cache::{Cache, CacheError};
+use crate::hash::md5sum_file;
+use crate::s3::{DownloadOutcome, DownloadSummary};
+use log::{debug, warn};
+use std::fs;
+use std::path::Path;
+
+/// Copy an object with a known md5sum and size to `path` through the
+/// cache
+///
+/// This is shared by the sources other than S3. `fetch` copies the
+/// object from `source` to the path it's given: a temporary path in
+/// the cache on a miss, or `path` itself if the object bypasses the
+/// cache. Fetched objects are checked against the md5sum, and
+/// `mismatch` makes the error for ones that don't match.
+pub(crate) fn fetch_through_cache<E: From<CacheError>>(
+    cache: &Cache,
+    source: &str,
+    md5sum: &str,
+    size: u64,
+    path: &Path,
+    fetch: &dyn Fn(&Path) -> Result<(), E>,
+    mismatch: &dyn Fn() -> E,
+) -> Result<DownloadSummary, E> {
+    let outcome = if cache.contains(md5sum) {
+        debug!("cache hit for {} ({})", source, md5sum);
+        if let Err(err) = cache.add_alias(md5sum, source) {
+            warn!("failed to record alias of {}: {}", md5sum, err);
+        }
+        cache.record_hit(size)?;
+        DownloadOutcome::CacheHit
+    } else if let Some(reason) = cache.admit(size)? {
+        debug!(
+            "bypassing cache for {} ({} bytes): {}",
+            source, size, reason
+        );
+        fetch(path)?;
+        return Ok(DownloadSummary {
+            outcome: DownloadOutcome::DirectBypass { reason },
+            bytes: size,
+        });
+    } else {
+        let _lock = cache.lock_entry(md5sum)?;
+        if cache.contains(md5sum) {
+            debug!("{} was cached by another download", md5sum);
+            cache.record_hit(size)?;
+            DownloadOutcome::CacheHit
+        } else {
+            debug!("cache miss for {} ({}), downloading", source, md5sum);
+            let tmp_path = cache.temporary_path(md5sum);
+            fetch(&tmp_path)?;
+            let actual = md5sum_file(&tmp_path)
+                .map_err(|err| CacheError::HashError(tmp_path.clone(), err))?;
+            if actual != md5sum {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(mismatch());
+            }
+            cache.finish_temporary(md5sum)?;
+            if let Err(err) = cache.set_source(md5sum, source) {
+                warn!("failed to record source of {}: {}", md5sum, err);
+            }
+            cache.record_miss(size)?;
+            DownloadOutcome::CachedThenCopied
+        }
+    };
+    if let Err(err) = cache.record_access(md5sum) {
+        warn!("failed to record access to {}: {}", md5sum, err);
+    }
+    cache.copy(md5sum, path)?;
+    Ok(DownloadSummary {
+        outcome,
+        bytes: size,
+    })
+}
@@ -0,0 +1,235 @@
+use crate::manifest::is_md5sum;
+#[cfg(feature = "asm")]
+use md5_asm::Digest;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+/// Size of the chunks files are read in
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of chunks read ahead of the one being hashed
+const CHUNKS_READ_AHEAD: usize = 2;
+
+/// Extension of the checksum files that some producers publish next to
+/// each file, e.g. `app.tar.md5` for `app.tar`
+pub(crate) const SIDECAR_EXTENSION: &str = ".md5";
+
+/// Get the md5sum from the contents of a checksum file
+///
+/// Both the md5sum output format (`<md5sum>  <name>`, or just the
+/// md5sum) and the BSD one (`MD5 (<name>) = <md5sum>`) are accepted.
+/// Only the first line is used.
+pub(crate) fn parse_md5sum_file(contents: &str) -> Option<String> {
+    let line = contents.lines().next()?.trim();
+    let md5sum = match line.strip_prefix("MD5 (") {
+        Some(rest) => rest.rsplit_once(" = ")?.1,
+        None => line.split_whitespace().next()?,
+    };
+    Some(md5sum.to_ascii_lowercase()).filter(|md5sum| is_md5sum(md5sum))
+}
+
+/// Incremental md5 hashing
+///
+/// This uses the md-5 crate's assembly implementation with the `asm`
+/// feature, and the md5 crate otherwise.
+#[derive(Clone)]
+struct Md5 {
+    #[cfg(feature = "asm")]
+    inner: md5_asm::Md5,
+    #[cfg(not(feature = "asm"))]
+    inner: md5::Context,
+}
+
+impl Md5 {
+    fn new() -> Md5 {
+        Md5 {
+            #[cfg(feature = "asm")]
+            inner: md5_asm::Md5::new(),
+            #[cfg(not(feature = "asm"))]
+            inner: md5::Context::new(),
+        }
+    }
+
+    #[cfg(feature = "asm")]
+    fn consume(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    #[cfg(not(feature = "asm"))]
+    fn consume(&mut self, data: &[u8]) {
+        self.inner.consume(data);
+    }
+
+    /// Get the md5sum as a lowercase hex string
+    #[cfg(feature = "asm")]
+    fn finish(self) -> String {
+        format!("{:x}", self.inner.finalize())
+    }
+
+    #[cfg(not(feature = "asm"))]
+    fn finish(self) -> String {
+        format!("{:x}", self.inner.compute())
+    }
+}
+
+/// Compute the md5sum of a file as a lowercase hex string
+///
+/// md5 can't be computed in parallel, so to keep the hashing busy the
+/// file is read in another thread, a few chunks ahead.
+pub fn md5sum_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel(CHUNKS_READ_AHEAD);
+        scope.spawn(move || loop {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let chunk = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    buf.truncate(len);
+                    Ok(buf)
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(err) => Err(err),
+            };
+            let failed = chunk.is_err();
+            // The receiver is gone if hashing stopped early
+            if sender.send(chunk).is_err() || failed {
+                break;
+            }
+        });
+        let mut md5 = Md5::new();
+        for chunk in receiver {
+            md5.consume(&chunk?);
+        }
+        Ok(md5.finish())
+    })
+}
+
+/// Writer that computes the md5sum of everything written through it
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    context: Md5,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            context: Md5::new(),
+        }
+    }
+
+    /// Continue hashing data that was written before, such as the
+    /// start of a partial download
+    ///
+    /// `written` is hashed but not written to `inner`.
+    pub fn resume(
+        inner: W,
+        written: &mut dyn Read,
+    ) -> io::Result<HashingWriter<W>> {
+        let mut writer = HashingWriter::new(inner);
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let len = written.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            writer.context.consume(&buf[..len]);
+        }
+        Ok(writer)
+    }
+
+    /// Get the md5sum of the data written so far as a lowercase hex
+    /// string
+    pub fn md5sum(self) -> String {
+        self.context.finish()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.context.consume(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_md5sum_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test");
+        fs::write(&path, "hello").unwrap();
+        assert_eq!(
+            md5sum_file(&path).unwrap(),
+            "5d41402abc4b2a76b9719d911017c592"
+        );
+
+        // Several chunks, the last one partial
+        let data: Vec<u8> =
+            (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+        assert_eq!(
+            md5sum_file(&path).unwrap(),
+            format!("{:x}", md5::compute(&data))
+        );
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(
+            md5sum_file(&path).unwrap(),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert!(md5sum_file(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_parse_md5sum_file() {
+        let md5sum = Some("5d41402abc4b2a76b9719d911017c592".to_string());
+        assert_eq!(
+            parse_md5sum_file("5d41402abc4b2a76b9719d911017c592  a.tar\n"),
+            md5sum
+        );
+        assert_eq!(
+            parse_md5sum_file("5D41402ABC4B2A76B9719D911017C592"),
+            md5sum
+        );
+        assert_eq!(
+            parse_md5sum_file(
+                "MD5 (a = b.tar) = 5d41402abc4b2a76b9719d911017c592\n"
+            ),
+            md5sum
+        );
+        assert_eq!(parse_md5sum_file(""), None);
+        assert_eq!(parse_md5sum_file("not found\n"), None);
+    }
+
+    #[test]
+    fn test_hashing_writer() {
+        let mut out = Vec::new();
+        let mut writer = HashingWriter::new(&mut out);
+        writer.write_all(b"hel").unwrap();
+        writer.write_all(b"lo").unwrap();
+        assert_eq!(writer.md5sum(), "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(out, b"hello");
+
+        let mut out = Vec::new();
+        let mut writer =
+            HashingWriter::resume(&mut out, &mut &b"hel"[..]).unwrap();
+        writer.write_all(b"lo").unwrap();
+        assert_eq!(writer.md5sum(), "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(out, b"lo");
+    }
+}
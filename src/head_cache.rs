@@ -0,0 +1,221 @@
+use crate::s3::{HeadObject, HeadObjectMetadata};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io};
+
+#[derive(Clone, Debug)]
+struct CachedHead {
+    /// When the head was fetched, in seconds since the Unix epoch
+    time: u64,
+    head: HeadObject,
+}
+
+/// Recent head-object results, by URL
+///
+/// Results are kept in memory for the life of the process and, if a
+/// path is given, in a file so that other processes can use them too.
+/// A result is used until it's `ttl_in_s` old, so a change to the
+/// object in that time won't be noticed. The file has one line per
+/// URL: `<time> <size> <etag> <last modified> <storage class> <md5sum>
+/// <url>`, with `-` for a missing storage class or md5sum.
+pub(crate) struct HeadCache {
+    ttl_in_s: u64,
+    path: Option<PathBuf>,
+    heads: Mutex<HashMap<String, CachedHead>>,
+}
+
+fn parse_line(line: &str) -> Option<(String, CachedHead)> {
+    let mut fields = line.splitn(7, ' ');
+    let mut next = || fields.next();
+    let time = next()?.parse().ok()?;
+    let content_length = next()?.parse().ok()?;
+    let etag = next()?.to_string();
+    let last_modified = next()?.to_string();
+    let optional = |field: &str| {
+        Some(field)
+            .filter(|field| *field != "-")
+            .map(str::to_string)
+    };
+    let storage_class = optional(next()?);
+    let md5sum = optional(next()?);
+    let url = next()?.to_string();
+    let head = HeadObject {
+        last_modified,
+        content_length,
+        etag,
+        storage_class,
+        metadata: HeadObjectMetadata { md5sum },
+    };
+    Some((url, CachedHead { time, head }))
+}
+
+fn format_line(url: &str, cached: &CachedHead) -> String {
+    let head = &cached.head;
+    format!(
+        "{} {} {} {} {} {} {}\n",
+        cached.time,
+        head.content_length,
+        head.etag,
+        head.last_modified,
+        head.storage_class.as_deref().unwrap_or("-"),
+        head.metadata.md5sum.as_deref().unwrap_or("-"),
+        url
+    )
+}
+
+fn load(path: &Path) -> io::Result<HashMap<String, CachedHead>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().filter_map(parse_line).collect()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+impl HeadCache {
+    pub(crate) fn new(ttl_in_s: u64, path: Option<PathBuf>) -> HeadCache {
+        HeadCache {
+            ttl_in_s,
+            path,
+            heads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    fn is_fresh(&self, cached: &CachedHead, now: u64) -> bool {
+        cached.time <= now && now - cached.time < self.ttl_in_s
+    }
+
+    /// Get a URL's head if it was fetched less than the TTL ago
+    pub(crate) fn get(
+        &self,
+        url: &str,
+        now: u64,
+    ) -> io::Result<Option<HeadObject>> {
+        let mut heads = self.heads.lock().unwrap();
+        if let Some(cached) = heads.get(url) {
+            if self.is_fresh(cached, now) {
+                return Ok(Some(cached.head.clone()));
+            }
+        }
+        if let Some(path) = &self.path {
+            if let Some(cached) = load(path)?.remove(url) {
+                if self.is_fresh(&cached, now) {
+                    let head = cached.head.clone();
+                    heads.insert(url.to_string(), cached);
+                    return Ok(Some(head));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record a URL's head, dropping expired results from the file
+    pub(crate) fn insert(
+        &self,
+        url: &str,
+        head: &HeadObject,
+        now: u64,
+    ) -> io::Result<()> {
+        let cached = CachedHead {
+            time: now,
+            head: head.clone(),
+        };
+        self.heads
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), cached.clone());
+        self.update_file(now, |heads| {
+            heads.insert(url.to_string(), cached);
+        })
+    }
+
+    /// Forget a URL's head, e.g. because the object was replaced
+    pub(crate) fn remove(&self, url: &str, now: u64) -> io::Result<()> {
+        self.heads.lock().unwrap().remove(url);
+        self.update_file(now, |heads| {
+            heads.remove(url);
+        })
+    }
+
+    fn update_file(
+        &self,
+        now: u64,
+        f: impl FnOnce(&mut HashMap<String, CachedHead>),
+    ) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut heads = load(path)?;
+        heads.retain(|_, cached| self.is_fresh(cached, now));
+        f(&mut heads);
+        let contents: String = heads
+            .iter()
+            .map(|(url, cached)| format_line(url, cached))
+            .collect();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::HeadObjectMetadata;
+
+    fn head(md5sum: &str) -> HeadObject {
+        HeadObject {
+            last_modified: "2024-01-01T00:00:00+00:00".to_string(),
+            content_length: 5,
+            etag: "\"etag\"".to_string(),
+            storage_class: None,
+            metadata: HeadObjectMetadata {
+                md5sum: Some(md5sum.to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn test_head_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("heads.txt");
+        let url = "s3://bucket/key";
+
+        let cache = HeadCache::new(60, None);
+        assert!(cache.get(url, 100).unwrap().is_none());
+        cache.insert(url, &head("aaa"), 100).unwrap();
+        let got = cache.get(url, 159).unwrap().unwrap();
+        assert_eq!(got.metadata.md5sum.as_deref(), Some("aaa"));
+        assert!(cache.get(url, 160).unwrap().is_none());
+        cache.remove(url, 100).unwrap();
+        assert!(cache.get(url, 100).unwrap().is_none());
+
+        // Results in the file are seen by another process
+        let cache = HeadCache::new(60, Some(path.clone()));
+        cache.insert(url, &head("bbb"), 100).unwrap();
+        let mut glacier = head("ccc");
+        glacier.storage_class = Some("GLACIER".to_string());
+        glacier.metadata.md5sum = None;
+        cache.insert("s3://bucket/my file", &glacier, 100).unwrap();
+        let other = HeadCache::new(60, Some(path.clone()));
+        let got = other.get(url, 120).unwrap().unwrap();
+        assert_eq!(got.metadata.md5sum.as_deref(), Some("bbb"));
+        assert_eq!(got.content_length, 5);
+        assert_eq!(got.etag, "\"etag\"");
+        let got = other.get("s3://bucket/my file", 120).unwrap().unwrap();
+        assert_eq!(got.storage_class.as_deref(), Some("GLACIER"));
+        assert_eq!(got.metadata.md5sum, None);
+        other.remove(url, 120).unwrap();
+        let other = HeadCache::new(60, Some(path.clone()));
+        assert!(other.get(url, 120).unwrap().is_none());
+
+        // Expired results are dropped from the file
+        other.insert(url, &head("ddd"), 200).unwrap();
+        assert_eq!(load(&path).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::cache::{Cache, CacheError};
+use crate::fetch::fetch_through_cache;
+use crate::hash::{parse_md5sum_file, SIDECAR_EXTENSION};
+use crate::manifest::is_md5sum;
+use crate::s3::{BypassReason, DownloadOutcome, DownloadSummary};
+use log::{debug, error, warn};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use thiserror::Error;
+
+/// Environment variable holding the value of an Authorization header
+/// to send, e.g. "Bearer <token>"
+const AUTHORIZATION_VAR: &str = "HORST3_HTTP_AUTHORIZATION";
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error(transparent)]
+    CacheError(#[from] CacheError),
+    #[error("checksum mismatch for {0}")]
+    ChecksumMismatch(String),
+    #[error("failed to download {0} ({1})")]
+    CommandFailed(String, ExitStatus),
+    #[error("invalid response headers from {0}")]
+    InvalidResponse(String),
+    #[error("invalid HTTP URL: {0}")]
+    InvalidUrl(String),
+    #[error("failed to run curl")]
+    IoError(#[source] io::Error),
+}
+
+/// A file in an HTTP artifact repository, such as Artifactory or
+/// Nexus, used as the source of an object
+///
+/// The md5sum comes from the X-Checksum-Md5 response header, or with
+/// `checksum_sidecars` set, from a `<url>.md5` file if there is no
+/// header. Files without one are downloaded directly. Requests are
+/// made with curl, which reads credentials from ~/.netrc. If the
+/// HORST3_HTTP_AUTHORIZATION environment variable is set, it's sent as
+/// the Authorization header instead. It's passed to curl on stdin so it
+/// doesn't show up in the process list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpFile {
+    pub url: String,
+}
+
+/// Get the size and md5sum from the headers of a HEAD response
+///
+/// With redirects there are several responses, and the last one is
+/// for the file itself.
+fn parse_headers(headers: &str) -> Option<(u64, Option<String>)> {
+    let response = headers
+        .split("\r\n\r\n")
+        .filter(|response| !response.trim().is_empty())
+        .last()?;
+    let mut size = None;
+    let mut md5sum = None;
+    for line in response.lines() {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            size = value.parse().ok();
+        } else if name.eq_ignore_ascii_case("x-checksum-md5")
+            && is_md5sum(value)
+        {
+            md5sum = Some(value.to_ascii_lowercase());
+        }
+    }
+    Some((size?, md5sum))
+}
+
+impl HttpFile {
+    pub fn from_url(url: &str) -> Result<HttpFile, HttpError> {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            Ok(HttpFile {
+                url: url.to_string(),
+            })
+        } else {
+            Err(HttpError::InvalidUrl(url.to_string()))
+        }
+    }
+
+    /// Run curl with the given arguments followed by the URL, whether
+    /// or not it succeeds
+    fn curl_output(&self, args: &[&str]) -> Result<Output, HttpError> {
+        let authorization = std::env::var(AUTHORIZATION_VAR).ok();
+        let mut command = Command::new("curl");
+        command
+            .args(["--fail", "--silent", "--show-error", "--location"])
+            .arg("--netrc-optional")
+            .args(args);
+        if authorization.is_some() {
+            command.args(["--header", "@-"]).stdin(Stdio::piped());
+        }
+        let mut child = command
+            .arg(&self.url)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(HttpError::IoError)?;
+        if let (Some(authorization), Some(mut stdin)) =
+            (authorization, child.stdin.take())
+        {
+            writeln!(stdin, "Authorization: {}", authorization)
+                .map_err(HttpError::IoError)?;
+        }
+        child.wait_with_output().map_err(HttpError::IoError)
+    }
+
+    /// Run curl with the given arguments followed by the URL
+    fn curl(&self, args: &[&str]) -> Result<Output, HttpError> {
+        let output = self.curl_output(args)?;
+        if !output.status.success() {
+            error!("{}", String::from_utf8_lossy(&output.stderr).trim());
+            return Err(HttpError::CommandFailed(
+                self.url.clone(),
+                output.status,
+            ));
+        }
+        Ok(output)
+    }
+
+    /// Get the size and md5sum (if the server provides it) of the file
+    fn head(&self) -> Result<(u64, Option<String>), HttpError> {
+        let output = self.curl(&["--head"])?;
+        parse_headers(&String::from_utf8_lossy(&output.stdout))
+            .ok_or_else(|| HttpError::InvalidResponse(self.url.clone()))
+    }
+
+    /// Get the md5sum from the `<url>.md5` checksum file next to the
+    /// file, if there is a valid one
+    fn sidecar_md5sum(&self) -> Result<Option<String>, HttpError> {
+        let sidecar = HttpFile {
+            url: format!("{}{}", self.url, SIDECAR_EXTENSION),
+        };
+        let output = sidecar.curl_output(&[])?;
+        if !output.status.success() {
+            debug!("no checksum file at {}", sidecar.url);
+            return Ok(None);
+        }
+        let md5sum =
+            parse_md5sum_file(&String::from_utf8_lossy(&output.stdout));
+        if md5sum.is_none() {
+            warn!("{} isn't a valid checksum file", sidecar.url);
+        }
+        Ok(md5sum)
+    }
+
+    fn fetch(&self, path: &Path) -> Result<(), HttpError> {
+        let path = path.to_str().ok_or_else(|| {
+            HttpError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("non-UTF-8 path: {}", path.display()),
+            ))
+        })?;
+        self.curl(&["--output", path])?;
+        Ok(())
+    }
+
+    /// Download the file to `path` through the cache
+    pub fn download(
+        &self,
+        cache: &Cache,
+        path: &Path,
+    ) -> Result<DownloadSummary, HttpError> {
+        let (size, mut md5sum) = self.head()?;
+        if md5sum.is_none() && cache.checksum_sidecars() {
+            md5sum = self.sidecar_md5sum()?;
+        }
+        cache.record_download(|| self.download_impl(cache, path, size, md5sum))
+    }
+
+    fn download_impl(
+        &self,
+        cache: &Cache,
+        path: &Path,
+        size: u64,
+        md5sum: Option<String>,
+    ) -> Result<DownloadSummary, HttpError> {
+        let md5sum = match md5sum {
+            Some(md5sum) => md5sum,
+            None => {
+                debug!("bypassing cache for {}: no md5sum", self.url);
+                self.fetch(path)?;
+                return Ok(DownloadSummary {
+                    outcome: DownloadOutcome::DirectBypass {
+                        reason: BypassReason::NoMd5sum,
+                    },
+                    bytes: size,
+                });
+            }
+        };
+        fetch_through_cache(
+            cache,
+            &self.url,
+            &md5sum,
+            size,
+            path,
+            &|path| self.fetch(path),
+            &|| HttpError::ChecksumMismatch(self.url.clone()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_headers() {
+        let headers = "HTTP/1.1 302 Found\r\n\
+                       Location: https://cdn/a.tar\r\n\
+                       Content-Length: 0\r\n\
+                       \r\n\
+                       HTTP/1.1 200 OK\r\n\
+                       content-length: 5\r\n\
+                       X-Checksum-Md5: 5D41402ABC4B2A76B9719D911017C592\r\n\
+                       X-Checksum-Sha1: aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d\r\n\
+                       \r\n";
+        assert_eq!(
+            parse_headers(headers),
+            Some((5, Some("5d41402abc4b2a76b9719d911017c592".to_string())))
+        );
+        assert_eq!(
+            parse_headers("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"),
+            Some((5, None))
+        );
+        assert_eq!(parse_headers("HTTP/1.1 200 OK\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_from_url() {
+        assert!(HttpFile::from_url("https://repo/a.tar").is_ok());
+        assert!(HttpFile::from_url("ftp://repo/a.tar").is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::s3::{run_aws, ObjectSummary, S3Error, S3Url};
+use serde::Deserialize;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::process::{Command, Stdio};
+use std::thread;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum InventoryError {
+    #[error("failed to decompress {0}")]
+    DecompressError(String, #[source] io::Error),
+    #[error("{0}: line {1}: invalid row")]
+    InvalidRow(String, usize),
+    #[error("failed to parse inventory manifest {0}")]
+    JsonError(String, #[source] serde_json::Error),
+    #[error("inventory has no {0} field")]
+    MissingField(&'static str),
+    #[error(transparent)]
+    S3Error(#[from] S3Error),
+    #[error("{format} inventories are not supported, only CSV")]
+    UnsupportedFormat { format: String },
+    #[error("inventory lists bucket {inventory}, not {requested}")]
+    WrongBucket {
+        inventory: String,
+        requested: String,
+    },
+}
+
+/// The manifest.json written alongside each inventory report
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InventoryManifest {
+    source_bucket: String,
+    /// ARN of the bucket the report files are in
+    destination_bucket: String,
+    file_format: String,
+    /// Comma-separated names of the columns in the report files
+    file_schema: String,
+    files: Vec<InventoryFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InventoryFile {
+    key: String,
+}
+
+/// The objects listed in an S3 Inventory report
+///
+/// Reading an inventory is much faster than listing a bucket with
+/// millions of objects, at the cost of the listing being up to a day or
+/// a week old.
+#[derive(Clone, Debug)]
+pub struct Inventory {
+    /// Bucket the inventory lists
+    pub bucket: String,
+    pub objects: Vec<ObjectSummary>,
+}
+
+impl Inventory {
+    /// Read the report described by an inventory's manifest.json
+    ///
+    /// Only CSV reports are supported. The report must include the
+    /// size, last modified date and ETag fields. For inventories of
+    /// all object versions, only the current versions are kept.
+    pub fn read(manifest_url: &S3Url) -> Result<Inventory, InventoryError> {
+        let json = download(manifest_url)?;
+        let manifest: InventoryManifest = serde_json::from_slice(&json)
+            .map_err(|err| {
+                InventoryError::JsonError(manifest_url.to_string(), err)
+            })?;
+        if !manifest.file_format.eq_ignore_ascii_case("csv") {
+            return Err(InventoryError::UnsupportedFormat {
+                format: manifest.file_format,
+            });
+        }
+        let schema = Schema::parse(&manifest.file_schema)?;
+        let destination_bucket = manifest
+            .destination_bucket
+            .trim_start_matches("arn:aws:s3:::")
+            .to_string();
+
+        let mut objects = Vec::new();
+        for file in &manifest.files {
+            let url = S3Url::new(destination_bucket.clone(), file.key.clone());
+            let compressed = download(&url)?;
+            let csv = gunzip(&compressed).map_err(|err| {
+                InventoryError::DecompressError(url.to_string(), err)
+            })?;
+            objects.extend(schema.parse_rows(&url.to_string(), &csv[..])?);
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(Inventory {
+            bucket: manifest.source_bucket,
+            objects,
+        })
+    }
+
+    /// Get the objects whose key starts with the prefix's key
+    pub fn objects_under(
+        &self,
+        prefix: &S3Url,
+    ) -> Result<Vec<ObjectSummary>, InventoryError> {
+        if prefix.bucket != self.bucket {
+            return Err(InventoryError::WrongBucket {
+                inventory: self.bucket.clone(),
+                requested: prefix.bucket.clone(),
+            });
+        }
+        Ok(self
+            .objects
+            .iter()
+            .filter(|object| object.key.starts_with(&prefix.key))
+            .cloned()
+            .collect())
+    }
+}
+
+fn download(url: &S3Url) -> Result<Vec<u8>, S3Error> {
+    run_aws("s3 cp", url, &["s3", "cp", &url.to_string(), "-"])
+}
+
+/// Decompress gzip data with the gzip command
+fn gunzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("gzip")
+        .arg("-dc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let output = thread::scope(|scope| {
+        // Write from another thread so that gzip can't block on a full
+        // stdout pipe while we block on a full stdin pipe
+        let writer = scope.spawn(move || stdin.write_all(data));
+        let output = child.wait_with_output()?;
+        writer.join().expect("gzip writer panicked")?;
+        Ok::<_, io::Error>(output)
+    })?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "gzip failed ({})",
+            output.status
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Positions of the fields used from each row of a report
+#[derive(Debug, PartialEq)]
+struct Schema {
+    key: usize,
+    size: usize,
+    last_modified: usize,
+    etag: usize,
+    is_latest: Option<usize>,
+    is_delete_marker: Option<usize>,
+}
+
+impl Schema {
+    fn parse(file_schema: &str) -> Result<Schema, InventoryError> {
+        let fields: Vec<&str> = file_schema.split(',').map(str::trim).collect();
+        let find = |name| fields.iter().position(|field| *field == name);
+        let require =
+            |name| find(name).ok_or(InventoryError::MissingField(name));
+        Ok(Schema {
+            key: require("Key")?,
+            size: require("Size")?,
+            last_modified: require("LastModifiedDate")?,
+            etag: require("ETag")?,
+            is_latest: find("IsLatest"),
+            is_delete_marker: find("IsDeleteMarker"),
+        })
+    }
+
+    /// Parse the rows of a decompressed CSV report
+    ///
+    /// `name` is only used in errors.
+    fn parse_rows(
+        &self,
+        name: &str,
+        csv: impl BufRead,
+    ) -> Result<Vec<ObjectSummary>, InventoryError> {
+        let invalid_row =
+            |line_num| InventoryError::InvalidRow(name.to_string(), line_num);
+        let mut objects = Vec::new();
+        for (index, line) in csv.lines().enumerate() {
+            let line_num = index + 1;
+            let line = line.map_err(|err| {
+                InventoryError::DecompressError(name.to_string(), err)
+            })?;
+            if line.is_empty() {
+                continue;
+            }
+            let fields =
+                parse_csv_line(&line).ok_or_else(|| invalid_row(line_num))?;
+            let field = |index: usize| {
+                fields
+                    .get(index)
+                    .map(String::as_str)
+                    .ok_or_else(|| invalid_row(line_num))
+            };
+            let is_old_version = match self.is_latest {
+                Some(index) => field(index)? == "false",
+                None => false,
+            };
+            let is_delete_marker = match self.is_delete_marker {
+                Some(index) => field(index)? == "true",
+                None => false,
+            };
+            if is_old_version || is_delete_marker {
+                continue;
+            }
+            objects.push(ObjectSummary {
+                key: url_decode(field(self.key)?)
+                    .ok_or_else(|| invalid_row(line_num))?,
+                last_modified: field(self.last_modified)?.to_string(),
+                etag: field(self.etag)?.to_string(),
+                size: field(self.size)?
+                    .parse()
+                    .map_err(|_| invalid_row(line_num))?,
+            });
+        }
+        Ok(objects)
+    }
+}
+
+/// Split a CSV line into fields
+///
+/// Fields may be quoted, with `""` standing for a quote inside a
+/// quoted field. Returns None if a quote is unterminated.
+fn parse_csv_line(line: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+/// Decode a key from a CSV report, which is URL-encoded
+fn url_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv_line() {
+        assert_eq!(
+            parse_csv_line(r#""bucket","a,b","say ""hi""",,3"#).unwrap(),
+            ["bucket", "a,b", r#"say "hi""#, "", "3"]
+        );
+        assert!(parse_csv_line(r#""unterminated"#).is_none());
+    }
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!(
+            url_decode("dir/my+file%2B1.txt").unwrap(),
+            "dir/my file+1.txt"
+        );
+        assert_eq!(url_decode("caf%C3%A9").unwrap(), "café");
+        assert!(url_decode("bad%2").is_none());
+        assert!(url_decode("bad%zz").is_none());
+    }
+
+    #[test]
+    fn test_parse_rows() {
+        let schema = Schema::parse(
+            "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size, \
+             LastModifiedDate, ETag",
+        )
+        .unwrap();
+        let csv = concat!(
+            r#""b","a%20b","v2","true","false","5","2024-06-01","etag2""#,
+            "\n",
+            r#""b","a%20b","v1","false","false","3","2024-05-01","etag1""#,
+            "\n",
+            r#""b","gone","v3","true","true","","2024-06-01","""#,
+            "\n",
+        );
+        let objects = schema.parse_rows("data.csv", csv.as_bytes()).unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, "a b");
+        assert_eq!(objects[0].size, 5);
+        assert_eq!(objects[0].last_modified, "2024-06-01");
+        assert_eq!(objects[0].etag, "etag2");
+
+        assert!(matches!(
+            schema.parse_rows("data.csv", &b"\"b\",\"k\"\n"[..]),
+            Err(InventoryError::InvalidRow(_, 1))
+        ));
+        assert!(matches!(
+            Schema::parse("Bucket, Key"),
+            Err(InventoryError::MissingField("Size"))
+        ));
+    }
+}
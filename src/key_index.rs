@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::{fs, io};
+
+/// Which object URLs are known to have which contents
+///
+/// Many keys often have the same contents, e.g. an artifact that is
+/// uploaded under both a version and a "latest" prefix. The index maps
+/// each URL downloaded through the cache to the md5sum of its contents,
+/// so that the URLs of an entry can be listed and a URL can be looked
+/// up without asking S3. It's stored one `<md5sum> <url>` line per URL.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct KeyIndex {
+    md5sums: BTreeMap<String, String>,
+}
+
+impl KeyIndex {
+    fn parse(s: &str) -> KeyIndex {
+        KeyIndex {
+            md5sums: s
+                .lines()
+                .filter_map(|line| {
+                    let (md5sum, url) = line.split_once(' ')?;
+                    Some((url.to_string(), md5sum.to_string()))
+                })
+                .collect(),
+        }
+    }
+
+    fn format(&self) -> String {
+        self.md5sums
+            .iter()
+            .map(|(url, md5sum)| format!("{} {}\n", md5sum, url))
+            .collect()
+    }
+
+    /// Read the index from a file, or get an empty index if it doesn't
+    /// exist
+    pub(crate) fn load(path: &Path) -> io::Result<KeyIndex> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(KeyIndex::parse(&contents)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(KeyIndex::default())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.format())
+    }
+
+    /// Get the md5sum of a URL's contents, if known
+    pub(crate) fn get(&self, url: &str) -> Option<&str> {
+        self.md5sums.get(url).map(String::as_str)
+    }
+
+    /// Record a URL's md5sum, returning whether that changed the index
+    pub(crate) fn insert(&mut self, url: &str, md5sum: &str) -> bool {
+        if self.get(url) == Some(md5sum) {
+            return false;
+        }
+        self.md5sums.insert(url.to_string(), md5sum.to_string());
+        true
+    }
+
+    /// Get the URLs of each md5sum, in order
+    pub(crate) fn urls_by_md5sum(&self) -> BTreeMap<&str, Vec<&str>> {
+        let mut urls: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (url, md5sum) in &self.md5sums {
+            urls.entry(md5sum).or_default().push(url);
+        }
+        urls
+    }
+
+    /// Keep only the URLs whose md5sum matches the predicate, returning
+    /// how many were removed
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&str) -> bool) -> usize {
+        let len = self.md5sums.len();
+        self.md5sums.retain(|_, md5sum| f(md5sum));
+        len - self.md5sums.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.txt");
+        let mut index = KeyIndex::load(&path).unwrap();
+        assert_eq!(index, KeyIndex::default());
+
+        assert!(index.insert("s3://b/v1/app.tar", "aaa"));
+        assert!(index.insert("s3://b/latest/app.tar", "aaa"));
+        assert!(index.insert("s3://b/my file", "bbb"));
+        assert!(!index.insert("s3://b/v1/app.tar", "aaa"));
+        index.save(&path).unwrap();
+        let index = KeyIndex::load(&path).unwrap();
+        assert_eq!(index.get("s3://b/my file"), Some("bbb"));
+        assert_eq!(index.get("s3://b/v2/app.tar"), None);
+        assert_eq!(
+            index.urls_by_md5sum()["aaa"],
+            ["s3://b/latest/app.tar", "s3://b/v1/app.tar"]
+        );
+
+        let mut index = index;
+        assert_eq!(index.retain(|md5sum| md5sum == "bbb"), 2);
+        assert_eq!(index.get("s3://b/v1/app.tar"), None);
+    }
+}
@@ -1,5 +1,68 @@
+mod alias;
+mod audit;
 mod cache;
+mod cancel;
+mod clock;
 mod configuration;
+mod doctor;
+mod failover;
+#[cfg(any(test, feature = "test-util"))]
+mod fake;
+mod fetch;
+mod hash;
+mod head_cache;
+mod http;
+mod inventory;
+mod key_index;
+mod local;
+mod manifest;
+mod metadata;
+mod metrics;
+#[cfg(feature = "otel")]
+mod otel;
+mod process;
+mod progress;
+mod quarantine;
+mod remote;
 mod s3;
+mod shared;
+mod ssh;
+mod stats;
+mod store;
+mod sync;
+mod template;
 
+pub use alias::{Alias, AliasError, Aliases};
+pub use cache::{Cache, CacheEntry, CacheError, EntryFilter, GcSummary};
+pub use cancel::CancelToken;
+pub use clock::{Clock, SystemClock};
+pub use configuration::{
+    check_config, parse_duration_as_secs, parse_size_as_bytes, Configuration,
+    ConfigurationError, LogFormat, LogTarget, Overrides, Setting,
+    SettingSource,
+};
+pub use doctor::{run_checks, Check, CheckStatus};
+pub use failover::{FailoverStore, Replica};
+#[cfg(feature = "test-util")]
+pub use fake::{FakeClock, MemoryStore};
+pub use hash::md5sum_file;
+pub use http::{HttpError, HttpFile};
+pub use inventory::{Inventory, InventoryError};
+pub use local::{LocalError, LocalFile};
+pub use manifest::{
+    format_manifest, list_manifest, parse_manifest, ManifestEntry,
+    ManifestError,
+};
+pub use metadata::EntryMetadata;
+pub use metrics::{cache_metrics, put_metrics, Metric, MetricsError};
+#[cfg(feature = "otel")]
+pub use otel::{init_otel, OtelError, OtelGuard};
+pub use process::{set_aws_limits, set_credentials_refresh_command, AwsLimits};
+pub use progress::Progress;
+pub use quarantine::QuarantinedEntry;
 pub use s3::*;
+pub use ssh::{SshError, SshFile};
+pub use stats::{Histogram, Stats, Transfers};
+pub use store::{AwsCli, ObjectStore};
+pub use sync::{sync_down, sync_down_listed, sync_up, SyncError, SyncSummary};
+pub use template::{TemplateError, Variables};
@@ -0,0 +1,364 @@
+use crate::cache::{Cache, CacheError};
+use crate::hash::md5sum_file;
+use crate::manifest::is_md5sum;
+use crate::s3::{DownloadOutcome, DownloadSummary};
+use filetime::FileTime;
+use log::{debug, warn};
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{io, process};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LocalError {
+    #[error(transparent)]
+    CacheError(#[from] CacheError),
+    #[error("checksum mismatch for {}", .0.display())]
+    ChecksumMismatch(PathBuf),
+    #[error("failed to copy {}", .0.display())]
+    CopyError(PathBuf, #[source] io::Error),
+    #[error("failed to hash {}", .0.display())]
+    HashError(PathBuf, #[source] io::Error),
+    #[error("failed to update the index of local files in {}", .0.display())]
+    IndexError(PathBuf, #[source] io::Error),
+    #[error("invalid file URL: {0}")]
+    InvalidUrl(String),
+    #[error("failed to read {}", .0.display())]
+    ReadError(PathBuf, #[source] io::Error),
+}
+
+/// Get a name for the temporary copy of a file whose md5sum isn't known
+/// yet, unique to this download
+fn unique_temp_name() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "local-{}-{}",
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A file on a local or network filesystem, such as a big NFS share,
+/// used as the source of an object
+///
+/// Files are cached by md5sum just like S3 objects. The md5sum is read
+/// from a `<path>.md5` sidecar file if there is one. Otherwise the
+/// file is hashed when it's first cached, and the md5sum is kept in an
+/// index in the cache directory until the file's size or modification
+/// time changes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalFile {
+    pub path: PathBuf,
+}
+
+/// A file's md5sum as of a given size and modification time
+struct IndexEntry {
+    md5sum: String,
+    size: u64,
+    mtime: i64,
+    path: String,
+}
+
+impl IndexEntry {
+    fn parse(line: &str) -> Option<IndexEntry> {
+        let mut parts = line.splitn(4, '\t');
+        Some(IndexEntry {
+            md5sum: parts.next()?.to_string(),
+            size: parts.next()?.parse().ok()?,
+            mtime: parts.next()?.parse().ok()?,
+            path: parts.next()?.to_string(),
+        })
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\n",
+            self.md5sum, self.size, self.mtime, self.path
+        )
+    }
+}
+
+fn index_path(cache: &Cache) -> PathBuf {
+    cache.root().join("local.index")
+}
+
+fn load_index(path: &Path) -> io::Result<Vec<IndexEntry>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            Ok(contents.lines().filter_map(IndexEntry::parse).collect())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+fn mtime(metadata: &Metadata) -> i64 {
+    FileTime::from_last_modification_time(metadata).unix_seconds()
+}
+
+impl LocalFile {
+    pub fn new(path: &Path) -> LocalFile {
+        LocalFile {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Parse a file:///<path> URL
+    pub fn from_url(url: &str) -> Result<LocalFile, LocalError> {
+        match url.strip_prefix("file://") {
+            Some(path) if path.starts_with('/') => {
+                Ok(LocalFile::new(Path::new(path)))
+            }
+            _ => Err(LocalError::InvalidUrl(url.to_string())),
+        }
+    }
+
+    pub fn to_url(&self) -> String {
+        format!("file://{}", self.path.display())
+    }
+
+    fn sidecar_md5sum(&self) -> Option<String> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".md5");
+        // The sidecar may be in md5sum's "<md5sum>  <name>" format
+        let contents = fs::read_to_string(path).ok()?;
+        let md5sum = contents.split_whitespace().next()?;
+        if is_md5sum(md5sum) {
+            Some(md5sum.to_ascii_lowercase())
+        } else {
+            None
+        }
+    }
+
+    /// Get the md5sum of the file without reading it, if it's known
+    fn known_md5sum(
+        &self,
+        cache: &Cache,
+        metadata: &Metadata,
+    ) -> Result<Option<String>, LocalError> {
+        if let Some(md5sum) = self.sidecar_md5sum() {
+            return Ok(Some(md5sum));
+        }
+        let path = index_path(cache);
+        let index = load_index(&path)
+            .map_err(|err| LocalError::IndexError(path, err))?;
+        Ok(index
+            .into_iter()
+            .find(|entry| {
+                Some(entry.path.as_str()) == self.path.to_str()
+                    && entry.size == metadata.len()
+                    && entry.mtime == mtime(metadata)
+            })
+            .map(|entry| entry.md5sum))
+    }
+
+    /// Remember the md5sum of the file as of its current size and
+    /// modification time
+    fn record_md5sum(
+        &self,
+        cache: &Cache,
+        md5sum: &str,
+        metadata: &Metadata,
+    ) -> Result<(), LocalError> {
+        // Non-UTF-8 paths are hashed every time instead
+        let file_path = match self.path.to_str() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let path = index_path(cache);
+        let index_error = |err| LocalError::IndexError(path.clone(), err);
+        let mut index = load_index(&path).map_err(index_error)?;
+        index.retain(|entry| entry.path != file_path);
+        index.push(IndexEntry {
+            md5sum: md5sum.to_string(),
+            size: metadata.len(),
+            mtime: mtime(metadata),
+            path: file_path.to_string(),
+        });
+        let contents: String = index.iter().map(IndexEntry::format).collect();
+        fs::write(&path, contents).map_err(index_error)
+    }
+
+    /// Copy the file into the cache, returning its md5sum
+    ///
+    /// The file is copied to a temporary file first and hashed there,
+    /// so that it's only read once from the (possibly slow) source.
+    fn download_into_cache(
+        &self,
+        cache: &Cache,
+        expected_md5sum: Option<&str>,
+        metadata: &Metadata,
+    ) -> Result<String, LocalError> {
+        // Locking the temporary name keeps gc from deleting the copy
+        let tmp_name = unique_temp_name();
+        let _tmp_lock = cache.lock_entry(&tmp_name)?;
+        let tmp_path = cache.temporary_path(&tmp_name);
+        fs::copy(&self.path, &tmp_path)
+            .map_err(|err| LocalError::CopyError(self.path.clone(), err))?;
+        let md5sum = md5sum_file(&tmp_path)
+            .map_err(|err| LocalError::HashError(tmp_path.clone(), err))?;
+        if expected_md5sum.is_some_and(|expected| expected != md5sum) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(LocalError::ChecksumMismatch(self.path.clone()));
+        }
+        let _lock = cache.lock_entry(&md5sum)?;
+        let dst_path = cache.temporary_path(&md5sum);
+        fs::rename(&tmp_path, &dst_path)
+            .map_err(|err| LocalError::CopyError(tmp_path, err))?;
+        cache.finish_temporary(&md5sum)?;
+        self.record_md5sum(cache, &md5sum, metadata)?;
+        Ok(md5sum)
+    }
+
+    /// Copy the file to `path` through the cache
+    pub fn download(
+        &self,
+        cache: &Cache,
+        path: &Path,
+    ) -> Result<DownloadSummary, LocalError> {
+        let metadata = fs::metadata(&self.path)
+            .map_err(|err| LocalError::ReadError(self.path.clone(), err))?;
+        cache.record_download(|| self.download_impl(cache, path, &metadata))
+    }
+
+    fn download_impl(
+        &self,
+        cache: &Cache,
+        path: &Path,
+        metadata: &Metadata,
+    ) -> Result<DownloadSummary, LocalError> {
+        let size = metadata.len();
+        let known_md5sum = self.known_md5sum(cache, metadata)?;
+
+        let (md5sum, outcome) = match known_md5sum {
+            Some(md5sum) if cache.contains(&md5sum) => {
+                debug!("cache hit for {} ({})", self.to_url(), md5sum);
+                if let Err(err) = cache.add_alias(&md5sum, &self.to_url()) {
+                    warn!("failed to record alias of {}: {}", md5sum, err);
+                }
+                cache.record_hit(size)?;
+                (md5sum, DownloadOutcome::CacheHit)
+            }
+            _ => {
+                if let Some(reason) = cache.admit(size)? {
+                    debug!(
+                        "bypassing cache for {} ({} bytes): {}",
+                        self.to_url(),
+                        size,
+                        reason
+                    );
+                    fs::copy(&self.path, path).map_err(|err| {
+                        LocalError::CopyError(self.path.clone(), err)
+                    })?;
+                    return Ok(DownloadSummary {
+                        outcome: DownloadOutcome::DirectBypass { reason },
+                        bytes: size,
+                    });
+                }
+                debug!("cache miss for {}, copying", self.to_url());
+                let md5sum = self.download_into_cache(
+                    cache,
+                    known_md5sum.as_deref(),
+                    metadata,
+                )?;
+                if let Err(err) = cache.set_source(&md5sum, &self.to_url()) {
+                    warn!("failed to record source of {}: {}", md5sum, err);
+                }
+                cache.record_miss(size)?;
+                (md5sum, DownloadOutcome::CachedThenCopied)
+            }
+        };
+        if let Err(err) = cache.record_access(&md5sum) {
+            warn!("failed to record access to {}: {}", md5sum, err);
+        }
+        cache.copy(&md5sum, path)?;
+        Ok(DownloadSummary {
+            outcome,
+            bytes: size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::test_configuration;
+    use std::thread;
+
+    #[test]
+    fn test_from_url() {
+        let file = LocalFile::from_url("file:///nfs/a.tar").unwrap();
+        assert_eq!(file.path, Path::new("/nfs/a.tar"));
+        assert_eq!(file.to_url(), "file:///nfs/a.tar");
+        assert!(LocalFile::from_url("file://nfs/a.tar").is_err());
+        assert!(LocalFile::from_url("/nfs/a.tar").is_err());
+    }
+
+    #[test]
+    fn test_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache =
+            Cache::open_with_configuration(test_configuration(dir.path()))
+                .unwrap();
+        let src_path = dir.path().join("src");
+        let dst_path = dir.path().join("dst");
+        fs::write(&src_path, "hello").unwrap();
+        let file = LocalFile::new(&src_path);
+
+        let summary = file.download(&cache, &dst_path).unwrap();
+        assert_eq!(summary.outcome, DownloadOutcome::CachedThenCopied);
+        assert_eq!(fs::read(&dst_path).unwrap(), b"hello");
+        assert!(cache.contains("5d41402abc4b2a76b9719d911017c592"));
+
+        // The md5sum is found in the index, so the source isn't read
+        let summary = file.download(&cache, &dst_path).unwrap();
+        assert_eq!(summary.outcome, DownloadOutcome::CacheHit);
+
+        // A wrong sidecar md5sum is caught when the file is cached
+        fs::write(dir.path().join("src.md5"), "0".repeat(32)).unwrap();
+        assert!(matches!(
+            file.download(&cache, &dst_path),
+            Err(LocalError::ChecksumMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_download_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache =
+            Cache::open_with_configuration(test_configuration(dir.path()))
+                .unwrap();
+        let names = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        for name in names {
+            fs::write(dir.path().join(name), name.repeat(8)).unwrap();
+        }
+        thread::scope(|scope| {
+            for name in names {
+                let cache = &cache;
+                let dir = dir.path();
+                scope.spawn(move || {
+                    let file = LocalFile::new(&dir.join(name));
+                    file.download(cache, &dir.join(format!("{}.dst", name)))
+                        .unwrap();
+                });
+            }
+        });
+        for name in names {
+            let path = dir.path().join(name);
+            let file = LocalFile::new(&path);
+            let metadata = fs::metadata(&path).unwrap();
+            let md5sum = md5sum_file(&path).unwrap();
+            assert!(cache.contains(&md5sum));
+            assert_eq!(
+                fs::read(dir.path().join(format!("{}.dst", name))).unwrap(),
+                name.repeat(8).as_bytes()
+            );
+            // Entries of the index may be lost to concurrent updates,
+            // but the ones that are there must be right
+            if let Some(known) = file.known_md5sum(&cache, &metadata).unwrap() {
+                assert_eq!(known, md5sum);
+            }
+        }
+    }
+}
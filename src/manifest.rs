@@ -0,0 +1,154 @@
+use crate::s3::{S3Error, S3Url};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ManifestError {
+    #[error("line {0}: invalid md5sum")]
+    InvalidMd5sum(usize),
+    #[error("line {0}: invalid size")]
+    InvalidSize(usize),
+    #[error("line {0}: invalid URL")]
+    InvalidUrl(usize),
+}
+
+/// An object listed in a manifest
+#[derive(Clone, Debug)]
+pub struct ManifestEntry {
+    pub url: S3Url,
+    /// md5sum the object is expected to have, if given
+    pub md5sum: Option<String>,
+    /// Size of the object in bytes, if given
+    pub size: Option<u64>,
+}
+
+pub(crate) fn is_md5sum(s: &str) -> bool {
+    s.len() == 32 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parse a manifest
+///
+/// Each line contains an object URL, optionally followed by whitespace
+/// and the object's md5sum, and then optionally its size in bytes.
+/// Empty lines and lines starting with '#' are ignored. Errors contain
+/// the (1-based) line number.
+pub fn parse_manifest(s: &str) -> Result<Vec<ManifestEntry>, ManifestError> {
+    let mut entries = Vec::new();
+    for (index, line) in s.lines().enumerate() {
+        let line_num = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let url = parts
+            .next()
+            .and_then(|url| url.parse::<S3Url>().ok())
+            .ok_or(ManifestError::InvalidUrl(line_num))?;
+        let md5sum = match parts.next() {
+            None => None,
+            Some(md5sum) if is_md5sum(md5sum) => {
+                Some(md5sum.to_ascii_lowercase())
+            }
+            Some(_) => return Err(ManifestError::InvalidMd5sum(line_num)),
+        };
+        let size = match (parts.next(), parts.next()) {
+            (None, _) => None,
+            (Some(size), None) => Some(
+                size.parse()
+                    .map_err(|_| ManifestError::InvalidSize(line_num))?,
+            ),
+            _ => return Err(ManifestError::InvalidSize(line_num)),
+        };
+        entries.push(ManifestEntry { url, md5sum, size });
+    }
+    Ok(entries)
+}
+
+/// Format entries as a manifest that `parse_manifest` can read
+///
+/// A size is only written for entries with an md5sum, since the
+/// columns are positional.
+pub fn format_manifest(entries: &[ManifestEntry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        out += &entry.url.to_string();
+        if let Some(md5sum) = &entry.md5sum {
+            out += &format!("  {}", md5sum);
+            if let Some(size) = entry.size {
+                out += &format!("  {}", size);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// List the objects under a prefix as manifest entries
+///
+/// The md5sum comes from the ETag where possible, and from the object
+/// metadata otherwise. Objects uploaded in multiple parts without an
+/// md5sum in their metadata are listed without one.
+pub fn list_manifest(prefix: &S3Url) -> Result<Vec<ManifestEntry>, S3Error> {
+    let mut entries = Vec::new();
+    for object in prefix.list()? {
+        let url = S3Url::new(prefix.bucket.clone(), object.key.clone());
+        let md5sum = match object.etag_md5sum() {
+            Some(md5sum) => Some(md5sum.to_string()),
+            None => url.md5sum()?,
+        };
+        entries.push(ManifestEntry {
+            url,
+            md5sum,
+            size: Some(object.size),
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5SUM: &str = "5d41402abc4b2a76b9719d911017c592";
+
+    #[test]
+    fn test_parse_manifest() {
+        let entries = parse_manifest(
+            "# comment\n\
+             s3://bucket/a\n\
+             \n\
+             s3://bucket/b  5D41402ABC4B2A76B9719D911017C592\n",
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].url.to_string(), "s3://bucket/a");
+        assert_eq!(entries[0].md5sum, None);
+        assert_eq!(entries[1].url.to_string(), "s3://bucket/b");
+        assert_eq!(entries[1].md5sum.as_deref(), Some(MD5SUM));
+
+        assert_eq!(
+            parse_manifest("s3://bucket/a\nbucket/b").unwrap_err(),
+            ManifestError::InvalidUrl(2)
+        );
+        assert_eq!(
+            parse_manifest("s3://bucket/a abc").unwrap_err(),
+            ManifestError::InvalidMd5sum(1)
+        );
+        assert_eq!(
+            parse_manifest(&format!("s3://bucket/a {} 1 2", MD5SUM))
+                .unwrap_err(),
+            ManifestError::InvalidSize(1)
+        );
+    }
+
+    #[test]
+    fn test_format_manifest() {
+        let s = format!(
+            "s3://bucket/a\ns3://bucket/b  {}\ns3://bucket/c  {}  5\n",
+            MD5SUM, MD5SUM
+        );
+        let entries = parse_manifest(&s).unwrap();
+        assert_eq!(entries[2].size, Some(5));
+        assert_eq!(format_manifest(&entries), s);
+    }
+}
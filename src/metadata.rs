@@ -0,0 +1,110 @@
+use crate::configuration::parse_config;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Extra information stored alongside a cached object
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntryMetadata {
+    /// URL the object was downloaded from
+    pub source: Option<String>,
+    /// Pinned objects are never evicted
+    pub pinned: bool,
+    /// Number of times the object was requested through the cache
+    pub accesses: u64,
+    /// Replica bucket and region the object was downloaded from,
+    /// because the source's bucket failed
+    pub served_by: Option<String>,
+    /// Symlinks and hardlinks to the object made by downloads. The
+    /// object isn't evicted while any of them still exists.
+    pub links: Vec<PathBuf>,
+}
+
+const ACCESSES: &str = "accesses";
+const LINK: &str = "link";
+const PINNED: &str = "pinned";
+const SERVED_BY: &str = "served_by";
+const SOURCE: &str = "source";
+
+impl EntryMetadata {
+    fn parse(s: &str) -> EntryMetadata {
+        let map = parse_config(s);
+        EntryMetadata {
+            source: map.get(SOURCE).map(|val| val.to_string()),
+            pinned: map.get(PINNED) == Some(&"true"),
+            accesses: map
+                .get(ACCESSES)
+                .and_then(|val| val.parse().ok())
+                .unwrap_or(0),
+            served_by: map.get(SERVED_BY).map(|val| val.to_string()),
+            // Unlike the other keys, there is a line for each link
+            links: s
+                .lines()
+                .filter_map(|line| {
+                    let (key, val) = line.split_once('=')?;
+                    (key.trim() == LINK).then(|| PathBuf::from(val.trim()))
+                })
+                .collect(),
+        }
+    }
+
+    fn format(&self) -> String {
+        let mut out = String::new();
+        if let Some(source) = &self.source {
+            out += &format!("{} = {}\n", SOURCE, source);
+        }
+        if self.pinned {
+            out += &format!("{} = true\n", PINNED);
+        }
+        if self.accesses > 0 {
+            out += &format!("{} = {}\n", ACCESSES, self.accesses);
+        }
+        if let Some(served_by) = &self.served_by {
+            out += &format!("{} = {}\n", SERVED_BY, served_by);
+        }
+        for link in &self.links {
+            out += &format!("{} = {}\n", LINK, link.display());
+        }
+        out
+    }
+
+    /// Read metadata from a file, or get empty metadata if it doesn't
+    /// exist
+    pub(crate) fn load(path: &Path) -> io::Result<EntryMetadata> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(EntryMetadata::parse(&contents)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(EntryMetadata::default())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.format())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.meta");
+        assert_eq!(
+            EntryMetadata::load(&path).unwrap(),
+            EntryMetadata::default()
+        );
+
+        let metadata = EntryMetadata {
+            source: Some("s3://bucket/key".to_string()),
+            pinned: true,
+            accesses: 3,
+            served_by: Some("bucket-eu (eu-west-1)".to_string()),
+            links: vec![PathBuf::from("/a/b"), PathBuf::from("/c")],
+        };
+        metadata.save(&path).unwrap();
+        assert_eq!(EntryMetadata::load(&path).unwrap(), metadata);
+    }
+}
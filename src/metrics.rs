@@ -0,0 +1,173 @@
+use crate::process::aws_output;
+use crate::stats::{Histogram, Stats};
+use log::error;
+use serde::Serialize;
+use std::io;
+use std::process::{Command, ExitStatus};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MetricsError {
+    #[error("failed to put metric data in namespace {0} ({1})")]
+    CommandFailed(String, ExitStatus),
+    #[error("failed to run the aws CLI")]
+    IoError(#[source] io::Error),
+    #[error("failed to serialize metric data")]
+    JsonError(#[source] serde_json::Error),
+}
+
+/// A CloudWatch data point, or a distribution of values
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Metric {
+    pub metric_name: &'static str,
+    /// The value of a single data point
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    /// The values of a distribution, each seen `counts` times
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub counts: Vec<f64>,
+    /// CloudWatch unit, such as "Count" or "Bytes"
+    pub unit: &'static str,
+}
+
+impl Metric {
+    fn new(
+        metric_name: &'static str,
+        value: f64,
+        unit: &'static str,
+    ) -> Metric {
+        Metric {
+            metric_name,
+            value: Some(value),
+            values: Vec::new(),
+            counts: Vec::new(),
+            unit,
+        }
+    }
+
+    /// Make a distribution with the midpoint of each of the histogram's
+    /// non-empty buckets as its values
+    fn histogram(
+        metric_name: &'static str,
+        histogram: &Histogram,
+        unit: &'static str,
+    ) -> Metric {
+        let (values, counts) = histogram
+            .buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(index, count)| {
+                let range = Histogram::bucket_range(index);
+                let midpoint =
+                    (*range.start() as f64 + *range.end() as f64) / 2.0;
+                (midpoint, *count as f64)
+            })
+            .unzip();
+        Metric {
+            metric_name,
+            value: None,
+            values,
+            counts,
+            unit,
+        }
+    }
+}
+
+/// Get the metrics describing a cache
+///
+/// The counters are totals since the stats were last reset, so use
+/// CloudWatch's RATE or DIFF functions to graph them over time. The
+/// download durations and sizes are also totals, published as
+/// distributions for each of hits, misses and direct downloads.
+pub fn cache_metrics(stats: &Stats, usage_bytes: u64) -> Vec<Metric> {
+    let mut metrics = vec![
+        Metric::new("Hits", stats.hits as f64, "Count"),
+        Metric::new("Misses", stats.misses as f64, "Count"),
+        Metric::new("HitRatio", stats.hit_ratio() * 100.0, "Percent"),
+        Metric::new("BytesFromCache", stats.hit_bytes as f64, "Bytes"),
+        Metric::new("BytesDownloaded", stats.miss_bytes as f64, "Bytes"),
+        Metric::new("Evictions", stats.evictions as f64, "Count"),
+        Metric::new("EvictedBytes", stats.evicted_bytes as f64, "Bytes"),
+        Metric::new("UsageBytes", usage_bytes as f64, "Bytes"),
+    ];
+    for (duration_name, size_name, transfers) in [
+        ("HitDuration", "HitSize", &stats.hit_transfers),
+        ("MissDuration", "MissSize", &stats.miss_transfers),
+        ("DirectDuration", "DirectSize", &stats.direct_transfers),
+    ] {
+        // CloudWatch rejects distributions without values
+        if transfers.size.count() == 0 {
+            continue;
+        }
+        metrics.push(Metric::histogram(
+            duration_name,
+            &transfers.duration_ms,
+            "Milliseconds",
+        ));
+        metrics.push(Metric::histogram(size_name, &transfers.size, "Bytes"));
+    }
+    metrics
+}
+
+/// Send metrics to CloudWatch
+pub fn put_metrics(
+    namespace: &str,
+    metrics: &[Metric],
+) -> Result<(), MetricsError> {
+    let metric_data =
+        serde_json::to_string(metrics).map_err(MetricsError::JsonError)?;
+    let output = aws_output(Command::new("aws").args([
+        "cloudwatch",
+        "put-metric-data",
+        "--namespace",
+        namespace,
+        "--metric-data",
+        &metric_data,
+    ]))
+    .map_err(MetricsError::IoError)?;
+    if !output.status.success() {
+        error!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        return Err(MetricsError::CommandFailed(
+            namespace.to_string(),
+            output.status,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_metrics() {
+        let mut stats = Stats {
+            hits: 3,
+            misses: 1,
+            evictions: 2,
+            ..Default::default()
+        };
+        stats.miss_transfers.duration_ms.record(3);
+        stats.miss_transfers.duration_ms.record(3);
+        stats.miss_transfers.size.record(0);
+        stats.miss_transfers.size.record(1);
+        let metrics = cache_metrics(&stats, 100);
+        let get = |name| {
+            metrics
+                .iter()
+                .find(|metric| metric.metric_name == name)
+                .unwrap()
+        };
+        assert_eq!(get("HitRatio").value, Some(75.0));
+        assert_eq!(get("Evictions").value, Some(2.0));
+        assert_eq!(get("UsageBytes").value, Some(100.0));
+        assert_eq!(get("MissDuration").values, [2.5]);
+        assert_eq!(get("MissDuration").counts, [2.0]);
+        assert_eq!(get("MissSize").values, [0.0, 1.0]);
+        assert!(metrics.iter().all(|metric| metric.metric_name != "HitSize"));
+    }
+}
@@ -0,0 +1,57 @@
+use log::warn;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use thiserror::Error;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+
+#[derive(Debug, Error)]
+pub enum OtelError {
+    #[error("failed to create the OTLP exporter")]
+    ExporterError(#[source] ExporterBuildError),
+    #[error("failed to install the tracing subscriber")]
+    SubscriberError(#[source] TryInitError),
+}
+
+/// Keeps spans being exported until dropped
+///
+/// Dropping the guard flushes any spans that haven't been sent yet.
+pub struct OtelGuard {
+    provider: SdkTracerProvider,
+}
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        if let Err(err) = self.provider.shutdown() {
+            warn!("failed to flush OpenTelemetry spans: {}", err);
+        }
+    }
+}
+
+/// Export the library's `tracing` spans to an OpenTelemetry collector
+///
+/// This installs a global `tracing` subscriber that sends spans over
+/// OTLP/HTTP, so it fails if the program already installed one. The
+/// collector is configured with the standard `OTEL_EXPORTER_OTLP_*`
+/// environment variables, such as `OTEL_EXPORTER_OTLP_ENDPOINT`.
+pub fn init_otel(service_name: &str) -> Result<OtelGuard, OtelError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(OtelError::ExporterError)?;
+    let resource = Resource::builder()
+        .with_service_name(service_name.to_string())
+        .build();
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    let tracer = provider.tracer("horst3");
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()
+        .map_err(OtelError::SubscriberError)?;
+    Ok(OtelGuard { provider })
+}
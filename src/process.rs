@@ -0,0 +1,315 @@
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits on the aws processes run by horst3
+///
+/// These apply to the whole process, so that many threads downloading
+/// at once queue for a slot instead of each starting its own aws
+/// process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AwsLimits {
+    /// Maximum number of aws processes running at once
+    pub max_processes: usize,
+    /// Kill aws processes that run for longer than this
+    pub timeout: Option<Duration>,
+}
+
+impl Default for AwsLimits {
+    fn default() -> AwsLimits {
+        AwsLimits {
+            max_processes: 16,
+            timeout: None,
+        }
+    }
+}
+
+struct Pool {
+    limits: Option<AwsLimits>,
+    running: usize,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    limits: None,
+    running: 0,
+});
+static SLOT_FREED: Condvar = Condvar::new();
+
+/// Set the limits on aws processes
+///
+/// Processes that are already running are not affected.
+pub fn set_aws_limits(limits: AwsLimits) {
+    POOL.lock().unwrap().limits = Some(limits);
+    SLOT_FREED.notify_all();
+}
+
+fn limits() -> AwsLimits {
+    POOL.lock().unwrap().limits.unwrap_or_default()
+}
+
+/// Shell command that refreshes expired AWS credentials, and when it
+/// last ran
+struct CredentialsRefresh {
+    command: Option<String>,
+    last_run: Option<Instant>,
+}
+
+static CREDENTIALS_REFRESH: Mutex<CredentialsRefresh> =
+    Mutex::new(CredentialsRefresh {
+        command: None,
+        last_run: None,
+    });
+
+/// Credentials refreshed this recently are assumed to still be fresh,
+/// so that many threads finding expired credentials at once only
+/// refresh them once
+const CREDENTIALS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Set the shell command that refreshes expired AWS credentials, such
+/// as "aws sso login"
+pub fn set_credentials_refresh_command(command: Option<String>) {
+    CREDENTIALS_REFRESH.lock().unwrap().command = command;
+}
+
+/// Run the credentials refresh command
+///
+/// Returns false if there is no refresh command, in which case the
+/// request that found expired credentials should fail.
+pub(crate) fn refresh_credentials() -> io::Result<bool> {
+    let mut refresh = CREDENTIALS_REFRESH.lock().unwrap();
+    let command = match &refresh.command {
+        Some(command) => command,
+        None => return Ok(false),
+    };
+    if refresh.last_run.is_some_and(|last_run| {
+        last_run.elapsed() < CREDENTIALS_REFRESH_INTERVAL
+    }) {
+        return Ok(true);
+    }
+    info!("credentials expired, running {:?}", command);
+    // Keep stdout clean for commands like horst3 cat that write
+    // objects to it
+    let status = shell_command(command)
+        .stdout(Stdio::from(io::stderr()))
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "\"{}\" failed ({})",
+            command, status
+        )));
+    }
+    refresh.last_run = Some(Instant::now());
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+/// Regions of buckets that aren't in the default region
+static BUCKET_REGIONS: Mutex<BTreeMap<String, String>> =
+    Mutex::new(BTreeMap::new());
+
+/// Set the region passed to aws commands for a bucket's objects
+pub(crate) fn set_bucket_region(bucket: &str, region: &str) {
+    BUCKET_REGIONS
+        .lock()
+        .unwrap()
+        .insert(bucket.to_string(), region.to_string());
+}
+
+/// Make an aws command for a bucket, with its region if it has one
+pub(crate) fn aws_command(bucket: &str) -> Command {
+    let mut command = Command::new("aws");
+    if let Some(region) = BUCKET_REGIONS.lock().unwrap().get(bucket) {
+        command.args(["--region", region]);
+    }
+    command
+}
+
+/// A place in the pool, given back when dropped
+struct Slot;
+
+impl Slot {
+    /// Wait until fewer than the maximum number of processes are
+    /// running
+    fn acquire() -> Slot {
+        let mut pool = POOL.lock().unwrap();
+        let max_processes =
+            |pool: &Pool| pool.limits.unwrap_or_default().max_processes.max(1);
+        if pool.running >= max_processes(&pool) {
+            debug!("waiting for one of {} aws processes to exit", pool.running);
+        }
+        while pool.running >= max_processes(&pool) {
+            pool = SLOT_FREED.wait(pool).unwrap();
+        }
+        pool.running += 1;
+        Slot
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        POOL.lock().unwrap().running -= 1;
+        SLOT_FREED.notify_one();
+    }
+}
+
+/// A running aws process
+///
+/// The process is killed if it runs past the timeout, in which case
+/// waiting for it fails with `io::ErrorKind::TimedOut`.
+pub(crate) struct AwsChild {
+    child: Arc<Mutex<Child>>,
+    /// Stdout, if it was piped
+    pub stdout: Option<ChildStdout>,
+    timed_out: Arc<AtomicBool>,
+    /// Dropped to stop the watchdog thread
+    _exited: Option<Sender<()>>,
+    _slot: Slot,
+}
+
+impl AwsChild {
+    /// Start an aws command once there is a slot for it
+    pub fn spawn(command: &mut Command) -> io::Result<AwsChild> {
+        let slot = Slot::acquire();
+        let mut child = command.spawn()?;
+        let stdout = child.stdout.take();
+        let child = Arc::new(Mutex::new(child));
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let exited = limits().timeout.map(|timeout| {
+            let (sender, receiver) = mpsc::channel::<()>();
+            let child = child.clone();
+            let timed_out = timed_out.clone();
+            thread::spawn(move || {
+                if receiver.recv_timeout(timeout)
+                    == Err(RecvTimeoutError::Timeout)
+                {
+                    timed_out.store(true, Ordering::SeqCst);
+                    let _ = child.lock().unwrap().kill();
+                }
+            });
+            sender
+        });
+        Ok(AwsChild {
+            child,
+            stdout,
+            timed_out,
+            _exited: exited,
+            _slot: slot,
+        })
+    }
+
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.child.lock().unwrap().kill()
+    }
+
+    /// Wait for the process to exit
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        // Poll rather than block while holding the lock, so that the
+        // watchdog can still kill the process
+        let status = loop {
+            if let Some(status) = self.child.lock().unwrap().try_wait()? {
+                break status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        if self.timed_out.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "aws process timed out",
+            ));
+        }
+        Ok(status)
+    }
+
+    /// Wait for the process to exit, collecting its stdout and stderr
+    fn wait_with_output(&mut self) -> io::Result<Output> {
+        let stderr = self.child.lock().unwrap().stderr.take();
+        let stderr_reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut stderr) = stderr {
+                stderr.read_to_end(&mut buf)?;
+            }
+            Ok::<_, io::Error>(buf)
+        });
+        let mut stdout = Vec::new();
+        if let Some(out) = self.stdout.as_mut() {
+            out.read_to_end(&mut stdout)?;
+        }
+        let stderr = stderr_reader.join().expect("stderr reader panicked")?;
+        let status = self.wait()?;
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Run an aws command to completion, capturing its output
+pub(crate) fn aws_output(command: &mut Command) -> io::Result<Output> {
+    AwsChild::spawn(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?
+        .wait_with_output()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Restores the limits when dropped, even if the test fails
+    #[cfg(unix)]
+    struct RestoreLimits(Option<AwsLimits>);
+
+    #[cfg(unix)]
+    impl Drop for RestoreLimits {
+        fn drop(&mut self) {
+            POOL.lock().unwrap().limits = self.0;
+            SLOT_FREED.notify_all();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_limits() {
+        // Only this test changes the limits. The other tests use fake
+        // object stores, so they don't wait for slots.
+        let _restore = RestoreLimits(POOL.lock().unwrap().limits);
+        set_aws_limits(AwsLimits {
+            max_processes: 1,
+            timeout: Some(Duration::from_millis(100)),
+        });
+        let slot = Slot::acquire();
+        let (sender, receiver) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            let _slot = Slot::acquire();
+            sender.send(()).unwrap();
+        });
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(100)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        drop(slot);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        waiter.join().unwrap();
+
+        let err = aws_output(Command::new("sleep").arg("10")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,11 @@
+/// Progress of a transfer, passed to progress callbacks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    /// Bytes transferred so far
+    pub transferred: u64,
+    /// Total size of the object, if known
+    pub total: Option<u64>,
+    /// Whether the bytes are being copied out of the local cache rather
+    /// than downloaded
+    pub from_cache: bool,
+}
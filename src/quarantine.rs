@@ -0,0 +1,113 @@
+use crate::configuration::parse_config;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Subdirectory of the cache directory that corrupt entries are moved
+/// to
+pub(crate) const QUARANTINE_DIR: &str = "quarantine";
+
+const REPORT_EXTENSION: &str = "report";
+
+const ACTUAL_MD5SUM: &str = "actual_md5sum";
+const MD5SUM: &str = "md5sum";
+const REASON: &str = "reason";
+const SIZE: &str = "size";
+const SOURCE: &str = "source";
+const TIME: &str = "time";
+
+/// A corrupt cache entry kept for investigation
+///
+/// The entry's file is kept in the quarantine directory along with a
+/// `.report` file holding the rest of these fields.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QuarantinedEntry {
+    /// The md5sum the entry was stored under
+    pub md5sum: String,
+    /// The md5sum of the entry's contents
+    pub actual_md5sum: String,
+    /// Where the entry's file is now
+    pub path: PathBuf,
+    pub size: u64,
+    /// URL the object was downloaded from, if known
+    pub source: Option<String>,
+    /// When the entry was quarantined, in seconds since the Unix epoch
+    pub time: u64,
+    /// What found the corruption
+    pub reason: String,
+}
+
+impl QuarantinedEntry {
+    pub(crate) fn report_path(path: &Path) -> PathBuf {
+        path.with_extension(REPORT_EXTENSION)
+    }
+
+    /// Read the report of a quarantined file
+    pub(crate) fn load(path: &Path) -> io::Result<QuarantinedEntry> {
+        let contents = fs::read_to_string(Self::report_path(path))?;
+        let map = parse_config(&contents);
+        let get = |key| map.get(key).map(|val| val.to_string());
+        Ok(QuarantinedEntry {
+            md5sum: get(MD5SUM).unwrap_or_default(),
+            actual_md5sum: get(ACTUAL_MD5SUM).unwrap_or_default(),
+            path: path.to_path_buf(),
+            size: get(SIZE).and_then(|val| val.parse().ok()).unwrap_or(0),
+            source: get(SOURCE),
+            time: get(TIME).and_then(|val| val.parse().ok()).unwrap_or(0),
+            reason: get(REASON).unwrap_or_default(),
+        })
+    }
+
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let mut out = format!(
+            "{} = {}\n{} = {}\n{} = {}\n{} = {}\n{} = {}\n",
+            MD5SUM,
+            self.md5sum,
+            ACTUAL_MD5SUM,
+            self.actual_md5sum,
+            SIZE,
+            self.size,
+            TIME,
+            self.time,
+            REASON,
+            self.reason
+        );
+        if let Some(source) = &self.source {
+            out += &format!("{} = {}\n", SOURCE, source);
+        }
+        fs::write(Self::report_path(&self.path), out)
+    }
+
+    /// Check if a file in the quarantine directory is a quarantined
+    /// entry rather than a report
+    pub(crate) fn is_entry_path(path: &Path) -> bool {
+        path.extension().is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quarantined_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("5d41402abc4b2a76b9719d911017c592-100");
+        let entry = QuarantinedEntry {
+            md5sum: "5d41402abc4b2a76b9719d911017c592".to_string(),
+            actual_md5sum: "a1f3b2c1c1c5cb4b3f2d7b3b1c8d6a5e".to_string(),
+            path: path.clone(),
+            size: 5,
+            source: Some("s3://bucket/key".to_string()),
+            time: 100,
+            reason: "cache verify".to_string(),
+        };
+        entry.save().unwrap();
+        assert!(QuarantinedEntry::report_path(&path).exists());
+        assert!(QuarantinedEntry::is_entry_path(&path));
+        assert!(!QuarantinedEntry::is_entry_path(
+            &QuarantinedEntry::report_path(&path)
+        ));
+        assert_eq!(QuarantinedEntry::load(&path).unwrap(), entry);
+    }
+}
@@ -0,0 +1,237 @@
+use log::{debug, warn};
+use std::io;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// curl's exit code for HTTP errors such as 404 with --fail
+const CURL_HTTP_ERROR: i32 = 22;
+
+/// How long to skip the server once too many requests in a row failed
+/// to reach it
+const SKIP_DURATION: Duration = Duration::from_secs(60);
+
+/// How long `check_reachable` waits if the policy has no timeout
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    #[error("failed to download {0} ({1})")]
+    CommandFailed(String, ExitStatus),
+    #[error("failed to run curl")]
+    IoError(#[source] io::Error),
+    #[error("skipping {0} after {1} failed requests in a row")]
+    Skipped(String, usize),
+}
+
+impl RemoteError {
+    /// Check if the server couldn't be reached, as opposed to it not
+    /// having the object
+    fn is_unreachable(&self) -> bool {
+        match self {
+            RemoteError::CommandFailed(_, status) => {
+                status.code() != Some(CURL_HTTP_ERROR)
+            }
+            RemoteError::IoError(_) | RemoteError::Skipped(..) => false,
+        }
+    }
+}
+
+/// When to give up on the remote cache server and go to S3 instead
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RemotePolicy {
+    /// Number of times to retry a request that failed to reach the
+    /// server
+    pub retries: usize,
+    /// Give up on a request if it can't connect or receives no data
+    /// for this long
+    pub timeout: Option<Duration>,
+    /// Skip the server for a minute after this many requests in a row
+    /// failed to reach it. 0 means never.
+    pub max_failures: usize,
+}
+
+/// Requests in a row that failed to reach the server
+#[derive(Debug, Default)]
+struct Failures {
+    count: usize,
+    skip_until: Option<Instant>,
+}
+
+/// Second-level cache server on the LAN
+///
+/// Objects are requested by md5sum with a plain GET of <url>/<md5sum>.
+pub struct RemoteCache {
+    url: String,
+    policy: RemotePolicy,
+    failures: Mutex<Failures>,
+}
+
+impl RemoteCache {
+    pub fn new(url: &str, policy: RemotePolicy) -> RemoteCache {
+        RemoteCache {
+            url: url.trim_end_matches('/').to_string(),
+            policy,
+            failures: Mutex::new(Failures::default()),
+        }
+    }
+
+    /// Get the URL of an object on the server
+    pub fn object_url(&self, md5sum: &str) -> String {
+        format!("{}/{}", self.url, md5sum)
+    }
+
+    /// Download an object from the server
+    ///
+    /// Requests that fail to reach the server are retried according
+    /// to the policy. Once too many in a row have failed, requests fail
+    /// straight away with `RemoteError::Skipped` for a while, so that a
+    /// broken server doesn't slow down every download.
+    pub fn download(
+        &self,
+        md5sum: &str,
+        path: &Path,
+    ) -> Result<(), RemoteError> {
+        self.check_skipped()?;
+        let mut result = self.try_download(md5sum, path);
+        for attempt in 1..=self.policy.retries {
+            match &result {
+                Err(err) if err.is_unreachable() => debug!(
+                    "retrying {} ({} of {}): {}",
+                    self.object_url(md5sum),
+                    attempt,
+                    self.policy.retries,
+                    err
+                ),
+                _ => break,
+            }
+            result = self.try_download(md5sum, path);
+        }
+        self.record(&result);
+        result
+    }
+
+    fn try_download(
+        &self,
+        md5sum: &str,
+        path: &Path,
+    ) -> Result<(), RemoteError> {
+        let url = self.object_url(md5sum);
+        let mut command = Command::new("curl");
+        command.args(["--fail", "--silent", "--show-error", "--location"]);
+        if let Some(timeout) = self.policy.timeout {
+            let secs = timeout.as_secs().max(1).to_string();
+            // Abort if the transfer stalls rather than capping its
+            // total time, which large objects legitimately need
+            command.args(["--connect-timeout", &secs]).args([
+                "--speed-limit",
+                "1",
+                "--speed-time",
+                &secs,
+            ]);
+        }
+        let status = command
+            .arg("--output")
+            .arg(path)
+            .arg(&url)
+            .status()
+            .map_err(RemoteError::IoError)?;
+        if !status.success() {
+            return Err(RemoteError::CommandFailed(url, status));
+        }
+        Ok(())
+    }
+
+    /// Check that the server responds to a HEAD request of its URL
+    ///
+    /// Any HTTP response counts, even an error status.
+    pub(crate) fn check_reachable(&self) -> Result<(), RemoteError> {
+        let secs = self.policy.timeout.unwrap_or(CHECK_TIMEOUT).as_secs();
+        let status = Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--head"])
+            .args(["--max-time", &secs.max(1).to_string()])
+            .arg(format!("{}/", self.url))
+            .stdout(Stdio::null())
+            .status()
+            .map_err(RemoteError::IoError)?;
+        if status.success() || status.code() == Some(CURL_HTTP_ERROR) {
+            Ok(())
+        } else {
+            Err(RemoteError::CommandFailed(self.url.clone(), status))
+        }
+    }
+
+    fn check_skipped(&self) -> Result<(), RemoteError> {
+        let failures = self.failures.lock().unwrap();
+        match failures.skip_until {
+            Some(until) if Instant::now() < until => {
+                Err(RemoteError::Skipped(self.url.clone(), failures.count))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Keep track of requests in a row that failed to reach the server
+    fn record(&self, result: &Result<(), RemoteError>) {
+        let mut failures = self.failures.lock().unwrap();
+        match result {
+            Err(err) if err.is_unreachable() => {
+                failures.count += 1;
+                let max_failures = self.policy.max_failures;
+                if max_failures > 0 && failures.count >= max_failures {
+                    warn!(
+                        "{} failed {} requests in a row, skipping it for {}s",
+                        self.url,
+                        failures.count,
+                        SKIP_DURATION.as_secs()
+                    );
+                    failures.skip_until = Some(Instant::now() + SKIP_DURATION);
+                }
+            }
+            Err(RemoteError::IoError(_)) => {}
+            _ => *failures = Failures::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_url() {
+        let remote =
+            RemoteCache::new("http://cache.lan:8080/", RemotePolicy::default());
+        assert_eq!(remote.object_url("abc"), "http://cache.lan:8080/abc");
+        let remote =
+            RemoteCache::new("http://cache.lan:8080", RemotePolicy::default());
+        assert_eq!(remote.object_url("abc"), "http://cache.lan:8080/abc");
+    }
+
+    #[test]
+    fn test_skip_unreachable_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dst");
+        // Nothing listens on port 1, so connections are refused
+        let remote = RemoteCache::new(
+            "http://127.0.0.1:1",
+            RemotePolicy {
+                retries: 1,
+                timeout: Some(Duration::from_secs(5)),
+                max_failures: 2,
+            },
+        );
+        for _ in 0..2 {
+            assert!(matches!(
+                remote.download("abc", &path),
+                Err(RemoteError::CommandFailed(..))
+            ));
+        }
+        assert!(matches!(
+            remote.download("abc", &path),
+            Err(RemoteError::Skipped(_, 2))
+        ));
+    }
+}
@@ -1,37 +1,667 @@
 use crate::cache::{Cache, CacheError};
-use log::error;
-use serde::Deserialize;
-use std::path::Path;
-use std::process::{Command, ExitStatus};
-use std::{fs, io};
+use crate::cancel::CancelToken;
+use crate::hash::{
+    md5sum_file, parse_md5sum_file, HashingWriter, SIDECAR_EXTENSION,
+};
+use crate::process::{aws_command, aws_output, refresh_credentials, AwsChild};
+use crate::progress::Progress;
+use crate::remote::RemoteError;
+use crate::store::{AwsCli, ObjectStore};
+use filetime::FileTime;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use thiserror::Error;
 
+/// User-defined metadata of an object
+#[derive(Clone, Debug, Deserialize)]
+pub struct HeadObjectMetadata {
+    /// Set by `S3Url::upload`; objects without it can't be cached
+    pub md5sum: Option<String>,
+}
+
+/// An object's metadata, as returned by head-object
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HeadObject {
+    pub last_modified: String,
+    pub content_length: u64,
+    #[serde(rename = "ETag")]
+    pub etag: String,
+    /// Not set for objects in the STANDARD storage class
+    pub storage_class: Option<String>,
+    pub metadata: HeadObjectMetadata,
+}
+
+/// What's known about an object without downloading it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ObjectMetadata {
+    /// Size in bytes
+    pub size: u64,
+    pub last_modified: String,
+    pub etag: String,
+    pub storage_class: String,
+    /// Set by `S3Url::upload`; objects without it can't be cached
+    pub md5sum: Option<String>,
+}
+
+impl From<HeadObject> for ObjectMetadata {
+    fn from(head: HeadObject) -> ObjectMetadata {
+        ObjectMetadata {
+            size: head.content_length,
+            last_modified: head.last_modified,
+            etag: head.etag,
+            // head-object leaves out the default storage class
+            storage_class: head
+                .storage_class
+                .unwrap_or_else(|| "STANDARD".to_string()),
+            md5sum: head.metadata.md5sum,
+        }
+    }
+}
+
+/// The headers of an object that copying it onto itself has to keep
 #[derive(Debug, Deserialize)]
-struct HeadObjectMetadata {
-    md5sum: Option<String>,
+#[serde(rename_all = "PascalCase")]
+struct CopiedHeaders {
+    #[serde(rename = "ETag")]
+    etag: String,
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    content_encoding: Option<String>,
+    content_type: Option<String>,
+    server_side_encryption: Option<String>,
+    #[serde(rename = "SSEKMSKeyId")]
+    sse_kms_key_id: Option<String>,
+    storage_class: Option<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+/// An object returned by listing a prefix
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ObjectSummary {
+    pub key: String,
+    pub last_modified: String,
+    #[serde(rename = "ETag")]
+    pub etag: String,
+    pub size: u64,
+}
+
+impl ObjectSummary {
+    /// Get the object's md5sum from its ETag
+    ///
+    /// This is only possible for objects that were not uploaded in
+    /// multiple parts; the ETag of a multipart upload contains a '-'.
+    pub fn etag_md5sum(&self) -> Option<&str> {
+        etag_md5sum(&self.etag)
+    }
+}
+
+fn etag_md5sum(etag: &str) -> Option<&str> {
+    let etag = etag.trim_matches('"');
+    if etag.contains('-') {
+        None
+    } else {
+        Some(etag)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
-struct HeadObject {
-    last_modified: String,
-    content_length: u64,
-    metadata: HeadObjectMetadata,
+struct ListObjects {
+    #[serde(default)]
+    contents: Vec<ObjectSummary>,
+}
+
+/// How `S3Url::latest` picks the newest of several objects
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LatestBy {
+    /// The most recently modified object
+    #[default]
+    LastModified,
+    /// The object whose key sorts last, e.g. for date-stamped keys
+    Key,
 }
 
-#[derive(Debug)]
+/// What `S3Url::prefetch` did
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prefetch {
+    /// The object was already in the cache
+    AlreadyCached,
+    /// The object was downloaded into the cache
+    Fetched(u64),
+    /// The object can't be cached, either because it has no md5sum or
+    /// because of its size
+    NotCacheable,
+}
+
+/// What `S3Url::backfill_md5sum` did
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backfill {
+    /// The object already had an md5sum
+    AlreadySet,
+    /// The md5sum was taken from the object's ETag
+    FromEtag(String),
+    /// The object was downloaded to compute its md5sum
+    Downloaded(String),
+}
+
+/// Why a download bypassed the cache
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BypassReason {
+    /// The object has no md5sum to look it up by
+    NoMd5sum,
+    /// `DownloadOptions::bypass_cache` was set
+    Requested,
+    /// The object doesn't fit in the cache
+    TooLarge,
+    /// The object is smaller than `min_cached_size`
+    BelowMinCachedSize,
+    /// The object is larger than `max_cached_size`
+    AboveMaxCachedSize,
+}
+
+impl fmt::Display for BypassReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            BypassReason::NoMd5sum => "no md5sum metadata",
+            BypassReason::Requested => "requested",
+            BypassReason::TooLarge => "doesn't fit",
+            BypassReason::BelowMinCachedSize => "smaller than min_cached_size",
+            BypassReason::AboveMaxCachedSize => "larger than max_cached_size",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// How a download through the cache got the object's contents
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadOutcome {
+    /// The object was already in the cache
+    CacheHit,
+    /// The object was downloaded into the cache and copied from there
+    CachedThenCopied,
+    /// The object was downloaded directly without being cached
+    DirectBypass { reason: BypassReason },
+    /// The destination already had the object's contents, so nothing
+    /// was downloaded (see `Overwrite::SkipUnchanged`)
+    AlreadyPresent,
+}
+
+/// Whether a download through the cache would be served from it, see
+/// `S3Url::cache_status`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStatus {
+    /// The object is in the cache
+    Hit,
+    /// The object would be downloaded into the cache
+    Miss,
+    /// The object would be downloaded without being cached
+    Uncacheable { reason: BypassReason },
+}
+
+/// What to do if the destination of a download already exists
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overwrite {
+    /// Replace the existing file. A symlink is replaced rather than
+    /// written through.
+    #[default]
+    Always,
+    /// Fail with `S3Error::DestinationExists`
+    Never,
+    /// Leave the file alone if its md5sum matches the object's, and
+    /// replace it otherwise
+    SkipUnchanged,
+    /// Keep the existing file as "<path>.bak", replacing any earlier
+    /// backup, then replace it
+    Backup,
+}
+
+/// How a download puts a cached object at its destination
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputMode {
+    /// Copy the cache entry
+    #[default]
+    Copy,
+    /// Make the destination a symlink to the cache entry (Unix only)
+    Symlink,
+    /// Make the destination a hardlink to the cache entry, or copy it
+    /// if they're on different filesystems
+    Hardlink,
+}
+
+/// Per-download settings for `S3Url::download_with_options`
+#[derive(Default)]
+pub struct DownloadOptions<'a> {
+    /// Download directly, without looking in or adding to the cache
+    pub bypass_cache: bool,
+    /// Download the object again even if it is already cached, and ask
+    /// S3 for its metadata even if a recent result is cached
+    pub force_refresh: bool,
+    /// Check the destination against the object's md5sum after
+    /// copying. If a cached copy turns out to be corrupt, it is
+    /// moved to the cache's quarantine directory.
+    pub verify: bool,
+    pub overwrite: Overwrite,
+    pub progress: Option<&'a mut dyn FnMut(Progress)>,
+    /// Stop early with `S3Error::Cancelled` when triggered
+    pub cancel: Option<&'a CancelToken>,
+    /// Set the destination's modification time to the object's
+    pub preserve_mtime: bool,
+    /// Permissions to give the destination, e.g. 0o755. Ignored on
+    /// platforms other than Unix.
+    pub mode: Option<u32>,
+    /// Write directly to the destination instead of to
+    /// "<path>.partial", which is renamed once the download is
+    /// complete
+    pub write_in_place: bool,
+    /// Link to the cache entry instead of copying it. Linked entries
+    /// aren't evicted while the link exists, so the destination should
+    /// be treated as read-only. `preserve_mtime` and `mode` don't apply
+    /// to links.
+    pub output: OutputMode,
+}
+
+/// Per-upload settings for `S3Url::upload_with_options`
+#[derive(Default)]
+pub struct UploadOptions<'a> {
+    /// Also add the uploaded file to this cache, so that downloading
+    /// the object later doesn't fetch it again
+    pub write_through: Option<&'a Cache>,
+    /// Don't upload the file if the object already exists with the
+    /// same md5sum in its metadata
+    pub skip_if_exists: bool,
+}
+
+/// What an upload did
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UploadSummary {
+    pub md5sum: String,
+    /// Whether the file was added to the cache
+    pub cached: bool,
+    /// Whether the upload was skipped because the object already had
+    /// the same contents
+    pub skipped: bool,
+}
+
+/// What a download through the cache did
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct DownloadSummary {
+    pub outcome: DownloadOutcome,
+    /// Size of the object in bytes
+    pub bytes: u64,
+}
+
+impl DownloadSummary {
+    /// Whether the bytes were copied out of the cache without being
+    /// downloaded
+    pub fn from_cache(&self) -> bool {
+        self.outcome == DownloadOutcome::CacheHit
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct S3Url {
     pub bucket: String,
     pub key: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum S3Error {
-    CacheError(CacheError),
-    CommandFailed(ExitStatus),
-    IoError(io::Error),
-    JsonError(serde_json::Error),
-    MoveError(io::Error),
+    /// The credentials are missing or don't grant access
+    #[error("access denied: {0}")]
+    AccessDenied(String),
+    #[error("cache error")]
+    CacheError(#[from] CacheError),
+    /// The credentials have expired, e.g. an `aws sso login` session
+    #[error("AWS credentials have expired: {0}")]
+    CredentialsExpired(String),
+    /// `credentials_refresh_command` failed
+    #[error("failed to refresh AWS credentials")]
+    CredentialsRefreshFailed(#[source] io::Error),
+    /// The transfer was stopped with a `CancelToken`
+    #[error("transfer cancelled")]
+    Cancelled,
+    /// Downloaded data doesn't match the expected md5sum
+    #[error("downloaded data doesn't match md5sum {0}")]
+    ChecksumMismatch(String),
+    /// An aws command (such as "s3 cp") failed for a URL
+    #[error("aws {0} failed for {1} ({2})")]
+    CommandFailed(String, String, ExitStatus),
+    #[error("failed to hash {}", .0.display())]
+    HashError(PathBuf, #[source] io::Error),
+    /// `Overwrite::Never` was requested but the destination exists
+    #[error("{} already exists", .0.display())]
+    DestinationExists(PathBuf),
+    #[error("failed to back up {}", .0.display())]
+    BackupError(PathBuf, #[source] io::Error),
+    /// An object's last-modified time couldn't be parsed
+    #[error("invalid timestamp \"{0}\"")]
+    InvalidTimestamp(String),
+    #[error("invalid S3 URL \"{0}\"")]
+    InvalidUrl(String),
+    #[error("failed to run aws")]
+    IoError(#[source] io::Error),
+    #[error("failed to parse aws output")]
+    JsonError(#[source] serde_json::Error),
+    #[error("failed to move download to {}", .0.display())]
+    MoveError(PathBuf, #[source] io::Error),
+    /// S3 couldn't be reached
+    #[error("network error: {0}")]
+    NetworkError(String),
+    /// A local path can't be used in an object key because it isn't
+    /// valid UTF-8
+    #[error("path is not valid UTF-8")]
     NonUtf8Path,
+    /// The object or bucket doesn't exist
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("no remote cache configured")]
+    NotInRemoteCache,
+    /// The cache is in offline mode and the object isn't cached
+    #[error("{0} isn't cached and horst3 is offline")]
+    Offline(String),
+    #[error("remote cache error")]
+    RemoteError(#[from] RemoteError),
+    #[error("failed to write download")]
+    WriteError(#[source] io::Error),
+}
+
+/// Turn a failed aws command into an error
+///
+/// The aws CLI doesn't use distinct exit codes for common failures, so
+/// they are recognized by the error message instead.
+fn command_error(
+    command: &str,
+    url: &S3Url,
+    status: ExitStatus,
+    stderr: &[u8],
+) -> S3Error {
+    let stderr = String::from_utf8_lossy(stderr);
+    let contains_any = |patterns: &[&str]| {
+        patterns.iter().any(|pattern| stderr.contains(pattern))
+    };
+    if contains_any(&["(404)", "NoSuchKey", "NoSuchBucket"]) {
+        S3Error::NotFound(url.to_string())
+    } else if contains_any(&[
+        "ExpiredToken",
+        "Token has expired",
+        "session associated with this profile has expired",
+    ]) {
+        S3Error::CredentialsExpired(stderr.trim().to_string())
+    } else if contains_any(&[
+        "(403)",
+        "AccessDenied",
+        "InvalidAccessKeyId",
+        "SignatureDoesNotMatch",
+        "Unable to locate credentials",
+    ]) {
+        S3Error::AccessDenied(stderr.trim().to_string())
+    } else if contains_any(&[
+        "Could not connect to the endpoint URL",
+        "Connect timeout",
+        "Read timeout",
+    ]) {
+        S3Error::NetworkError(stderr.trim().to_string())
+    } else {
+        error!("{}", stderr.trim());
+        S3Error::CommandFailed(command.to_string(), url.to_string(), status)
+    }
+}
+
+/// Run an aws command for a URL, returning its stdout
+///
+/// If the credentials have expired and a refresh command is set with
+/// `set_credentials_refresh_command`, the credentials are refreshed and
+/// the command is run once more.
+pub(crate) fn run_aws<S: AsRef<OsStr>>(
+    command: &str,
+    url: &S3Url,
+    args: &[S],
+) -> Result<Vec<u8>, S3Error> {
+    let run = || {
+        let output = aws_output(aws_command(&url.bucket).args(args))
+            .map_err(S3Error::IoError)?;
+        if !output.status.success() {
+            return Err(command_error(
+                command,
+                url,
+                output.status,
+                &output.stderr,
+            ));
+        }
+        Ok(output.stdout)
+    };
+    match run() {
+        Err(S3Error::CredentialsExpired(message)) => {
+            if !refresh_credentials()
+                .map_err(S3Error::CredentialsRefreshFailed)?
+            {
+                return Err(S3Error::CredentialsExpired(message));
+            }
+            run()
+        }
+        result => result,
+    }
+}
+
+/// Write a download to "<path>.partial" and rename it to `path` once
+/// `write` succeeds, so that an interrupted download doesn't leave
+/// behind a truncated file that looks complete
+fn write_atomically<T>(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<T, S3Error>,
+) -> Result<T, S3Error> {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".partial");
+    let partial_path = path.with_file_name(file_name);
+    match write(&partial_path) {
+        Ok(value) => {
+            fs::rename(&partial_path, path)
+                .map_err(|err| S3Error::MoveError(path.to_path_buf(), err))?;
+            Ok(value)
+        }
+        Err(err) => {
+            if partial_path.exists() {
+                if let Err(err) = fs::remove_file(&partial_path) {
+                    error!(
+                        "failed to delete {}: {}",
+                        partial_path.display(),
+                        err
+                    );
+                }
+            }
+            Err(err)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Parse an RFC 3339 timestamp, such as an object's last-modified
+/// time, into seconds since the Unix epoch
+///
+/// Fractional seconds are dropped.
+fn parse_timestamp(s: &str) -> Option<i64> {
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (time, offset_s) = match time.strip_suffix('Z') {
+        Some(time) => (time, 0),
+        None => {
+            let index = time.rfind(['+', '-'])?;
+            let (time, offset) = time.split_at(index);
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let offset_s = hours.parse::<i64>().ok()? * 3600
+                + minutes.parse::<i64>().ok()? * 60;
+            if offset.starts_with('-') {
+                (time, -offset_s)
+            } else {
+                (time, offset_s)
+            }
+        }
+    };
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+
+    // Days since the epoch for a proleptic Gregorian date, see
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset_s)
+}
+
+/// Check if a string matches a pattern where `*` matches any run of
+/// characters
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == s,
+        Some((prefix, rest)) => match s.strip_prefix(prefix) {
+            Some(s) => (0..=s.len())
+                .filter(|&index| s.is_char_boundary(index))
+                .any(|index| glob_matches(rest, &s[index..])),
+            None => false,
+        },
+    }
+}
+
+/// Pick the newest object whose key, without `prefix`, matches
+/// `pattern`
+///
+/// Objects with an unparseable last-modified time count as the oldest.
+/// Ties are broken by key.
+fn pick_latest<'a>(
+    objects: &'a [ObjectSummary],
+    prefix: &str,
+    pattern: &str,
+    by: LatestBy,
+) -> Option<&'a ObjectSummary> {
+    let matching = objects.iter().filter(|object| {
+        object
+            .key
+            .strip_prefix(prefix)
+            .is_some_and(|rest| glob_matches(pattern, rest))
+    });
+    match by {
+        LatestBy::LastModified => matching.max_by_key(|object| {
+            (
+                parse_timestamp(&object.last_modified).unwrap_or(i64::MIN),
+                &object.key,
+            )
+        }),
+        LatestBy::Key => matching.max_by_key(|object| &object.key),
+    }
+}
+
+/// Get the key that follows `key` in a numbered sequence
+///
+/// The last number in the key's file name is incremented, keeping any
+/// zero padding, so `data/part-0009.bin` is followed by
+/// `data/part-0010.bin`. Returns None if the file name has no number.
+fn next_key(key: &str) -> Option<String> {
+    let name_start = key.rfind('/').map_or(0, |index| index + 1);
+    let end =
+        name_start + key[name_start..].rfind(|c: char| c.is_ascii_digit())? + 1;
+    let start = key[name_start..end]
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(name_start, |index| name_start + index + 1);
+    let digits = &key[start..end];
+    let next = digits.parse::<u64>().ok()?.checked_add(1)?;
+    Some(format!(
+        "{}{:0width$}{}",
+        &key[..start],
+        next,
+        &key[end..],
+        width = digits.len()
+    ))
+}
+
+/// Keep a copy of `path` as "<path>.bak" before it's replaced
+///
+/// The backup is a hardlink where possible, since the download replaces
+/// the path rather than writing into the existing file.
+fn back_up(path: &Path) -> Result<(), S3Error> {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(".bak");
+    let backup_path = PathBuf::from(backup_path);
+    let err = |err| S3Error::BackupError(path.to_path_buf(), err);
+    match fs::remove_file(&backup_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(err(e)),
+        _ => {}
+    }
+    if fs::hard_link(path, &backup_path).is_err() {
+        fs::copy(path, &backup_path).map_err(err)?;
+    }
+    Ok(())
+}
+
+/// Check that a downloaded file has the expected md5sum
+fn verify_download(path: &Path, md5sum: &str) -> Result<(), S3Error> {
+    let actual = md5sum_file(path)
+        .map_err(|err| S3Error::HashError(path.to_path_buf(), err))?;
+    if actual != md5sum {
+        return Err(S3Error::ChecksumMismatch(md5sum.to_string()));
+    }
+    Ok(())
+}
+
+/// Number of bytes of a temporary file left over from an interrupted
+/// download that can be kept
+///
+/// Only the size can be checked up front, since S3 doesn't provide
+/// hashes of parts of an object. If the kept bytes turn out to be
+/// corrupt, the md5sum of the finished download won't match and the
+/// temporary file is deleted, so the next download starts over.
+fn partial_length(tmp_path: &Path, total: u64) -> u64 {
+    let len = match fs::metadata(tmp_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return 0,
+    };
+    if len >= total {
+        return 0;
+    }
+    if len > 0 {
+        debug!(
+            "resuming download into {} at byte {}",
+            tmp_path.display(),
+            len
+        );
+    }
+    len
 }
 
 impl S3Url {
@@ -46,75 +676,1840 @@ impl S3Url {
     }
 
     /// Request the object's metadata
-    fn head_object(&self) -> Result<HeadObject, S3Error> {
-        let output = Command::new("aws")
-            .args(&[
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self), fields(url = %self.to_string()), err)
+    )]
+    pub fn head_object(&self) -> Result<HeadObject, S3Error> {
+        let stdout = run_aws(
+            "s3api head-object",
+            self,
+            &[
                 "s3api",
                 "head-object",
                 "--bucket",
                 &self.bucket,
                 "--key",
                 &self.key,
-            ])
-            .output()
-            .map_err(S3Error::IoError)?;
-        if !output.status.success() {
-            return Err(S3Error::CommandFailed(output.status));
+            ],
+        )?;
+        serde_json::from_slice(&stdout).map_err(S3Error::JsonError)
+    }
+
+    /// Get the object's size, modification time and other metadata
+    pub fn metadata(&self) -> Result<ObjectMetadata, S3Error> {
+        self.head_object().map(ObjectMetadata::from)
+    }
+
+    /// Get the md5sum stored in the object metadata, if any
+    pub fn md5sum(&self) -> Result<Option<String>, S3Error> {
+        Ok(self.head_object()?.metadata.md5sum)
+    }
+
+    /// Get the md5sum from the `<key>.md5` checksum file next to the
+    /// object, if there is a valid one
+    pub(crate) fn sidecar_md5sum(
+        &self,
+        store: &dyn ObjectStore,
+    ) -> Option<String> {
+        if self.key.ends_with(SIDECAR_EXTENSION) {
+            return None;
+        }
+        let sidecar = S3Url::new(
+            self.bucket.clone(),
+            format!("{}{}", self.key, SIDECAR_EXTENSION),
+        );
+        let mut contents = Vec::new();
+        match store.get_object(
+            &sidecar,
+            &mut contents,
+            None,
+            &mut |_| {},
+            &CancelToken::new(),
+        ) {
+            Ok(()) => {}
+            Err(S3Error::NotFound(_)) => return None,
+            Err(err) => {
+                warn!("failed to get {}: {}", sidecar.to_string(), err);
+                return None;
+            }
+        }
+        let md5sum = parse_md5sum_file(&String::from_utf8_lossy(&contents));
+        match &md5sum {
+            Some(md5sum) => {
+                debug!("md5sum of {} is {}", self.to_string(), md5sum)
+            }
+            None => {
+                warn!("{} isn't a valid checksum file", sidecar.to_string())
+            }
         }
-        serde_json::from_slice(&output.stdout).map_err(S3Error::JsonError)
+        md5sum
     }
 
-    /// Download the object directly (bypassing the cache)
-    pub fn download_direct(&self, path: &Path) -> Result<(), S3Error> {
-        let path_str = path.to_str().ok_or(S3Error::NonUtf8Path)?;
-        let status = Command::new("aws")
-            .args(&["s3", "cp", &self.to_string(), path_str])
-            .status()
-            .map_err(S3Error::IoError)?;
+    /// List all objects whose key starts with this URL's key
+    pub fn list(&self) -> Result<Vec<ObjectSummary>, S3Error> {
+        let stdout = run_aws(
+            "s3api list-objects-v2",
+            self,
+            &[
+                "s3api",
+                "list-objects-v2",
+                "--bucket",
+                &self.bucket,
+                "--prefix",
+                &self.key,
+            ],
+        )?;
+        // The output is empty rather than an empty listing when no
+        // objects match
+        if stdout.iter().all(|c| c.is_ascii_whitespace()) {
+            return Ok(Vec::new());
+        }
+        let list: ListObjects =
+            serde_json::from_slice(&stdout).map_err(S3Error::JsonError)?;
+        Ok(list.contents)
+    }
+
+    /// Find the newest object under this prefix matching `pattern`
+    ///
+    /// The pattern is matched against the rest of the key after the
+    /// prefix, where `*` matches anything (including '/'). For example,
+    /// with the prefix `s3://bucket/builds/` the pattern `*/app.tar`
+    /// matches `builds/2024-06-01/app.tar`. Download the result as
+    /// usual to get it through the cache.
+    pub fn latest(
+        &self,
+        pattern: &str,
+        by: LatestBy,
+    ) -> Result<S3Url, S3Error> {
+        let objects = self.list()?;
+        match pick_latest(&objects, &self.key, pattern, by) {
+            Some(object) => {
+                Ok(S3Url::new(self.bucket.clone(), object.key.clone()))
+            }
+            None => Err(S3Error::NotFound(format!(
+                "{}{}",
+                self.to_string(),
+                pattern
+            ))),
+        }
+    }
+
+    /// Delete the object
+    pub fn delete(&self) -> Result<(), S3Error> {
+        run_aws("s3 rm", self, &["s3", "rm", &self.to_string()])?;
+        Ok(())
+    }
+
+    /// Generate a URL that allows anyone to download the object until
+    /// it expires
+    pub fn presign(&self, expires_in_s: u64) -> Result<String, S3Error> {
+        let stdout = run_aws(
+            "s3 presign",
+            self,
+            &[
+                "s3",
+                "presign",
+                &self.to_string(),
+                "--expires-in",
+                &expires_in_s.to_string(),
+            ],
+        )?;
+        Ok(String::from_utf8_lossy(&stdout).trim().to_string())
+    }
+
+    /// Copy the output of a process such as "aws s3 cp <url> -" to a
+    /// writer
+    fn stream_output(
+        child: &mut AwsChild,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        let stdout = child.stdout.as_mut().expect("stdout is piped");
+        let mut buf = vec![0; 1024 * 1024];
+        let mut transferred = 0;
+        loop {
+            if cancel.is_cancelled() {
+                return Err(S3Error::Cancelled);
+            }
+            let len = stdout.read(&mut buf).map_err(S3Error::IoError)?;
+            if len == 0 {
+                break;
+            }
+            out.write_all(&buf[..len]).map_err(S3Error::WriteError)?;
+            transferred += len as u64;
+            progress(Progress {
+                transferred,
+                total,
+                from_cache: false,
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(url = %self.to_string(), path = %path.display()),
+            err
+        )
+    )]
+    fn download_direct_impl(
+        &self,
+        store: &dyn ObjectStore,
+        path: &Path,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        let mut file = File::create(path).map_err(S3Error::WriteError)?;
+        let result = store.get_object(self, &mut file, total, progress, cancel);
+        if let Err(S3Error::Cancelled) = result {
+            drop(file);
+            if let Err(err) = fs::remove_file(path) {
+                error!("failed to delete {}: {}", path.display(), err);
+            }
+        }
+        result
+    }
+
+    /// Download the object to `path`, returning the md5sum of the data
+    /// written
+    ///
+    /// If `offset` is non-zero, the first `offset` bytes of `path` are
+    /// kept and only the rest of the object is downloaded. Unlike
+    /// `download_direct_impl` this doesn't delete the file on failure.
+    fn download_hashed(
+        &self,
+        store: &dyn ObjectStore,
+        path: &Path,
+        offset: u64,
+        total: u64,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<String, S3Error> {
+        if offset == 0 {
+            let file = File::create(path).map_err(S3Error::WriteError)?;
+            let mut out = HashingWriter::new(io::BufWriter::new(file));
+            store.get_object(self, &mut out, Some(total), progress, cancel)?;
+            out.flush().map_err(S3Error::WriteError)?;
+            return Ok(out.md5sum());
+        }
+        let existing = File::open(path).map_err(S3Error::WriteError)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map_err(S3Error::WriteError)?;
+        file.set_len(offset).map_err(S3Error::WriteError)?;
+        let mut out = HashingWriter::resume(
+            io::BufWriter::new(file),
+            &mut existing.take(offset),
+        )
+        .map_err(S3Error::WriteError)?;
+        store.get_object_from(
+            self,
+            offset,
+            &mut out,
+            Some(total),
+            progress,
+            cancel,
+        )?;
+        out.flush().map_err(S3Error::WriteError)?;
+        Ok(out.md5sum())
+    }
+
+    /// Write the object's contents using the aws CLI
+    pub(crate) fn write_direct_impl(
+        &self,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        let mut child = AwsChild::spawn(
+            aws_command(&self.bucket)
+                .args(["s3", "cp", "--no-progress", &self.to_string(), "-"])
+                .stdout(Stdio::piped()),
+        )
+        .map_err(S3Error::IoError)?;
+        self.finish_stream("s3 cp", &mut child, out, total, progress, cancel)
+    }
+
+    /// Write the object's contents from byte `offset` onwards
+    ///
+    /// The aws CLI can't stream part of an object, so this fetches a
+    /// presigned URL with curl instead.
+    pub(crate) fn write_range_impl(
+        &self,
+        offset: u64,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        let url = self.presign(3600)?;
+        // curl is part of an S3 download here, so it counts against
+        // the aws process limit
+        let mut child = AwsChild::spawn(
+            Command::new("curl")
+                .args(["--fail", "--silent", "--show-error", "--range"])
+                .arg(format!("{}-", offset))
+                .arg(url)
+                .stdout(Stdio::piped()),
+        )
+        .map_err(S3Error::IoError)?;
+        let total = total.map(|total| total.saturating_sub(offset));
+        self.finish_stream("curl", &mut child, out, total, progress, cancel)
+    }
+
+    /// Copy a download process's output to a writer and wait for it
+    /// to exit
+    fn finish_stream(
+        &self,
+        command: &str,
+        child: &mut AwsChild,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        if let Err(err) =
+            S3Url::stream_output(child, out, total, progress, cancel)
+        {
+            if let Err(err) = child.kill() {
+                error!("failed to kill {} process: {}", command, err);
+            }
+            let _ = child.wait();
+            return Err(err);
+        }
+        let status = child.wait().map_err(S3Error::IoError)?;
         if !status.success() {
-            return Err(S3Error::CommandFailed(status));
+            return Err(S3Error::CommandFailed(
+                command.to_string(),
+                self.to_string(),
+                status,
+            ));
         }
         Ok(())
     }
 
-    pub fn download(&self, path: &Path) -> Result<(), S3Error> {
-        let head = self.head_object()?;
+    /// Download the object directly (bypassing the cache)
+    pub fn download_direct(&self, path: &Path) -> Result<(), S3Error> {
+        self.download_direct_cancellable(path, &mut |_| {}, &CancelToken::new())
+    }
+
+    /// Download the object directly (bypassing the cache), reporting
+    /// progress as bytes arrive
+    pub fn download_direct_with_progress(
+        &self,
+        path: &Path,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<(), S3Error> {
+        self.download_direct_cancellable(path, progress, &CancelToken::new())
+    }
+
+    /// Download the object directly (bypassing the cache), stopping
+    /// early with `S3Error::Cancelled` if `cancel` is triggered
+    ///
+    /// The object is written to "<path>.partial", which is renamed to
+    /// `path` once the download is complete, or deleted if it fails.
+    pub fn download_direct_cancellable(
+        &self,
+        path: &Path,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        write_atomically(path, |partial_path| {
+            self.download_direct_impl(
+                &AwsCli,
+                partial_path,
+                None,
+                progress,
+                cancel,
+            )
+        })
+    }
+
+    /// Upload a file, storing its md5sum in the object metadata
+    ///
+    /// Returns the md5sum of the file.
+    pub fn upload(&self, path: &Path) -> Result<String, S3Error> {
+        let md5sum = md5sum_file(path)
+            .map_err(|err| S3Error::HashError(path.to_path_buf(), err))?;
+        self.upload_with_md5sum(path, &md5sum)?;
+        Ok(md5sum)
+    }
+
+    fn upload_with_md5sum(
+        &self,
+        path: &Path,
+        md5sum: &str,
+    ) -> Result<(), S3Error> {
+        let url = self.to_string();
+        let metadata = format!("md5sum={}", md5sum);
+        // The path is passed as an OsStr so that it doesn't have to be
+        // valid UTF-8
+        let args = [
+            OsStr::new("s3"),
+            OsStr::new("cp"),
+            path.as_os_str(),
+            OsStr::new(&url),
+            OsStr::new("--metadata"),
+            OsStr::new(&metadata),
+        ];
+        run_aws("s3 cp", self, &args)?;
+        Ok(())
+    }
+
+    /// Check if the object exists with this md5sum in its metadata
+    fn has_md5sum(&self, md5sum: &str) -> Result<bool, S3Error> {
+        match self.md5sum() {
+            Ok(existing) => Ok(existing.as_deref() == Some(md5sum)),
+            Err(S3Error::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Upload a file like `upload`, with extra settings
+    ///
+    /// Failing to add the file to the cache doesn't fail the upload;
+    /// it is logged and reported in the summary instead. This includes
+    /// the file not fitting in the cache.
+    pub fn upload_with_options(
+        &self,
+        path: &Path,
+        options: UploadOptions,
+    ) -> Result<UploadSummary, S3Error> {
+        let md5sum = md5sum_file(path)
+            .map_err(|err| S3Error::HashError(path.to_path_buf(), err))?;
+        let skipped = options.skip_if_exists && self.has_md5sum(&md5sum)?;
+        if skipped {
+            debug!(
+                "{} already has md5sum {}, skipping upload",
+                self.to_string(),
+                md5sum
+            );
+        } else {
+            self.upload_with_md5sum(path, &md5sum)?;
+        }
+        let mut cached = false;
+        if let Some(cache) = options.write_through {
+            if !skipped {
+                cache.forget_head(self);
+            }
+            match cache.insert(&md5sum, path) {
+                Ok(true) => {
+                    cached = true;
+                    if let Err(err) =
+                        cache.set_source(&md5sum, &self.to_string())
+                    {
+                        warn!("failed to record source of {}: {}", md5sum, err);
+                    }
+                }
+                Ok(false) => {
+                    warn!("not enough space to cache {}", path.display())
+                }
+                Err(err) => {
+                    warn!("failed to cache {}: {}", path.display(), err)
+                }
+            }
+        }
+        Ok(UploadSummary {
+            md5sum,
+            cached,
+            skipped,
+        })
+    }
+
+    /// Store the md5sum of an object that wasn't uploaded with
+    /// `S3Url::upload` in its metadata, so that it can be cached
+    ///
+    /// The md5sum is taken from the ETag if the object was uploaded in
+    /// one part and isn't encrypted with KMS or a customer key;
+    /// otherwise the object is downloaded and hashed. The metadata is
+    /// then replaced by copying the object onto itself, keeping its
+    /// other metadata, content headers and storage class. With
+    /// `dry_run` the md5sum is computed but not stored.
+    pub fn backfill_md5sum(&self, dry_run: bool) -> Result<Backfill, S3Error> {
+        let stdout = run_aws(
+            "s3api head-object",
+            self,
+            &[
+                "s3api",
+                "head-object",
+                "--bucket",
+                &self.bucket,
+                "--key",
+                &self.key,
+            ],
+        )?;
+        let mut headers: CopiedHeaders =
+            serde_json::from_slice(&stdout).map_err(S3Error::JsonError)?;
+        if headers.metadata.contains_key("md5sum") {
+            return Ok(Backfill::AlreadySet);
+        }
+        // The ETag of a single-part upload is only its md5sum if the
+        // object is unencrypted or encrypted with SSE-S3
+        let etag_is_md5sum = matches!(
+            headers.server_side_encryption.as_deref(),
+            None | Some("AES256")
+        );
+        let backfill = match etag_md5sum(&headers.etag) {
+            Some(md5sum) if etag_is_md5sum => {
+                Backfill::FromEtag(md5sum.to_string())
+            }
+            _ => {
+                let mut out = HashingWriter::new(io::sink());
+                self.write_direct_impl(
+                    &mut out,
+                    None,
+                    &mut |_| {},
+                    &CancelToken::new(),
+                )?;
+                Backfill::Downloaded(out.md5sum())
+            }
+        };
+        let md5sum = match &backfill {
+            Backfill::FromEtag(md5sum) | Backfill::Downloaded(md5sum) => {
+                md5sum.clone()
+            }
+            Backfill::AlreadySet => return Ok(backfill),
+        };
+        if dry_run {
+            return Ok(backfill);
+        }
+        headers.metadata.insert("md5sum".to_string(), md5sum);
+        let metadata = serde_json::to_string(&headers.metadata)
+            .map_err(S3Error::JsonError)?;
+        let url = self.to_string();
+        let mut args = vec![
+            "s3",
+            "cp",
+            "--only-show-errors",
+            &url,
+            &url,
+            "--metadata-directive",
+            "REPLACE",
+            "--metadata",
+            &metadata,
+        ];
+        let optional = [
+            ("--cache-control", &headers.cache_control),
+            ("--content-disposition", &headers.content_disposition),
+            ("--content-encoding", &headers.content_encoding),
+            ("--content-type", &headers.content_type),
+            ("--storage-class", &headers.storage_class),
+            ("--sse", &headers.server_side_encryption),
+            ("--sse-kms-key-id", &headers.sse_kms_key_id),
+        ];
+        for (flag, value) in &optional {
+            if let Some(value) = value {
+                args.extend([*flag, value.as_str()]);
+            }
+        }
+        run_aws("s3 cp", self, &args)?;
+        Ok(backfill)
+    }
+
+    /// Download the object into the cache
+    ///
+    /// The configured remote cache server is tried first, then S3. The
+    /// download is checked against the md5sum before it is added.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip(self, cache, progress, cancel),
+            fields(url = %self.to_string()),
+            err
+        )
+    )]
+    fn download_into_cache(
+        &self,
+        cache: &Cache,
+        md5sum: &str,
+        total: u64,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        let _lock = cache.lock_entry(md5sum).map_err(S3Error::CacheError)?;
+        if cache.contains(md5sum) {
+            debug!("{} was cached by another download", md5sum);
+            return Ok(());
+        }
+        let tmp_path = cache.temporary_path(md5sum);
+
+        // Downloads from S3 are hashed as they're written, so only
+        // downloads from the remote cache have to be read back
+        let mut streamed_md5sum = None;
+        let mut result = Err(S3Error::NotInRemoteCache);
+        if let Some(remote) = cache.remote_cache() {
+            result = remote
+                .download(md5sum, &tmp_path)
+                .map_err(S3Error::RemoteError);
+            match &result {
+                Ok(()) => progress(Progress {
+                    transferred: total,
+                    total: Some(total),
+                    from_cache: false,
+                }),
+                Err(S3Error::RemoteError(err @ RemoteError::Skipped(..))) => {
+                    debug!("{}", err)
+                }
+                Err(err) => warn!(
+                    "failed to download {} from {}: {}",
+                    md5sum,
+                    remote.object_url(md5sum),
+                    err
+                ),
+            }
+        }
+        if result.is_err() {
+            result = if cancel.is_cancelled() {
+                Err(S3Error::Cancelled)
+            } else {
+                self.download_hashed(
+                    cache.object_store(),
+                    &tmp_path,
+                    partial_length(&tmp_path, total),
+                    total,
+                    progress,
+                    cancel,
+                )
+                .map(|actual| streamed_md5sum = Some(actual))
+            };
+        }
+        if result.is_ok() {
+            result = match streamed_md5sum {
+                Some(actual) if actual != md5sum => {
+                    Err(S3Error::ChecksumMismatch(md5sum.to_string()))
+                }
+                Some(_) => Ok(()),
+                None => verify_download(&tmp_path, md5sum),
+            };
+        }
+
+        if let Err(err) = result {
+            // The download may already have cleaned up after itself
+            if tmp_path.exists() {
+                if let Err(err) = fs::remove_file(&tmp_path) {
+                    error!("failed to delete {}: {}", tmp_path.display(), err);
+                }
+            }
+            return Err(err);
+        }
+
+        cache.finish_temporary(md5sum).map_err(S3Error::CacheError)
+    }
+
+    /// Download the object through the cache
+    ///
+    /// Objects are looked up first in the local cache, then in the
+    /// remote cache server (if configured), and finally in S3. Both the
+    /// remote and the S3 download populate the local cache. Objects
+    /// without an md5sum, or too large for the cache, are downloaded
+    /// directly; the returned summary says which of these happened.
+    pub fn download(&self, path: &Path) -> Result<DownloadSummary, S3Error> {
+        self.download_with_progress(path, &mut |_| {})
+    }
+
+    /// Download the object through the cache, reporting progress
+    ///
+    /// See `download` for details. Bytes copied out of the local cache
+    /// are reported with `from_cache` set.
+    pub fn download_with_progress(
+        &self,
+        path: &Path,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<DownloadSummary, S3Error> {
+        let cache = Cache::open().map_err(S3Error::CacheError)?;
+        self.download_with_cache(&cache, path, progress)
+    }
+
+    /// Download the object through an already open cache, reporting
+    /// progress
+    ///
+    /// See `download_with_progress` for details.
+    pub fn download_with_cache(
+        &self,
+        cache: &Cache,
+        path: &Path,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<DownloadSummary, S3Error> {
+        self.download_cancellable(cache, path, progress, &CancelToken::new())
+    }
+
+    /// Download the object through an already open cache, stopping
+    /// early with `S3Error::Cancelled` if `cancel` is triggered
+    ///
+    /// See `download_with_options` for details.
+    pub fn download_cancellable(
+        &self,
+        cache: &Cache,
+        path: &Path,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<DownloadSummary, S3Error> {
+        let options = DownloadOptions {
+            progress: Some(progress),
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        self.download_with_options(cache, path, options)
+    }
+
+    /// Download the object through an already open cache
+    ///
+    /// See `download` for how the object is looked up and
+    /// `DownloadOptions` for what can be changed per download. Partial
+    /// downloads, whether into the cache or directly to `path`, are
+    /// deleted on cancellation.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(url = %self.to_string(), path = %path.display()),
+            ret,
+            err
+        )
+    )]
+    pub fn download_with_options(
+        &self,
+        cache: &Cache,
+        path: &Path,
+        options: DownloadOptions,
+    ) -> Result<DownloadSummary, S3Error> {
+        if options.overwrite == Overwrite::Never && path.exists() {
+            return Err(S3Error::DestinationExists(path.to_path_buf()));
+        }
+        if options.force_refresh && cache.is_offline() {
+            return Err(S3Error::Offline(self.to_string()));
+        }
+        let head = cache.head_object(self, options.force_refresh)?;
+        if fs::symlink_metadata(path).is_ok() {
+            match (options.overwrite, &head.metadata.md5sum) {
+                (Overwrite::SkipUnchanged, Some(md5sum))
+                    if verify_download(path, md5sum).is_ok() =>
+                {
+                    debug!("{} is unchanged, skipping", path.display());
+                    return Ok(DownloadSummary {
+                        outcome: DownloadOutcome::AlreadyPresent,
+                        bytes: head.content_length,
+                    });
+                }
+                (Overwrite::Backup, _) => back_up(path)?,
+                _ => {}
+            }
+            // Don't write into whatever the destination is, in
+            // particular not through a symlink into the cache
+            if options.write_in_place {
+                fs::remove_file(path).map_err(S3Error::WriteError)?;
+            }
+        }
+        let preserve_mtime = options.preserve_mtime;
+        let mode = options.mode;
+        let write_in_place = options.write_in_place;
+        let output = options.output;
+        let is_linked = |summary: &DownloadSummary| {
+            output != OutputMode::Copy
+                && !matches!(
+                    summary.outcome,
+                    DownloadOutcome::DirectBypass { .. }
+                )
+        };
+        let download = |path: &Path| {
+            let summary =
+                self.download_with_options_impl(cache, path, &head, options)?;
+            // Changing a link would change the cache entry
+            if is_linked(&summary) {
+                return Ok(summary);
+            }
+            if preserve_mtime {
+                let mtime =
+                    parse_timestamp(&head.last_modified).ok_or_else(|| {
+                        S3Error::InvalidTimestamp(head.last_modified.clone())
+                    })?;
+                filetime::set_file_mtime(
+                    path,
+                    FileTime::from_unix_time(mtime, 0),
+                )
+                .map_err(S3Error::WriteError)?;
+            }
+            if let Some(mode) = mode {
+                set_mode(path, mode).map_err(S3Error::WriteError)?;
+            }
+            Ok(summary)
+        };
+        let summary = cache.record_download(|| {
+            if write_in_place {
+                download(path)
+            } else {
+                write_atomically(path, download)
+            }
+        })?;
+        match &head.metadata.md5sum {
+            Some(md5sum) if is_linked(&summary) => {
+                cache.add_link(md5sum, path).map_err(S3Error::CacheError)?
+            }
+            _ => {}
+        }
+        Ok(summary)
+    }
+
+    fn download_with_options_impl(
+        &self,
+        cache: &Cache,
+        path: &Path,
+        head: &HeadObject,
+        options: DownloadOptions,
+    ) -> Result<DownloadSummary, S3Error> {
+        let mut no_progress = |_| {};
+        let progress: &mut dyn FnMut(Progress) = match options.progress {
+            Some(progress) => progress,
+            None => &mut no_progress,
+        };
+        let no_cancel = CancelToken::new();
+        let cancel = options.cancel.unwrap_or(&no_cancel);
+
+        let store = cache.object_store();
+        let total = head.content_length;
+        let summary = |outcome| DownloadSummary {
+            outcome,
+            bytes: total,
+        };
+        let bypass = |reason| summary(DownloadOutcome::DirectBypass { reason });
 
         // If the object doesn't have an md5sum then we can't look it
         // up in the cache
-        let md5sum;
-        if let Some(m) = head.metadata.md5sum.as_ref() {
-            md5sum = m;
+        let md5sum = match &head.metadata.md5sum {
+            Some(md5sum) if !options.bypass_cache => md5sum,
+            md5sum => {
+                if md5sum.is_some() {
+                    debug!(
+                        "bypassing cache for {}: requested",
+                        self.to_string()
+                    );
+                } else {
+                    debug!(
+                        "bypassing cache for {}: no md5sum metadata",
+                        self.to_string()
+                    );
+                }
+                self.download_direct_impl(
+                    store,
+                    path,
+                    Some(total),
+                    progress,
+                    cancel,
+                )?;
+                if let Some(md5sum) = md5sum {
+                    if options.verify {
+                        verify_download(path, md5sum)?;
+                    }
+                    return Ok(bypass(BypassReason::Requested));
+                }
+                return Ok(bypass(BypassReason::NoMd5sum));
+            }
+        };
+
+        if options.force_refresh && cache.contains(md5sum) {
+            cache.remove(md5sum).map_err(S3Error::CacheError)?;
+        }
+        let is_hit =
+            match self.ensure_cached(cache, md5sum, total, progress, cancel)? {
+                Ok(is_hit) => is_hit,
+                Err(reason) => {
+                    self.download_direct_impl(
+                        store,
+                        path,
+                        Some(total),
+                        progress,
+                        cancel,
+                    )?;
+                    if options.verify {
+                        verify_download(path, md5sum)?;
+                    }
+                    return Ok(bypass(reason));
+                }
+            };
+        cache
+            .place(md5sum, path, options.output)
+            .map_err(S3Error::CacheError)?;
+        if options.verify {
+            if let Err(err) = verify_download(path, md5sum) {
+                // The cached copy is corrupt, so make sure the next
+                // download fetches the object again
+                match cache.quarantine(md5sum, "verification after download") {
+                    Ok(entry) => warn!(
+                        "{} is corrupt, moved it to {}",
+                        md5sum,
+                        entry.path.display()
+                    ),
+                    Err(err) => {
+                        error!("failed to quarantine {}: {}", md5sum, err)
+                    }
+                }
+                return Err(err);
+            }
+        }
+        if is_hit {
+            progress(Progress {
+                transferred: total,
+                total: Some(total),
+                from_cache: true,
+            });
+            Ok(summary(DownloadOutcome::CacheHit))
         } else {
-            return self.download_direct(path);
+            Ok(summary(DownloadOutcome::CachedThenCopied))
         }
+    }
 
-        let cache = Cache::open().map_err(S3Error::CacheError)?;
-        if cache.contains(md5sum) {
-            cache.copy(md5sum, path).map_err(S3Error::CacheError)
+    /// Write the object's contents through the cache, e.g. to stdout
+    ///
+    /// This works like `download_with_cache`, except that the contents
+    /// are written to `out` instead of a file.
+    pub fn write_with_cache(
+        &self,
+        cache: &Cache,
+        out: &mut dyn Write,
+    ) -> Result<DownloadSummary, S3Error> {
+        let head = cache.head_object(self, false)?;
+        cache.record_download(|| self.write_with_cache_impl(cache, &head, out))
+    }
+
+    fn write_with_cache_impl(
+        &self,
+        cache: &Cache,
+        head: &HeadObject,
+        out: &mut dyn Write,
+    ) -> Result<DownloadSummary, S3Error> {
+        let cancel = CancelToken::new();
+        let store = cache.object_store();
+        let total = head.content_length;
+        let summary = |outcome| DownloadSummary {
+            outcome,
+            bytes: total,
+        };
+        let bypass = |reason| summary(DownloadOutcome::DirectBypass { reason });
+
+        let md5sum = match &head.metadata.md5sum {
+            Some(md5sum) => md5sum,
+            None => {
+                debug!(
+                    "bypassing cache for {}: no md5sum metadata",
+                    self.to_string()
+                );
+                store.get_object(
+                    self,
+                    out,
+                    Some(total),
+                    &mut |_| {},
+                    &cancel,
+                )?;
+                return Ok(bypass(BypassReason::NoMd5sum));
+            }
+        };
+        let is_hit = match self.ensure_cached(
+            cache,
+            md5sum,
+            total,
+            &mut |_| {},
+            &cancel,
+        )? {
+            Ok(is_hit) => is_hit,
+            Err(reason) => {
+                store.get_object(
+                    self,
+                    out,
+                    Some(total),
+                    &mut |_| {},
+                    &cancel,
+                )?;
+                return Ok(bypass(reason));
+            }
+        };
+        cache.copy_to(md5sum, out).map_err(S3Error::CacheError)?;
+        if is_hit {
+            Ok(summary(DownloadOutcome::CacheHit))
         } else {
-            match cache.make_space(head.content_length) {
-                Ok(true) => {
-                    // Download the object into the cache
-                    let tmp_path = cache.temporary_path(md5sum);
-                    if let Err(err) = self.download_direct(&tmp_path) {
-                        if let Err(err) = fs::remove_file(&tmp_path) {
-                            error!(
-                                "failed to delete {}: {}",
-                                tmp_path.display(),
-                                err
-                            );
-                        }
-                        Err(err)
-                    } else {
-                        let final_path = cache.path(md5sum);
-                        fs::rename(tmp_path, final_path)
-                            .map_err(S3Error::MoveError)
+            Ok(summary(DownloadOutcome::CachedThenCopied))
+        }
+    }
+
+    /// Make sure the object is in the cache, downloading it if needed
+    ///
+    /// Returns whether it was a cache hit, or why the object bypasses
+    /// the cache. Hits and misses are recorded in the stats.
+    fn ensure_cached(
+        &self,
+        cache: &Cache,
+        md5sum: &str,
+        total: u64,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<Result<bool, BypassReason>, S3Error> {
+        let is_hit = cache.contains(md5sum);
+        let stats_result = if is_hit {
+            debug!("cache hit for {} ({})", self.to_string(), md5sum);
+            if let Err(err) = cache.add_alias(md5sum, &self.to_string()) {
+                warn!("failed to record alias of {}: {}", md5sum, err);
+            }
+            cache.record_hit(total)
+        } else {
+            if let Some(reason) =
+                cache.admit(total).map_err(S3Error::CacheError)?
+            {
+                debug!(
+                    "bypassing cache for {} ({} bytes): {}",
+                    self.to_string(),
+                    total,
+                    reason
+                );
+                return Ok(Err(reason));
+            }
+            debug!(
+                "cache miss for {} ({}), downloading",
+                self.to_string(),
+                md5sum
+            );
+            self.download_into_cache(cache, md5sum, total, progress, cancel)?;
+            if let Err(err) = cache.set_source(md5sum, &self.to_string()) {
+                warn!("failed to record source of {}: {}", md5sum, err);
+            }
+            if let Some(replica) = cache.object_store().failed_over_to(self) {
+                info!("{} was served by replica {}", self.to_string(), replica);
+                if let Err(err) = cache.set_served_by(md5sum, &replica) {
+                    warn!("failed to record replica of {}: {}", md5sum, err);
+                }
+            }
+            cache.record_miss(total)
+        };
+        if let Err(err) = stats_result {
+            warn!("failed to update cache stats: {}", err);
+        }
+        if let Err(err) = cache.record_access(md5sum) {
+            warn!("failed to record access to {}: {}", md5sum, err);
+        }
+        Ok(Ok(is_hit))
+    }
+
+    /// Download the object into the cache without copying it anywhere
+    ///
+    /// If the expected md5sum is known, the object isn't requested at
+    /// all when it's already cached. Otherwise the expected md5sum is
+    /// checked against the one in the object metadata. Prefetches don't
+    /// count as cache hits or misses.
+    ///
+    /// Space is made for each object separately, so prefetching in
+    /// parallel can exceed the size limit by up to the size of the
+    /// other objects being downloaded at the same time.
+    pub fn prefetch(
+        &self,
+        cache: &Cache,
+        expected_md5sum: Option<&str>,
+    ) -> Result<Prefetch, S3Error> {
+        if let Some(md5sum) = expected_md5sum {
+            if cache.contains(md5sum) {
+                return Ok(Prefetch::AlreadyCached);
+            }
+        }
+
+        let head = cache.head_object(self, false)?;
+        let md5sum = match head.metadata.md5sum {
+            Some(md5sum) => md5sum,
+            None => return Ok(Prefetch::NotCacheable),
+        };
+        if let Some(expected) = expected_md5sum {
+            if expected != md5sum {
+                return Err(S3Error::ChecksumMismatch(expected.to_string()));
+            }
+        }
+        if cache.contains(&md5sum) {
+            return Ok(Prefetch::AlreadyCached);
+        }
+
+        let total = head.content_length;
+        if cache.admit(total).map_err(S3Error::CacheError)?.is_some() {
+            return Ok(Prefetch::NotCacheable);
+        }
+        self.download_into_cache(
+            cache,
+            &md5sum,
+            total,
+            &mut |_| {},
+            &CancelToken::new(),
+        )?;
+        if let Err(err) = cache.set_source(&md5sum, &self.to_string()) {
+            warn!("failed to record source of {}: {}", md5sum, err);
+        }
+        Ok(Prefetch::Fetched(total))
+    }
+
+    /// Check whether a download of the object would be served from the
+    /// cache, without downloading anything or changing the cache
+    ///
+    /// This needs the object's head, so it asks S3 unless the head is
+    /// cached. Nothing is evicted to work out whether a missing object
+    /// would fit, and hits and misses aren't recorded in the stats.
+    pub fn cache_status(&self, cache: &Cache) -> Result<CacheStatus, S3Error> {
+        let head = cache.head_object(self, false)?;
+        let md5sum = match &head.metadata.md5sum {
+            Some(md5sum) => md5sum,
+            None => {
+                return Ok(CacheStatus::Uncacheable {
+                    reason: BypassReason::NoMd5sum,
+                })
+            }
+        };
+        if cache.contains(md5sum) {
+            return Ok(CacheStatus::Hit);
+        }
+        match cache
+            .would_admit(head.content_length)
+            .map_err(S3Error::CacheError)?
+        {
+            Some(reason) => Ok(CacheStatus::Uncacheable { reason }),
+            None => Ok(CacheStatus::Miss),
+        }
+    }
+
+    /// Get the URLs of the next `count` objects in a numbered sequence
+    ///
+    /// See `next_key` for how the sequence is determined. The list is
+    /// empty if the key isn't numbered.
+    pub fn siblings(&self, count: usize) -> Vec<S3Url> {
+        let mut siblings = Vec::new();
+        let mut key = self.key.clone();
+        while siblings.len() < count {
+            key = match next_key(&key) {
+                Some(key) => key,
+                None => break,
+            };
+            siblings.push(S3Url::new(self.bucket.clone(), key.clone()));
+        }
+        siblings
+    }
+
+    /// Prefetch the objects likely to be requested after this one
+    ///
+    /// This is opt-in with the `prefetch_siblings` setting, which is how
+    /// many of the following objects in a numbered sequence to fetch
+    /// into the cache. If `prefetch_patterns` is set, only URLs matching
+    /// one of the patterns get their siblings prefetched.
+    ///
+    /// The prefetch runs in a background thread, which stops at the
+    /// first sibling that doesn't exist. Returns None if there is
+    /// nothing to prefetch, which is always the case offline.
+    pub fn prefetch_siblings(
+        &self,
+        cache: Arc<Cache>,
+    ) -> Option<thread::JoinHandle<()>> {
+        if cache.is_offline() {
+            return None;
+        }
+        let patterns = cache.prefetch_patterns();
+        let url = self.to_string();
+        if !patterns.is_empty()
+            && !patterns.iter().any(|pattern| glob_matches(pattern, &url))
+        {
+            return None;
+        }
+        let siblings = self.siblings(cache.prefetch_siblings());
+        if siblings.is_empty() {
+            return None;
+        }
+        Some(thread::spawn(move || {
+            for sibling in siblings {
+                match sibling.prefetch(&cache, None) {
+                    Ok(prefetch) => {
+                        debug!(
+                            "prefetched sibling {}: {:?}",
+                            sibling.to_string(),
+                            prefetch
+                        )
+                    }
+                    Err(S3Error::NotFound(_)) => break,
+                    Err(err) => {
+                        warn!(
+                            "failed to prefetch {}: {}",
+                            sibling.to_string(),
+                            err
+                        );
+                        break;
                     }
                 }
-                Ok(false) => self.download_direct(path),
-                Err(err) => Err(S3Error::CacheError(err)),
             }
+        }))
+    }
+}
+
+impl FromStr for S3Url {
+    type Err = S3Error;
+
+    /// Parse s3://<bucket>/<key>
+    ///
+    /// The key may be empty, e.g. when referring to a whole bucket.
+    fn from_str(s: &str) -> Result<S3Url, S3Error> {
+        let invalid = || S3Error::InvalidUrl(s.to_string());
+        let rest = s.strip_prefix("s3://").ok_or_else(invalid)?;
+        let mut parts = rest.splitn(2, '/');
+        let bucket = parts.next().unwrap_or_default();
+        let key = parts.next().unwrap_or_default();
+        if bucket.is_empty() {
+            return Err(invalid());
         }
+        Ok(S3Url::new(bucket.to_string(), key.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{test_configuration, Configuration};
+    use crate::fake::MemoryStore;
+    use std::sync::Arc;
+
+    fn open_test_cache(dir: &Path) -> (Cache, Arc<MemoryStore>) {
+        let store = Arc::new(MemoryStore::new());
+        let cache = Cache::open_with_configuration(test_configuration(dir))
+            .unwrap()
+            .with_object_store(store.clone());
+        (cache, store)
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url: S3Url = "s3://bucket/a/b.tar".parse().unwrap();
+        assert_eq!(url.bucket, "bucket");
+        assert_eq!(url.key, "a/b.tar");
+        assert_eq!(url.to_string(), "s3://bucket/a/b.tar");
+
+        let url: S3Url = "s3://bucket".parse().unwrap();
+        assert_eq!(url.bucket, "bucket");
+        assert_eq!(url.key, "");
+
+        assert!("bucket/key".parse::<S3Url>().is_err());
+        assert!("s3:///key".parse::<S3Url>().is_err());
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("s3://bucket/a", "s3://bucket/a"));
+        assert!(!glob_matches("s3://bucket/a", "s3://bucket/ab"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("s3://*/part-*", "s3://bucket/x/part-1"));
+        assert!(!glob_matches("s3://*/part-*", "s3://bucket/x/file-1"));
+        assert!(glob_matches("*.bin", "s3://bucket/a.bin"));
+        assert!(!glob_matches("*.bin", "s3://bucket/a.bin.tmp"));
+    }
+
+    #[test]
+    fn test_siblings() {
+        assert_eq!(next_key("part-0009.bin").unwrap(), "part-0010.bin");
+        assert_eq!(next_key("a1/part-9").unwrap(), "a1/part-10");
+        assert_eq!(next_key("v2/data").as_deref(), None);
+        assert_eq!(next_key("").as_deref(), None);
+
+        let url: S3Url = "s3://bucket/data/part-0001".parse().unwrap();
+        let siblings: Vec<_> =
+            url.siblings(2).iter().map(|url| url.to_string()).collect();
+        assert_eq!(
+            siblings,
+            ["s3://bucket/data/part-0002", "s3://bucket/data/part-0003"]
+        );
+        let url: S3Url = "s3://bucket/data".parse().unwrap();
+        assert!(url.siblings(2).is_empty());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_timestamp("2020-01-01T00:00:00.000Z"),
+            Some(1_577_836_800)
+        );
+        assert_eq!(
+            parse_timestamp("2020-01-01T01:00:00+01:00"),
+            Some(1_577_836_800)
+        );
+        assert_eq!(
+            parse_timestamp("2020-03-01T12:34:56-00:30"),
+            Some(1_583_067_896)
+        );
+        assert_eq!(parse_timestamp("2020-13-01T00:00:00Z"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn test_pick_latest() {
+        let object = |key: &str, last_modified: &str| ObjectSummary {
+            key: key.to_string(),
+            last_modified: last_modified.to_string(),
+            etag: String::new(),
+            size: 0,
+        };
+        let objects = [
+            object("builds/2024-06-01/app.tar", "2024-06-02T00:00:00Z"),
+            object("builds/2024-05-01/app.tar", "2024-06-03T00:00:00Z"),
+            object("builds/2024-07-01/app.zip", "2024-07-01T00:00:00Z"),
+            object("builds/2024-07-01/app.tar", "invalid"),
+        ];
+        let latest = |pattern, by| {
+            pick_latest(&objects, "builds/", pattern, by)
+                .map(|object| object.key.as_str())
+        };
+        assert_eq!(
+            latest("*/app.tar", LatestBy::LastModified),
+            Some("builds/2024-05-01/app.tar")
+        );
+        assert_eq!(
+            latest("*/app.tar", LatestBy::Key),
+            Some("builds/2024-07-01/app.tar")
+        );
+        assert_eq!(latest("*.deb", LatestBy::Key), None);
+    }
+
+    #[test]
+    fn test_etag_md5sum() {
+        let mut object = ObjectSummary {
+            key: "key".to_string(),
+            last_modified: "2020-01-01T00:00:00.000Z".to_string(),
+            etag: "\"5d41402abc4b2a76b9719d911017c592\"".to_string(),
+            size: 5,
+        };
+        assert_eq!(
+            object.etag_md5sum(),
+            Some("5d41402abc4b2a76b9719d911017c592")
+        );
+        object.etag = "\"9b2cf535f27731c974343645a3985328-2\"".to_string();
+        assert_eq!(object.etag_md5sum(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_error() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let url: S3Url = "s3://bucket/key".parse().unwrap();
+        let status = ExitStatus::from_raw(255 << 8);
+        let err = |stderr: &str| {
+            command_error("s3api head-object", &url, status, stderr.as_bytes())
+        };
+        assert!(matches!(
+            err("An error occurred (404) when calling the HeadObject \
+                 operation: Not Found"),
+            S3Error::NotFound(_)
+        ));
+        assert!(matches!(
+            err("Unable to locate credentials."),
+            S3Error::AccessDenied(_)
+        ));
+        assert!(matches!(
+            err("An error occurred (ExpiredToken) when calling the \
+                 HeadObject operation: The provided token has expired."),
+            S3Error::CredentialsExpired(_)
+        ));
+        assert!(matches!(
+            err("Could not connect to the endpoint URL: \"https://...\""),
+            S3Error::NetworkError(_)
+        ));
+        assert!(matches!(err("something else"), S3Error::CommandFailed(..)));
+    }
+
+    #[test]
+    fn test_verify_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test");
+        fs::write(&path, "hello").unwrap();
+        assert!(
+            verify_download(&path, "5d41402abc4b2a76b9719d911017c592").is_ok()
+        );
+        assert!(matches!(
+            verify_download(&path, "00000000000000000000000000000000"),
+            Err(S3Error::ChecksumMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_download_with_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, store) = open_test_cache(dir.path());
+        let dst_path = dir.path().join("dst");
+
+        let url: S3Url = "s3://bucket/hello".parse().unwrap();
+        store.insert(&url, b"hello", Some("5d41402abc4b2a76b9719d911017c592"));
+        let summary = url.download_with_cache(&cache, &dst_path, &mut |_| {});
+        assert_eq!(
+            summary.unwrap(),
+            DownloadSummary {
+                outcome: DownloadOutcome::CachedThenCopied,
+                bytes: 5,
+            }
+        );
+        let summary = url.download_with_cache(&cache, &dst_path, &mut |_| {});
+        assert_eq!(summary.unwrap().outcome, DownloadOutcome::CacheHit);
+        assert_eq!(fs::read(&dst_path).unwrap(), b"hello");
+        assert_eq!(store.get_count(), 1);
+        let metadata =
+            cache.metadata("5d41402abc4b2a76b9719d911017c592").unwrap();
+        assert_eq!(metadata.accesses, 2);
+
+        // Another key with the same contents is a hit, and becomes an
+        // alias of the entry
+        let copy: S3Url = "s3://bucket/hello-copy".parse().unwrap();
+        store.insert(&copy, b"hello", Some("5d41402abc4b2a76b9719d911017c592"));
+        let summary = copy.download_with_cache(&cache, &dst_path, &mut |_| {});
+        assert_eq!(summary.unwrap().outcome, DownloadOutcome::CacheHit);
+        assert_eq!(
+            cache.entries().unwrap()[0].aliases,
+            ["s3://bucket/hello", "s3://bucket/hello-copy"]
+        );
+        assert_eq!(
+            cache.lookup_url("s3://bucket/hello-copy").unwrap().unwrap(),
+            "5d41402abc4b2a76b9719d911017c592"
+        );
+        assert_eq!(cache.lookup_url("s3://bucket/other").unwrap(), None);
+
+        let url: S3Url = "s3://bucket/no-md5sum".parse().unwrap();
+        store.insert(&url, b"hello", None);
+        let summary = url.download_with_cache(&cache, &dst_path, &mut |_| {});
+        assert_eq!(
+            summary.unwrap().outcome,
+            DownloadOutcome::DirectBypass {
+                reason: BypassReason::NoMd5sum
+            }
+        );
+
+        let url: S3Url = "s3://bucket/missing".parse().unwrap();
+        assert!(matches!(
+            url.download_with_cache(&cache, &dst_path, &mut |_| {}),
+            Err(S3Error::NotFound(_))
+        ));
+
+        // Data that doesn't match the md5sum isn't cached
+        let url: S3Url = "s3://bucket/corrupt".parse().unwrap();
+        let md5sum = "0".repeat(32);
+        store.insert(&url, b"hello", Some(&md5sum));
+        assert!(matches!(
+            url.download_with_cache(&cache, &dst_path, &mut |_| {}),
+            Err(S3Error::ChecksumMismatch(_))
+        ));
+        assert!(!cache.contains(&md5sum));
+        assert!(!cache.temporary_path(&md5sum).exists());
+    }
+
+    #[test]
+    fn test_resume_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, store) = open_test_cache(dir.path());
+        let dst_path = dir.path().join("dst");
+        let md5sum = "5d41402abc4b2a76b9719d911017c592";
+        let url: S3Url = "s3://bucket/hello".parse().unwrap();
+        store.insert(&url, b"hello", Some(md5sum));
+
+        // A partial download left over from a crash is kept
+        fs::write(cache.temporary_path(md5sum), "hel").unwrap();
+        url.download_with_cache(&cache, &dst_path, &mut |_| {})
+            .unwrap();
+        assert_eq!(fs::read(&dst_path).unwrap(), b"hello");
+        assert!(cache.contains(md5sum));
+        assert!(!cache.temporary_path(md5sum).exists());
+
+        // A corrupt one is deleted once the download fails to verify,
+        // and the next download starts over
+        cache.remove(md5sum).unwrap();
+        fs::write(cache.temporary_path(md5sum), "jel").unwrap();
+        assert!(matches!(
+            url.download_with_cache(&cache, &dst_path, &mut |_| {}),
+            Err(S3Error::ChecksumMismatch(_))
+        ));
+        assert!(!cache.temporary_path(md5sum).exists());
+        url.download_with_cache(&cache, &dst_path, &mut |_| {})
+            .unwrap();
+        assert_eq!(fs::read(&dst_path).unwrap(), b"hello");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_download_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, store) = open_test_cache(dir.path());
+        let dst_path = dir.path().join(OsStr::from_bytes(b"dst\xff"));
+
+        let url: S3Url = "s3://bucket/hello".parse().unwrap();
+        store.insert(&url, b"hello", Some("5d41402abc4b2a76b9719d911017c592"));
+        url.download_with_cache(&cache, &dst_path, &mut |_| {})
+            .unwrap();
+        assert_eq!(fs::read(&dst_path).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_object_metadata() {
+        let head = HeadObject {
+            last_modified: "2020-01-01T00:00:00+00:00".to_string(),
+            content_length: 5,
+            etag: "\"5d41402abc4b2a76b9719d911017c592\"".to_string(),
+            storage_class: None,
+            metadata: HeadObjectMetadata {
+                md5sum: Some("5d41402abc4b2a76b9719d911017c592".to_string()),
+            },
+        };
+        let metadata = ObjectMetadata::from(head);
+        assert_eq!(metadata.size, 5);
+        assert_eq!(metadata.storage_class, "STANDARD");
+        assert_eq!(
+            metadata.md5sum.as_deref(),
+            Some("5d41402abc4b2a76b9719d911017c592")
+        );
+    }
+
+    #[test]
+    fn test_download_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, store) = open_test_cache(dir.path());
+        let dst_path = dir.path().join("dst");
+        let url: S3Url = "s3://bucket/hello".parse().unwrap();
+        let md5sum = "5d41402abc4b2a76b9719d911017c592";
+        store.insert(&url, b"hello", Some(md5sum));
+        let download = |options| {
+            url.download_with_options(&cache, &dst_path, options)
+                .map(|summary| summary.outcome)
+        };
+
+        let options = DownloadOptions {
+            bypass_cache: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            download(options).unwrap(),
+            DownloadOutcome::DirectBypass {
+                reason: BypassReason::Requested
+            }
+        );
+        assert!(!cache.contains(md5sum));
+
+        let options = DownloadOptions {
+            overwrite: Overwrite::Never,
+            ..Default::default()
+        };
+        assert!(matches!(
+            download(options),
+            Err(S3Error::DestinationExists(_))
+        ));
+
+        let options = DownloadOptions {
+            force_refresh: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            download(options).unwrap(),
+            DownloadOutcome::CachedThenCopied
+        );
+        let options = DownloadOptions {
+            force_refresh: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            download(options).unwrap(),
+            DownloadOutcome::CachedThenCopied
+        );
+        assert_eq!(store.get_count(), 3);
+
+        let options = DownloadOptions {
+            overwrite: Overwrite::SkipUnchanged,
+            ..Default::default()
+        };
+        assert_eq!(download(options).unwrap(), DownloadOutcome::AlreadyPresent);
+
+        // The backup keeps the old contents
+        fs::write(&dst_path, "old").unwrap();
+        let options = DownloadOptions {
+            overwrite: Overwrite::Backup,
+            ..Default::default()
+        };
+        assert_eq!(download(options).unwrap(), DownloadOutcome::CacheHit);
+        assert_eq!(fs::read(&dst_path).unwrap(), b"hello");
+        assert_eq!(fs::read(dir.path().join("dst.bak")).unwrap(), b"old");
+
+        // A corrupt entry fails verification and is quarantined
+        fs::write(cache.path(md5sum), "hellp").unwrap();
+        let options = DownloadOptions {
+            verify: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            download(options),
+            Err(S3Error::ChecksumMismatch(_))
+        ));
+        assert!(!cache.contains(md5sum));
+        let quarantined = cache.quarantined().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].md5sum, md5sum);
+        assert_eq!(quarantined[0].source.as_deref(), Some("s3://bucket/hello"));
+        assert_eq!(fs::read(&quarantined[0].path).unwrap(), b"hellp");
+
+        // Nothing is left behind by a failed download
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let options = DownloadOptions {
+            bypass_cache: true,
+            cancel: Some(&cancel),
+            ..Default::default()
+        };
+        let new_path = dir.path().join("new");
+        assert!(matches!(
+            url.download_with_options(&cache, &new_path, options),
+            Err(S3Error::Cancelled)
+        ));
+        assert!(!new_path.exists());
+        assert!(!dir.path().join("new.partial").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_download_file_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, store) = open_test_cache(dir.path());
+        let dst_path = dir.path().join("dst");
+        let url: S3Url = "s3://bucket/hello".parse().unwrap();
+        store.insert(&url, b"hello", Some("5d41402abc4b2a76b9719d911017c592"));
+
+        let options = DownloadOptions {
+            preserve_mtime: true,
+            mode: Some(0o751),
+            ..Default::default()
+        };
+        url.download_with_options(&cache, &dst_path, options)
+            .unwrap();
+        let metadata = fs::metadata(&dst_path).unwrap();
+        // MemoryStore objects were last modified at the epoch
+        assert_eq!(
+            FileTime::from_last_modification_time(&metadata).unix_seconds(),
+            0
+        );
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o751);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, store) = open_test_cache(dir.path());
+        let a: S3Url = "s3://bucket/a".parse().unwrap();
+        let b: S3Url = "s3://bucket/b".parse().unwrap();
+        store.insert(&a, b"hello", Some("5d41402abc4b2a76b9719d911017c592"));
+        store.insert(&b, b"world", Some("7d793037a0760186574b0282f2f435e7"));
+        a.download_with_cache(&cache, &dir.path().join("a1"), &mut |_| {})
+            .unwrap();
+        drop(cache);
+
+        let conf = Configuration {
+            offline: true,
+            ..test_configuration(dir.path())
+        };
+        let cache = Cache::open_with_configuration(conf)
+            .unwrap()
+            .with_object_store(store.clone());
+        let get_count = store.get_count();
+        let a_path = dir.path().join("a2");
+        let summary =
+            a.download_with_cache(&cache, &a_path, &mut |_| {}).unwrap();
+        assert_eq!(summary.outcome, DownloadOutcome::CacheHit);
+        assert_eq!(fs::read(&a_path).unwrap(), b"hello");
+
+        // Nothing that isn't cached is fetched
+        assert!(matches!(
+            b.download_with_cache(&cache, &dir.path().join("b"), &mut |_| {}),
+            Err(S3Error::Offline(url)) if url == "s3://bucket/b"
+        ));
+        let options = DownloadOptions {
+            bypass_cache: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            a.download_with_options(&cache, &dir.path().join("a3"), options),
+            Err(S3Error::Offline(_))
+        ));
+        assert_eq!(store.get_count(), get_count);
+    }
+
+    #[test]
+    fn test_cache_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, store) = open_test_cache(dir.path());
+        let hello: S3Url = "s3://bucket/hello".parse().unwrap();
+        let no_md5sum: S3Url = "s3://bucket/no-md5sum".parse().unwrap();
+        let big: S3Url = "s3://bucket/big".parse().unwrap();
+        store.insert(
+            &hello,
+            b"hello",
+            Some("5d41402abc4b2a76b9719d911017c592"),
+        );
+        store.insert(&no_md5sum, b"hello", None);
+        store.insert(&big, &[0; 101], Some(&"0".repeat(32)));
+
+        assert_eq!(hello.cache_status(&cache).unwrap(), CacheStatus::Miss);
+        assert_eq!(
+            no_md5sum.cache_status(&cache).unwrap(),
+            CacheStatus::Uncacheable {
+                reason: BypassReason::NoMd5sum
+            }
+        );
+        assert_eq!(
+            big.cache_status(&cache).unwrap(),
+            CacheStatus::Uncacheable {
+                reason: BypassReason::TooLarge
+            }
+        );
+        assert_eq!(store.get_count(), 0);
+
+        hello
+            .download_with_cache(&cache, &dir.path().join("dst"), &mut |_| {})
+            .unwrap();
+        assert_eq!(hello.cache_status(&cache).unwrap(), CacheStatus::Hit);
+        let missing: S3Url = "s3://bucket/missing".parse().unwrap();
+        assert!(matches!(
+            missing.cache_status(&cache),
+            Err(S3Error::NotFound(_))
+        ));
+
+        drop(cache);
+
+        // Checking doesn't evict entries to make space
+        let conf = Configuration {
+            cache_size_limit_in_bytes: 8,
+            min_cached_size_in_bytes: Some(4),
+            ..test_configuration(dir.path())
+        };
+        let cache = Cache::open_with_configuration(conf)
+            .unwrap()
+            .with_object_store(store.clone());
+        let world: S3Url = "s3://bucket/world".parse().unwrap();
+        let tiny: S3Url = "s3://bucket/tiny".parse().unwrap();
+        store.insert(
+            &world,
+            b"world",
+            Some("7d793037a0760186574b0282f2f435e7"),
+        );
+        store.insert(&tiny, b"hi", Some("49f68a5c8493ec2c0bf489821c21fc3b"));
+        assert_eq!(world.cache_status(&cache).unwrap(), CacheStatus::Miss);
+        assert_eq!(
+            tiny.cache_status(&cache).unwrap(),
+            CacheStatus::Uncacheable {
+                reason: BypassReason::BelowMinCachedSize
+            }
+        );
+        assert!(cache.contains("5d41402abc4b2a76b9719d911017c592"));
+    }
+
+    #[test]
+    fn test_checksum_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration {
+            checksum_sidecars: true,
+            ..test_configuration(dir.path())
+        };
+        let store = Arc::new(MemoryStore::new());
+        let cache = Cache::open_with_configuration(conf)
+            .unwrap()
+            .with_object_store(store.clone());
+        let a: S3Url = "s3://bucket/a.tar".parse().unwrap();
+        let b: S3Url = "s3://bucket/b.tar".parse().unwrap();
+        let c: S3Url = "s3://bucket/c.tar".parse().unwrap();
+        let sidecar = |url: &S3Url| -> S3Url {
+            format!("{}.md5", url.to_string()).parse().unwrap()
+        };
+        store.insert(&a, b"hello", None);
+        store.insert(
+            &sidecar(&a),
+            b"5d41402abc4b2a76b9719d911017c592  a.tar\n",
+            None,
+        );
+        store.insert(&b, b"world", None);
+        store.insert(&c, b"hello", None);
+        store.insert(&sidecar(&c), b"7d793037a0760186574b0282f2f435e7", None);
+
+        let path = dir.path().join("out");
+        let download = |url: &S3Url| {
+            url.download_with_cache(&cache, &path, &mut |_| {})
+                .map(|summary| summary.outcome)
+        };
+        assert_eq!(download(&a).unwrap(), DownloadOutcome::CachedThenCopied);
+        assert_eq!(download(&a).unwrap(), DownloadOutcome::CacheHit);
+        assert!(cache.contains("5d41402abc4b2a76b9719d911017c592"));
+
+        // Objects without a checksum file still bypass the cache
+        assert_eq!(
+            download(&b).unwrap(),
+            DownloadOutcome::DirectBypass {
+                reason: BypassReason::NoMd5sum
+            }
+        );
+
+        // A wrong checksum file is caught rather than trusted blindly
+        assert!(matches!(download(&c), Err(S3Error::ChecksumMismatch(_))));
+    }
+
+    #[test]
+    fn test_download_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, store) = open_test_cache(dir.path());
+        let a: S3Url = "s3://bucket/a".parse().unwrap();
+        let b: S3Url = "s3://bucket/b".parse().unwrap();
+        let a_md5sum = "cc7ed669cf88f201c3297c6a91e1d18d";
+        store.insert(&a, &[b'a'; 60], Some(a_md5sum));
+        store.insert(&b, &[b'b'; 60], Some("3cb5465a7e33b90e250e8e24a764e65a"));
+
+        let link_path = dir.path().join("a-link");
+        let options = DownloadOptions {
+            output: OutputMode::Symlink,
+            ..Default::default()
+        };
+        a.download_with_options(&cache, &link_path, options)
+            .unwrap();
+        let target = fs::read_link(&link_path).unwrap();
+        assert_eq!(target, fs::canonicalize(cache.path(a_md5sum)).unwrap());
+        let entry = cache.entries().unwrap().pop().unwrap();
+        assert!(entry.linked);
+
+        // The linked entry can't be evicted to make space
+        let b_path = dir.path().join("b");
+        let summary =
+            b.download_with_cache(&cache, &b_path, &mut |_| {}).unwrap();
+        assert_eq!(
+            summary.outcome,
+            DownloadOutcome::DirectBypass {
+                reason: BypassReason::TooLarge
+            }
+        );
+
+        // Downloading over the link replaces it rather than writing
+        // into the cache entry
+        store.insert(&a, &[b'x'; 60], Some(a_md5sum));
+        let options = DownloadOptions {
+            bypass_cache: true,
+            write_in_place: true,
+            ..Default::default()
+        };
+        a.download_with_options(&cache, &link_path, options)
+            .unwrap();
+        assert!(!fs::symlink_metadata(&link_path)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(cache.verify(a_md5sum).unwrap());
+
+        assert_eq!(cache.gc().unwrap().stale_links, 1);
+        let summary =
+            b.download_with_cache(&cache, &b_path, &mut |_| {}).unwrap();
+        assert_eq!(summary.outcome, DownloadOutcome::CachedThenCopied);
+        assert!(!cache.contains(a_md5sum));
     }
 }
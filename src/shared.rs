@@ -0,0 +1,160 @@
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::process::Command;
+
+/// Look up a group ID by name, or parse it if it's already numeric
+///
+/// Names are resolved with getent so that groups from NSS sources
+/// such as LDAP work too.
+#[cfg(unix)]
+fn lookup_group(name: &str) -> io::Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let not_found = || {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("group \"{}\" not found", name),
+        )
+    };
+    let output = Command::new("getent").args(["group", name]).output()?;
+    if !output.status.success() {
+        return Err(not_found());
+    }
+    // The output is <name>:<password>:<gid>:<members>
+    String::from_utf8_lossy(&output.stdout)
+        .split(':')
+        .nth(2)
+        .and_then(|gid| gid.trim().parse().ok())
+        .ok_or_else(not_found)
+}
+
+/// Set up the cache directory for sharing between users of a group
+///
+/// The directory gets the setgid bit so that everything created in it
+/// inherits its group. If `group` (a name or ID) is given the
+/// directory's group is changed to it first.
+#[cfg(unix)]
+pub(crate) fn share_dir(path: &Path, group: Option<&str>) -> io::Result<()> {
+    use std::os::unix::fs::{chown, MetadataExt};
+
+    if let Some(group) = group {
+        let gid = lookup_group(group)?;
+        if path.metadata()?.gid() != gid {
+            chown(path, None, Some(gid))?;
+        }
+    }
+    add_mode_bits(path, 0o2070)
+}
+
+/// Make a file in the cache writable by the group
+#[cfg(unix)]
+pub(crate) fn share_file(path: &Path) -> io::Result<()> {
+    add_mode_bits(path, 0o060)
+}
+
+/// Set mode bits unless they are already set
+///
+/// Only the owner can change the mode, so skipping files that are
+/// already shared avoids failing on other users' files.
+#[cfg(unix)]
+fn add_mode_bits(path: &Path, bits: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = path.metadata()?.permissions();
+    if permissions.mode() & bits != bits {
+        permissions.set_mode(permissions.mode() | bits);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// Get the ID of the user running this process
+#[cfg(unix)]
+fn current_uid() -> io::Result<u32> {
+    let output = Command::new("id").arg("-u").output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("id -u failed"));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Get the current user's cache directory under a common root
+///
+/// If the root doesn't exist yet it's created world-writable with the
+/// sticky bit, like /tmp, so that every user can add their own
+/// directory but not remove anyone else's.
+#[cfg(unix)]
+pub(crate) fn user_dir(root: &Path) -> io::Result<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if !root.exists() {
+        std::fs::create_dir_all(root)?;
+        std::fs::set_permissions(
+            root,
+            std::fs::Permissions::from_mode(0o1777),
+        )?;
+    }
+    Ok(root.join(current_uid()?.to_string()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn user_dir(_root: &Path) -> io::Result<PathBuf> {
+    Err(io::Error::other(
+        "per-user caches are only supported on Unix",
+    ))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn share_dir(_path: &Path, _group: Option<&str>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn share_file(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_share_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test");
+        fs::write(&path, "test").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        share_file(&path).unwrap();
+        let mode = path.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+    }
+
+    #[test]
+    fn test_user_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let path = user_dir(&root).unwrap();
+        assert_eq!(path.parent().unwrap(), root);
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse::<u32>()
+            .is_ok());
+        let mode = root.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o1777);
+    }
+
+    #[test]
+    fn test_lookup_group() {
+        assert_eq!(lookup_group("1234").unwrap(), 1234);
+    }
+}
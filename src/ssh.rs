@@ -0,0 +1,199 @@
+use crate::cache::{Cache, CacheError};
+use crate::fetch::fetch_through_cache;
+use crate::manifest::is_md5sum;
+use crate::s3::DownloadSummary;
+use log::error;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SshError {
+    #[error(transparent)]
+    CacheError(#[from] CacheError),
+    #[error("checksum mismatch for {0}")]
+    ChecksumMismatch(String),
+    #[error("failed to run {0} for {1} ({2})")]
+    CommandFailed(String, String, ExitStatus),
+    #[error("failed to create {}", .0.display())]
+    CreateError(PathBuf, #[source] io::Error),
+    #[error("unexpected output from {0}")]
+    InvalidOutput(String),
+    #[error("invalid SSH URL: {0}")]
+    InvalidUrl(String),
+    #[error("failed to run ssh")]
+    IoError(#[source] io::Error),
+}
+
+/// A file on a host reachable over SSH, such as a bastion or file
+/// server, used as the source of an object
+///
+/// This runs the ssh command, so authentication uses the user's SSH
+/// configuration and agent. The md5sum is read from a
+/// `<path>.md5` sidecar file on the host if there is one, and
+/// computed with `md5sum` on the host otherwise. Since that reads the
+/// whole file on each request, sidecar files are recommended for large
+/// files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SshFile {
+    /// Host, optionally with a user (user@host)
+    pub host: String,
+    pub port: Option<u16>,
+    /// Absolute path on the host
+    pub path: String,
+}
+
+/// Quote a string for the remote shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl SshFile {
+    /// Parse ssh://[<user>@]<host>[:<port>]/<path>
+    pub fn from_url(url: &str) -> Result<SshFile, SshError> {
+        let invalid = || SshError::InvalidUrl(url.to_string());
+        let rest = url.strip_prefix("ssh://").ok_or_else(invalid)?;
+        let index = rest.find('/').ok_or_else(invalid)?;
+        let (authority, path) = rest.split_at(index);
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                (host, Some(port.parse().map_err(|_| invalid())?))
+            }
+            None => (authority, None),
+        };
+        // A host starting with '-' would be read as an ssh option
+        if host.is_empty() || host.starts_with('-') || path.len() < 2 {
+            return Err(invalid());
+        }
+        Ok(SshFile {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    pub fn to_url(&self) -> String {
+        match self.port {
+            Some(port) => format!("ssh://{}:{}{}", self.host, port, self.path),
+            None => format!("ssh://{}{}", self.host, self.path),
+        }
+    }
+
+    /// Run a shell script on the host
+    fn ssh(&self, script: &str) -> Command {
+        let mut command = Command::new("ssh");
+        if let Some(port) = self.port {
+            command.args(["-p", &port.to_string()]);
+        }
+        command.arg("--").arg(&self.host).arg(script);
+        command
+    }
+
+    /// Get the size and md5sum of the file
+    fn stat(&self) -> Result<(u64, String), SshError> {
+        let path = shell_quote(&self.path);
+        let sidecar = shell_quote(&format!("{}.md5", self.path));
+        let script = format!(
+            "wc -c < {path} && (cat {sidecar} 2>/dev/null || md5sum {path})",
+            path = path,
+            sidecar = sidecar
+        );
+        let output = self.ssh(&script).output().map_err(SshError::IoError)?;
+        if !output.status.success() {
+            error!("{}", String::from_utf8_lossy(&output.stderr).trim());
+            return Err(SshError::CommandFailed(
+                "ssh".to_string(),
+                self.to_url(),
+                output.status,
+            ));
+        }
+        let invalid_output = || SshError::InvalidOutput("ssh".to_string());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut words = stdout.split_whitespace();
+        let size = words
+            .next()
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(invalid_output)?;
+        let md5sum = words
+            .next()
+            .filter(|md5sum| is_md5sum(md5sum))
+            .ok_or_else(invalid_output)?;
+        Ok((size, md5sum.to_ascii_lowercase()))
+    }
+
+    /// Copy the file to a local path
+    ///
+    /// The file is streamed with cat rather than copied with scp, so
+    /// that its path is quoted the same way whichever scp protocol the
+    /// host supports.
+    fn copy(&self, path: &Path) -> Result<(), SshError> {
+        let file = File::create(path)
+            .map_err(|err| SshError::CreateError(path.to_path_buf(), err))?;
+        let status = self
+            .ssh(&format!("cat {}", shell_quote(&self.path)))
+            .stdout(file)
+            .status()
+            .map_err(SshError::IoError)?;
+        if !status.success() {
+            return Err(SshError::CommandFailed(
+                "ssh".to_string(),
+                self.to_url(),
+                status,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Copy the file to `path` through the cache
+    pub fn download(
+        &self,
+        cache: &Cache,
+        path: &Path,
+    ) -> Result<DownloadSummary, SshError> {
+        let (size, md5sum) = self.stat()?;
+        let url = self.to_url();
+        cache.record_download(|| {
+            fetch_through_cache(
+                cache,
+                &url,
+                &md5sum,
+                size,
+                path,
+                &|path| self.copy(path),
+                &|| SshError::ChecksumMismatch(url.clone()),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_url() {
+        let file = SshFile::from_url("ssh://me@bastion/srv/a.tar").unwrap();
+        assert_eq!(file.host, "me@bastion");
+        assert_eq!(file.port, None);
+        assert_eq!(file.path, "/srv/a.tar");
+        assert_eq!(file.to_url(), "ssh://me@bastion/srv/a.tar");
+
+        let file = SshFile::from_url("ssh://bastion:2222/a").unwrap();
+        assert_eq!(file.port, Some(2222));
+        assert_eq!(file.to_url(), "ssh://bastion:2222/a");
+
+        assert!(SshFile::from_url("ssh://bastion").is_err());
+        assert!(SshFile::from_url("ssh://bastion/").is_err());
+        assert!(SshFile::from_url("ssh:///a").is_err());
+        assert!(SshFile::from_url("ssh://bastion:port/a").is_err());
+        assert!(SshFile::from_url("ssh://-oProxyCommand=touch x/a").is_err());
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/a b"), "'/a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,318 @@
+use crate::configuration::parse_config;
+use crate::s3::{DownloadOutcome, DownloadSummary};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::time::Duration;
+use std::{fs, io};
+
+/// Counts of values in buckets that double in size
+///
+/// Bucket 0 counts zeros, and bucket `i` counts values from `2^(i-1)`
+/// to `2^i - 1`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Histogram {
+    /// Number of values in each bucket, without trailing empty buckets
+    pub buckets: Vec<u64>,
+    /// Sum of all the values
+    pub sum: u64,
+}
+
+impl Histogram {
+    /// Get the values counted by a bucket
+    pub fn bucket_range(index: usize) -> RangeInclusive<u64> {
+        match index {
+            0 => 0..=0,
+            64.. => 1 << 63..=u64::MAX,
+            _ => 1 << (index - 1)..=(1 << index) - 1,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let index = (u64::BITS - value.leading_zeros()) as usize;
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    /// Number of values recorded
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Mean of the values, or 0 if there are none
+    pub fn mean(&self) -> f64 {
+        match self.count() {
+            0 => 0.0,
+            count => self.sum as f64 / count as f64,
+        }
+    }
+
+    /// Get an upper bound for the value below which a fraction `q` of
+    /// the values fall, e.g. 0.9 for the 90th percentile
+    ///
+    /// Returns None if there are no values.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket;
+            if seen >= rank {
+                return Some(*Histogram::bucket_range(index).end());
+            }
+        }
+        None
+    }
+
+    fn parse(buckets: &str, sum: &str) -> Histogram {
+        Histogram {
+            buckets: buckets
+                .split(',')
+                .filter(|bucket| !bucket.is_empty())
+                .map(|bucket| bucket.trim().parse().unwrap_or(0))
+                .collect(),
+            sum: sum.parse().unwrap_or(0),
+        }
+    }
+
+    fn format(&self) -> String {
+        let buckets: Vec<String> =
+            self.buckets.iter().map(u64::to_string).collect();
+        buckets.join(",")
+    }
+}
+
+/// Histograms of the downloads with one outcome
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Transfers {
+    /// How long the downloads took in milliseconds
+    pub duration_ms: Histogram,
+    /// Sizes of the downloaded objects in bytes
+    pub size: Histogram,
+}
+
+/// Cache usage counters, persisted in the cache directory
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// Number of downloads served from the cache
+    pub hits: u64,
+    /// Number of downloads that were fetched into the cache
+    pub misses: u64,
+    /// Bytes copied out of the cache instead of being downloaded
+    pub hit_bytes: u64,
+    /// Bytes downloaded into the cache
+    pub miss_bytes: u64,
+    /// Number of entries evicted to make space
+    pub evictions: u64,
+    /// Bytes evicted to make space
+    pub evicted_bytes: u64,
+    /// Downloads served from the cache
+    pub hit_transfers: Transfers,
+    /// Downloads that were fetched into the cache
+    pub miss_transfers: Transfers,
+    /// Downloads that bypassed the cache
+    pub direct_transfers: Transfers,
+}
+
+const HITS: &str = "hits";
+const MISSES: &str = "misses";
+const HIT_BYTES: &str = "hit_bytes";
+const MISS_BYTES: &str = "miss_bytes";
+const EVICTIONS: &str = "evictions";
+const EVICTED_BYTES: &str = "evicted_bytes";
+/// Prefixes of the keys of each outcome's histograms
+const HIT: &str = "hit";
+const MISS: &str = "miss";
+const DIRECT: &str = "direct";
+
+impl Stats {
+    fn parse(s: &str) -> Stats {
+        let map = parse_config(s);
+        let get = |key| {
+            map.get(key)
+                .and_then(|val: &&str| val.parse().ok())
+                .unwrap_or(0)
+        };
+        Stats {
+            hits: get(HITS),
+            misses: get(MISSES),
+            hit_bytes: get(HIT_BYTES),
+            miss_bytes: get(MISS_BYTES),
+            evictions: get(EVICTIONS),
+            evicted_bytes: get(EVICTED_BYTES),
+            hit_transfers: parse_transfers(&map, HIT),
+            miss_transfers: parse_transfers(&map, MISS),
+            direct_transfers: parse_transfers(&map, DIRECT),
+        }
+    }
+
+    fn format(&self) -> String {
+        let mut s = format!(
+            "{} = {}\n{} = {}\n{} = {}\n{} = {}\n{} = {}\n{} = {}\n",
+            HITS,
+            self.hits,
+            MISSES,
+            self.misses,
+            HIT_BYTES,
+            self.hit_bytes,
+            MISS_BYTES,
+            self.miss_bytes,
+            EVICTIONS,
+            self.evictions,
+            EVICTED_BYTES,
+            self.evicted_bytes
+        );
+        for (prefix, transfers) in [
+            (HIT, &self.hit_transfers),
+            (MISS, &self.miss_transfers),
+            (DIRECT, &self.direct_transfers),
+        ] {
+            for (name, histogram) in [
+                ("duration_ms", &transfers.duration_ms),
+                ("size", &transfers.size),
+            ] {
+                s += &format!(
+                    "{}_{} = {}\n{}_{}_sum = {}\n",
+                    prefix,
+                    name,
+                    histogram.format(),
+                    prefix,
+                    name,
+                    histogram.sum
+                );
+            }
+        }
+        s
+    }
+
+    /// Read stats from a file, or get zeroed stats if it doesn't exist
+    pub(crate) fn load(path: &Path) -> io::Result<Stats> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Stats::parse(&contents)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(Stats::default())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.format())
+    }
+
+    /// Add a finished download to the histograms of its outcome
+    pub(crate) fn record_download(
+        &mut self,
+        summary: &DownloadSummary,
+        duration: Duration,
+    ) {
+        let transfers = match summary.outcome {
+            DownloadOutcome::CacheHit => &mut self.hit_transfers,
+            DownloadOutcome::CachedThenCopied => &mut self.miss_transfers,
+            DownloadOutcome::DirectBypass { .. } => &mut self.direct_transfers,
+            DownloadOutcome::AlreadyPresent => return,
+        };
+        let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        transfers.duration_ms.record(millis);
+        transfers.size.record(summary.bytes);
+    }
+
+    /// Fraction of cached downloads that were hits
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+fn parse_transfers(map: &HashMap<&str, &str>, prefix: &str) -> Transfers {
+    let histogram = |name| {
+        let key = format!("{}_{}", prefix, name);
+        let sum_key = format!("{}_sum", key);
+        Histogram::parse(
+            map.get(key.as_str()).unwrap_or(&""),
+            map.get(sum_key.as_str()).unwrap_or(&""),
+        )
+    };
+    Transfers {
+        duration_ms: histogram("duration_ms"),
+        size: histogram("size"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::BypassReason;
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.txt");
+        assert_eq!(Stats::load(&path).unwrap(), Stats::default());
+        assert_eq!(Stats::default().hit_ratio(), 0.0);
+
+        let stats = Stats {
+            hits: 3,
+            misses: 1,
+            hit_bytes: 300,
+            miss_bytes: 100,
+            evictions: 2,
+            evicted_bytes: 200,
+            ..Default::default()
+        };
+        stats.save(&path).unwrap();
+        assert_eq!(Stats::load(&path).unwrap(), stats);
+        assert_eq!(stats.hit_ratio(), 0.75);
+
+        let mut stats = Stats::default();
+        let summary = |outcome| DownloadSummary {
+            outcome,
+            bytes: 100,
+        };
+        stats.record_download(
+            &summary(DownloadOutcome::CacheHit),
+            Duration::from_millis(5),
+        );
+        stats.record_download(
+            &summary(DownloadOutcome::DirectBypass {
+                reason: BypassReason::TooLarge,
+            }),
+            Duration::from_secs(2),
+        );
+        assert_eq!(stats.hit_transfers.duration_ms.buckets, [0, 0, 0, 1]);
+        assert_eq!(stats.hit_transfers.size.sum, 100);
+        assert_eq!(stats.miss_transfers, Transfers::default());
+        assert_eq!(stats.direct_transfers.duration_ms.sum, 2000);
+        stats.save(&path).unwrap();
+        assert_eq!(Stats::load(&path).unwrap(), stats);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        assert_eq!(histogram.mean(), 0.0);
+        for value in [0, 1, 2, 3, 100] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.buckets, [1, 1, 2, 0, 0, 0, 0, 1]);
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.mean(), 21.2);
+        assert_eq!(histogram.quantile(0.5), Some(3));
+        assert_eq!(histogram.quantile(1.0), Some(127));
+        assert_eq!(Histogram::bucket_range(7), 64..=127);
+        assert_eq!(Histogram::bucket_range(64), 1 << 63..=u64::MAX);
+    }
+}
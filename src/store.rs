@@ -0,0 +1,146 @@
+use crate::cancel::CancelToken;
+use crate::progress::Progress;
+use crate::s3::{HeadObject, S3Error, S3Url};
+use std::io::{self, Write};
+
+/// Where objects are downloaded from
+///
+/// This covers the requests that downloads through the cache make. The
+/// default implementation, `AwsCli`, talks to S3; tests can swap in a
+/// fake with `Cache::with_object_store`.
+pub trait ObjectStore: Send + Sync {
+    /// Get the object's metadata
+    fn head_object(&self, url: &S3Url) -> Result<HeadObject, S3Error>;
+
+    /// Write the object's contents, reporting progress as bytes arrive
+    ///
+    /// Should fail with `S3Error::Cancelled` if `cancel` is triggered.
+    fn get_object(
+        &self,
+        url: &S3Url,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error>;
+
+    /// Write the object's contents from byte `offset` onwards
+    ///
+    /// This is used to resume interrupted downloads. The default
+    /// implementation gets the whole object and skips the first
+    /// `offset` bytes.
+    fn get_object_from(
+        &self,
+        url: &S3Url,
+        offset: u64,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        let mut out = SkipWriter {
+            inner: out,
+            skip: offset,
+        };
+        self.get_object(url, &mut out, total, progress, cancel)
+    }
+
+    /// Describe the replica that requests for `url` currently go to
+    /// because its bucket failed, if any
+    fn failed_over_to(&self, _url: &S3Url) -> Option<String> {
+        None
+    }
+}
+
+/// Writer that drops the first `skip` bytes written through it
+struct SkipWriter<'a> {
+    inner: &'a mut dyn Write,
+    skip: u64,
+}
+
+impl Write for SkipWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skipped = self.skip.min(buf.len() as u64) as usize;
+        self.skip -= skipped as u64;
+        if skipped == buf.len() {
+            return Ok(skipped);
+        }
+        Ok(skipped + self.inner.write(&buf[skipped..])?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Access S3 by running the aws CLI
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AwsCli;
+
+impl ObjectStore for AwsCli {
+    fn head_object(&self, url: &S3Url) -> Result<HeadObject, S3Error> {
+        url.head_object()
+    }
+
+    fn get_object(
+        &self,
+        url: &S3Url,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        url.write_direct_impl(out, total, progress, cancel)
+    }
+
+    fn get_object_from(
+        &self,
+        url: &S3Url,
+        offset: u64,
+        out: &mut dyn Write,
+        total: Option<u64>,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        url.write_range_impl(offset, out, total, progress, cancel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_writer() {
+        let mut buf = Vec::new();
+        let mut out = SkipWriter {
+            inner: &mut buf,
+            skip: 3,
+        };
+        out.write_all(b"he").unwrap();
+        out.write_all(b"llo").unwrap();
+        out.write_all(b" world").unwrap();
+        assert_eq!(buf, b"lo world");
+    }
+}
+
+/// Fails every request, for a cache in offline mode
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Offline;
+
+impl ObjectStore for Offline {
+    fn head_object(&self, url: &S3Url) -> Result<HeadObject, S3Error> {
+        Err(S3Error::Offline(url.to_string()))
+    }
+
+    fn get_object(
+        &self,
+        url: &S3Url,
+        _out: &mut dyn Write,
+        _total: Option<u64>,
+        _progress: &mut dyn FnMut(Progress),
+        _cancel: &CancelToken,
+    ) -> Result<(), S3Error> {
+        Err(S3Error::Offline(url.to_string()))
+    }
+}
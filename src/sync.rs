@@ -0,0 +1,353 @@
+use crate::cache::Cache;
+use crate::hash::md5sum_file;
+use crate::progress::Progress;
+use crate::s3::{ObjectSummary, S3Error, S3Url};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::{fs, io};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error("failed to create directory {}", .0.display())]
+    CreateDirError(PathBuf, #[source] io::Error),
+    #[error("failed to delete {}", .0.display())]
+    DeleteError(PathBuf, #[source] io::Error),
+    #[error("failed to hash {}", .0.display())]
+    HashError(PathBuf, #[source] io::Error),
+    #[error(transparent)]
+    S3Error(#[from] S3Error),
+    #[error("failed to scan {}", .0.display())]
+    ScanError(PathBuf, #[source] io::Error),
+    #[error("key {0} would be written outside the destination directory")]
+    UnsafeKey(String),
+}
+
+/// Counts of what a sync did
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SyncSummary {
+    /// Files that were missing or changed and have been transferred
+    pub transferred: usize,
+    /// Files that were already up to date
+    pub unchanged: usize,
+    /// Extraneous files that were deleted
+    pub deleted: usize,
+    /// Bytes copied out of the local cache
+    pub bytes_from_cache: u64,
+    /// Bytes downloaded or uploaded over the network
+    pub bytes_from_network: u64,
+}
+
+impl SyncSummary {
+    fn add_transfer(&mut self, progress: Progress) {
+        self.transferred += 1;
+        if progress.from_cache {
+            self.bytes_from_cache += progress.transferred;
+        } else {
+            self.bytes_from_network += progress.transferred;
+        }
+    }
+}
+
+/// Get the paths of all files under a directory, relative to it
+fn walk_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(rel_dir) = dirs.pop() {
+        for entry in fs::read_dir(dir.join(&rel_dir))? {
+            let entry = entry?;
+            let rel_path = rel_dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                dirs.push(rel_path);
+            } else {
+                files.push(rel_path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Convert a relative path to the '/'-separated form used in keys
+fn path_to_key_suffix(path: &Path) -> Option<String> {
+    let parts: Option<Vec<&str>> =
+        path.iter().map(|part| part.to_str()).collect();
+    parts.map(|parts| parts.join("/"))
+}
+
+/// Convert the '/'-separated part of a key under a prefix to a relative
+/// path
+///
+/// Returns None if the path could point outside the directory it's
+/// joined to, e.g. because it contains `..` or starts with '/'.
+fn key_suffix_to_path(suffix: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(suffix);
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then_some(path)
+}
+
+/// Treat a URL as a directory by making sure a nonempty key ends in '/'
+fn as_dir_prefix(url: &S3Url) -> S3Url {
+    let mut key = url.key.clone();
+    if !key.is_empty() && !key.ends_with('/') {
+        key.push('/');
+    }
+    S3Url::new(url.bucket.clone(), key)
+}
+
+/// Check if a local file has the same contents as an object
+fn is_unchanged(path: &Path, object: &ObjectSummary) -> io::Result<bool> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if metadata.len() != object.size {
+        return Ok(false);
+    }
+    match object.etag_md5sum() {
+        Some(md5sum) => Ok(md5sum_file(path)? == md5sum),
+        // Without a usable ETag the best we can do is compare sizes
+        None => Ok(true),
+    }
+}
+
+/// Download all objects under a prefix into a directory
+///
+/// Objects are downloaded through `cache`, skipping files that are
+/// already up to date. If `delete` is true, local files that don't
+/// correspond to an object are deleted. The progress callback receives
+/// the key of the object being transferred.
+pub fn sync_down(
+    cache: &Cache,
+    prefix: &S3Url,
+    dir: &Path,
+    delete: bool,
+    progress: &mut dyn FnMut(&str, Progress),
+) -> Result<SyncSummary, SyncError> {
+    let objects = as_dir_prefix(prefix).list().map_err(SyncError::S3Error)?;
+    sync_down_listed(cache, prefix, objects, dir, delete, progress)
+}
+
+/// Download objects under a prefix into a directory, like `sync_down`,
+/// without listing the prefix
+///
+/// This is for when the objects come from elsewhere, such as an
+/// `Inventory`. Objects whose key isn't under the prefix are skipped.
+pub fn sync_down_listed(
+    cache: &Cache,
+    prefix: &S3Url,
+    objects: Vec<ObjectSummary>,
+    dir: &Path,
+    delete: bool,
+    progress: &mut dyn FnMut(&str, Progress),
+) -> Result<SyncSummary, SyncError> {
+    let prefix = as_dir_prefix(prefix);
+    let mut summary = SyncSummary::default();
+    let mut expected = HashSet::new();
+    for object in objects {
+        let rel_key = match object.key.strip_prefix(&prefix.key) {
+            Some(rel_key) => rel_key,
+            None => continue,
+        };
+        // Skip "directory" placeholder objects
+        if rel_key.is_empty() || rel_key.ends_with('/') {
+            continue;
+        }
+        let rel_path = key_suffix_to_path(rel_key)
+            .ok_or_else(|| SyncError::UnsafeKey(object.key.clone()))?;
+        let path = dir.join(&rel_path);
+        expected.insert(rel_path);
+
+        if is_unchanged(&path, &object)
+            .map_err(|err| SyncError::HashError(path.clone(), err))?
+        {
+            summary.unchanged += 1;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| {
+                SyncError::CreateDirError(parent.to_path_buf(), err)
+            })?;
+        }
+        let url = S3Url::new(prefix.bucket.clone(), object.key.clone());
+        let mut last = Progress {
+            transferred: 0,
+            total: Some(object.size),
+            from_cache: false,
+        };
+        url.download_with_cache(cache, &path, &mut |p| {
+            last = p;
+            progress(&object.key, p);
+        })
+        .map_err(SyncError::S3Error)?;
+        summary.add_transfer(last);
+    }
+
+    if delete && dir.exists() {
+        let files = walk_files(dir)
+            .map_err(|err| SyncError::ScanError(dir.to_path_buf(), err))?;
+        for rel_path in files {
+            if !expected.contains(&rel_path) {
+                let path = dir.join(rel_path);
+                fs::remove_file(&path)
+                    .map_err(|err| SyncError::DeleteError(path, err))?;
+                summary.deleted += 1;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Upload all files in a directory to a prefix
+///
+/// Files whose contents already match the object are skipped. If
+/// `delete` is true, objects under the prefix that don't correspond to
+/// a local file are deleted. The progress callback receives the key of
+/// the object being transferred, once the upload completes.
+pub fn sync_up(
+    dir: &Path,
+    prefix: &S3Url,
+    delete: bool,
+    progress: &mut dyn FnMut(&str, Progress),
+) -> Result<SyncSummary, SyncError> {
+    let prefix = as_dir_prefix(prefix);
+    let mut summary = SyncSummary::default();
+    let objects = prefix.list().map_err(SyncError::S3Error)?;
+    let objects_by_key: HashMap<&str, &ObjectSummary> = objects
+        .iter()
+        .map(|object| (object.key.as_str(), object))
+        .collect();
+    let mut expected = HashSet::new();
+    let files = walk_files(dir)
+        .map_err(|err| SyncError::ScanError(dir.to_path_buf(), err))?;
+    for rel_path in files {
+        let suffix = path_to_key_suffix(&rel_path)
+            .ok_or(SyncError::S3Error(S3Error::NonUtf8Path))?;
+        let key = format!("{}{}", prefix.key, suffix);
+        let path = dir.join(&rel_path);
+
+        let existing = objects_by_key.get(key.as_str());
+        expected.insert(key.clone());
+        if let Some(object) = existing {
+            if is_unchanged(&path, object)
+                .map_err(|err| SyncError::HashError(path.clone(), err))?
+            {
+                summary.unchanged += 1;
+                continue;
+            }
+        }
+        let size = fs::metadata(&path)
+            .map_err(|err| SyncError::ScanError(path.clone(), err))?
+            .len();
+        let url = S3Url::new(prefix.bucket.clone(), key);
+        url.upload(&path).map_err(SyncError::S3Error)?;
+        let p = Progress {
+            transferred: size,
+            total: Some(size),
+            from_cache: false,
+        };
+        progress(&url.key, p);
+        summary.add_transfer(p);
+    }
+
+    if delete {
+        for object in objects {
+            if !expected.contains(&object.key) {
+                let url = S3Url::new(prefix.bucket.clone(), object.key);
+                url.delete().map_err(SyncError::S3Error)?;
+                summary.deleted += 1;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::test_configuration;
+    use crate::fake::MemoryStore;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_walk_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/c"), "c").unwrap();
+        fs::write(dir.path().join("d"), "d").unwrap();
+        let files = walk_files(dir.path()).unwrap();
+        assert_eq!(files, [PathBuf::from("a/b/c"), PathBuf::from("d")]);
+        assert_eq!(path_to_key_suffix(&files[0]).unwrap(), "a/b/c");
+    }
+
+    #[test]
+    fn test_key_suffix_to_path() {
+        assert_eq!(key_suffix_to_path("a/b"), Some(PathBuf::from("a/b")));
+        assert_eq!(key_suffix_to_path("a//b"), Some(PathBuf::from("a/b")));
+        assert_eq!(key_suffix_to_path("../a"), None);
+        assert_eq!(key_suffix_to_path("a/../../b"), None);
+        assert_eq!(key_suffix_to_path("/etc/x"), None);
+        assert_eq!(key_suffix_to_path("./a"), None);
+    }
+
+    #[test]
+    fn test_sync_down_unsafe_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let cache =
+            Cache::open_with_configuration(test_configuration(dir.path()))
+                .unwrap()
+                .with_object_store(store.clone());
+        let prefix = S3Url::new("bucket".to_string(), "prefix".to_string());
+        let key = "prefix/../../.bashrc";
+        let url = S3Url::new("bucket".to_string(), key.to_string());
+        store.insert(&url, b"hello", Some("5d41402abc4b2a76b9719d911017c592"));
+        let objects = vec![ObjectSummary {
+            key: key.to_string(),
+            last_modified: "2020-01-01T00:00:00.000Z".to_string(),
+            etag: "\"5d41402abc4b2a76b9719d911017c592\"".to_string(),
+            size: 5,
+        }];
+        let dst = dir.path().join("a/b");
+        assert!(matches!(
+            sync_down_listed(&cache, &prefix, objects, &dst, false, &mut |_, _| {}),
+            Err(SyncError::UnsafeKey(k)) if k == key
+        ));
+        assert!(!dir.path().join(".bashrc").exists());
+        assert_eq!(store.get_count(), 0);
+    }
+
+    #[test]
+    fn test_as_dir_prefix() {
+        let url = S3Url::new("bucket".to_string(), "a".to_string());
+        assert_eq!(as_dir_prefix(&url).key, "a/");
+        let url = S3Url::new("bucket".to_string(), "a/".to_string());
+        assert_eq!(as_dir_prefix(&url).key, "a/");
+        let url = S3Url::new("bucket".to_string(), "".to_string());
+        assert_eq!(as_dir_prefix(&url).key, "");
+    }
+
+    #[test]
+    fn test_is_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test");
+        let mut object = ObjectSummary {
+            key: "test".to_string(),
+            last_modified: "2020-01-01T00:00:00.000Z".to_string(),
+            etag: "\"5d41402abc4b2a76b9719d911017c592\"".to_string(),
+            size: 5,
+        };
+        assert!(!is_unchanged(&path, &object).unwrap());
+        fs::write(&path, "hello").unwrap();
+        assert!(is_unchanged(&path, &object).unwrap());
+        fs::write(&path, "hellp").unwrap();
+        assert!(!is_unchanged(&path, &object).unwrap());
+        object.size = 6;
+        assert!(!is_unchanged(&path, &object).unwrap());
+    }
+}
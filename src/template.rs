@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::env;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("unmatched brace in {0}")]
+    UnmatchedBrace(String),
+    #[error("invalid variable name \"{0}\" in {1}")]
+    InvalidName(String, String),
+    #[error("undefined variables in {1}: {}", .0.join(", "))]
+    Undefined(Vec<String>, String),
+    #[error("invalid value {1:?} for variable {0}")]
+    InvalidValue(String, String),
+}
+
+/// Values for the `{name}` placeholders in templated URLs
+///
+/// Variables set on the command line take precedence over environment
+/// variables of the same name.
+#[derive(Clone, Debug, Default)]
+pub struct Variables {
+    vars: BTreeMap<String, String>,
+}
+
+impl Variables {
+    pub fn new() -> Variables {
+        Variables::default()
+    }
+
+    pub fn insert(&mut self, name: String, value: String) {
+        self.vars.insert(name, value);
+    }
+
+    /// Parse a `<name>=<value>` assignment
+    pub fn parse_assignment(s: &str) -> Option<(String, String)> {
+        let (name, value) = s.split_once('=')?;
+        if !is_valid_name(name) {
+            return None;
+        }
+        Some((name.to_string(), value.to_string()))
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.vars.get(name).cloned().or_else(|| env::var(name).ok())
+    }
+
+    /// Replace the `{name}` placeholders in `template`
+    ///
+    /// `{{` and `}}` stand for literal braces. Every placeholder must
+    /// have a value, and values can't be empty or contain `.` or `..`
+    /// path segments, so that a missing or bad variable fails before
+    /// anything is fetched rather than fetching the wrong key.
+    pub fn expand(&self, template: &str) -> Result<String, TemplateError> {
+        let unmatched = || TemplateError::UnmatchedBrace(template.to_string());
+        let mut out = String::new();
+        let mut undefined = Vec::new();
+        let mut rest = template;
+        while let Some(pos) = rest.find(['{', '}']) {
+            out.push_str(&rest[..pos]);
+            let brace = &rest[pos..pos + 1];
+            rest = &rest[pos + 1..];
+            if let Some(after) = rest.strip_prefix(brace) {
+                out.push_str(brace);
+                rest = after;
+                continue;
+            }
+            if brace == "}" {
+                return Err(unmatched());
+            }
+            let (name, after) = rest.split_once('}').ok_or_else(unmatched)?;
+            rest = after;
+            if !is_valid_name(name) {
+                return Err(TemplateError::InvalidName(
+                    name.to_string(),
+                    template.to_string(),
+                ));
+            }
+            match self.get(name) {
+                Some(value) => {
+                    check_value(name, &value)?;
+                    out.push_str(&value);
+                }
+                None => undefined.push(name.to_string()),
+            }
+        }
+        if !undefined.is_empty() {
+            return Err(TemplateError::Undefined(
+                undefined,
+                template.to_string(),
+            ));
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn check_value(name: &str, value: &str) -> Result<(), TemplateError> {
+    let valid = value
+        .split('/')
+        .all(|segment| !matches!(segment, "" | "." | ".."));
+    if valid {
+        Ok(())
+    } else {
+        Err(TemplateError::InvalidValue(
+            name.to_string(),
+            value.to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let mut vars = Variables::new();
+        vars.insert("branch".to_string(), "feature/x".to_string());
+        vars.insert("version".to_string(), "1.2.3".to_string());
+        vars.insert("up".to_string(), "a/../b".to_string());
+        vars.insert("empty".to_string(), "".to_string());
+        env::set_var("HORST3_TEST_TEMPLATE_VAR", "from-env");
+
+        let expand = |s| vars.expand(s).unwrap();
+        assert_eq!(
+            expand("s3://b/builds/{branch}/{version}/app.tar"),
+            "s3://b/builds/feature/x/1.2.3/app.tar"
+        );
+        assert_eq!(
+            expand("s3://b/{HORST3_TEST_TEMPLATE_VAR}"),
+            "s3://b/from-env"
+        );
+        assert_eq!(expand("s3://b/{{literal}}"), "s3://b/{literal}");
+        assert_eq!(expand("s3://b/key"), "s3://b/key");
+
+        assert!(matches!(
+            vars.expand("s3://b/{branch}/{os}/{arch}"),
+            Err(TemplateError::Undefined(names, _)) if names == ["os", "arch"]
+        ));
+        assert!(matches!(
+            vars.expand("s3://b/{branch"),
+            Err(TemplateError::UnmatchedBrace(_))
+        ));
+        assert!(matches!(
+            vars.expand("s3://b/branch}"),
+            Err(TemplateError::UnmatchedBrace(_))
+        ));
+        assert!(matches!(
+            vars.expand("s3://b/{}"),
+            Err(TemplateError::InvalidName(..))
+        ));
+        assert!(matches!(
+            vars.expand("s3://b/{up}"),
+            Err(TemplateError::InvalidValue(name, _)) if name == "up"
+        ));
+        assert!(matches!(
+            vars.expand("s3://b/{empty}/a"),
+            Err(TemplateError::InvalidValue(..))
+        ));
+
+        assert_eq!(
+            Variables::parse_assignment("branch=main=x"),
+            Some(("branch".to_string(), "main=x".to_string()))
+        );
+        assert_eq!(Variables::parse_assignment("branch"), None);
+        assert_eq!(Variables::parse_assignment("my-var=1"), None);
+    }
+}